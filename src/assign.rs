@@ -1,5 +1,5 @@
 extern crate nalgebra as na;
-use std::{collections::HashMap, f64::consts::PI};
+use std::{collections::HashMap, f64::consts::PI, fmt};
 
 use itertools::Itertools;
 use nalgebra::{Vector, Vector2};
@@ -10,6 +10,15 @@ pub struct Circle {
     pub r: f64,
 }
 
+/// How a circle is approximated by a regular polygon.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PolygonFit {
+    /// Vertices lie on the circle, so the polygon is slightly smaller than it.
+    Inscribed,
+    /// Vertices are pushed out so the polygon has the same area as the circle.
+    EqualArea,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Intersection {
     Inside(Circle),
@@ -26,15 +35,55 @@ impl Intersection {
     }
 }
 
+impl fmt::Display for Circle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Circle({:.3}, {:.3}, r={:.3}, area={:.3})", self.origin.x, self.origin.y, self.r, self.area())
+    }
+}
+
+impl From<&RadialArea> for Circle {
+    fn from(radial: &RadialArea) -> Self {
+        Circle::from_radial_simple(radial)
+    }
+}
+
 impl Circle {
     fn new(x: f64, y: f64, r: f64) -> Circle {
         Circle { origin: Vector2::new(x, y), r }
     }
 
+    /// The circle with the radial's target area, ignoring any overlaps.
+    pub fn from_radial_simple(radial: &RadialArea) -> Circle {
+        Circle { origin: radial.origin, r: (radial.area / PI).sqrt() }
+    }
+
     pub fn area(&self) -> f64 {
         PI * self.r * self.r
     }
 
+    /// Whether `p` lies inside or on the circle.
+    pub fn contains(&self, p: &Vector2<f64>) -> bool {
+        (p - self.origin).norm_squared() <= self.r * self.r
+    }
+
+    /// Approximates the circle by a regular polygon with `vertices` vertices, counterclockwise from
+    /// the positive x axis.
+    ///
+    /// An inscribed N-gon has area `N/2 r^2 sin(θ)` with `θ = 2π/N`, short of `π r^2`. With
+    /// `PolygonFit::EqualArea` the vertex radius is scaled by `sqrt(θ / sin(θ))` to make up the difference.
+    pub fn to_polygon(&self, vertices: usize, fit: PolygonFit) -> Vec<Vector2<f64>> {
+        assert!(vertices >= 3, "A polygon needs at least 3 vertices");
+        let theta = 2.0 * PI / vertices as f64;
+        let r = match fit {
+            PolygonFit::Inscribed => self.r,
+            PolygonFit::EqualArea => self.r * (theta / theta.sin()).sqrt(),
+        };
+        (0..vertices).map(|i| {
+            let a = theta * i as f64;
+            self.origin + Vector2::new(r * a.cos(), r * a.sin())
+        }).collect()
+    }
+
     pub fn distance(&self, other: &Circle) -> f64 {
         self.origin.metric_distance(&other.origin)
     }
@@ -153,48 +202,44 @@ impl Circle {
 
     /// Calculates the total area that `circle` shares with any other circle in the `others` slice.
     pub fn total_intersection(&self, others: &[Circle]) -> f64 {
-        let mut acc: f64 = 0.0;
-        for c in 1..=others.len() {
-            let polarity: f64 = if c % 2 == 0 {
-                -1.0
-            } else {
-                1.0
-            };
-
-            for (i, combs) in others.to_vec().into_iter().combinations(c).enumerate() {
-                let mut cs = combs.to_vec();
-                cs.push(*self);
-                // When polarity is negative we deduct to remove double counting of previous,
-                let pl = polarity * Circle::intersect_all(&cs);
-                if pl.is_nan() {
-                    panic!("Circle intersection NaN!");
-                }
-                acc += pl;
-                //println!("APPEND (c={}; {}): pl={}, t={}, cs:{:?}", c, i, pl, acc, &cs);
-            }
-        }
-
-        acc
+        self.inclusion_exclusion(others, Circle::intersect_all)
     }
 
     pub fn total_intersection_approx(&self, others: &[Circle]) -> f64 {
+        self.inclusion_exclusion(others, crate::intersect::intersect_all_approx)
+    }
+
+    /// Sums `intersect` over every combination of `others` together with `self`, alternating sign by
+    /// combination size. Combinations are walked in lexicographic order through one index buffer and one
+    /// scratch circle buffer, so nothing is allocated per combination.
+    fn inclusion_exclusion(&self, others: &[Circle], intersect: impl Fn(&[Circle]) -> f64) -> f64 {
+        let n = others.len();
         let mut acc: f64 = 0.0;
-        for c in 1..=others.len() {
+        let mut idx: Vec<usize> = Vec::with_capacity(n);
+        let mut cs: Vec<Circle> = Vec::with_capacity(n + 1);
+        for c in 1..=n {
             let polarity: f64 = if c % 2 == 0 {
                 -1.0
             } else {
                 1.0
             };
 
-            for (i, combs) in others.to_vec().into_iter().combinations(c).enumerate() {
-                let mut cs = combs.to_vec();
+            idx.clear();
+            idx.extend(0..c);
+            loop {
+                cs.clear();
+                cs.extend(idx.iter().map(|&i| others[i]));
                 cs.push(*self);
                 // When polarity is negative we deduct to remove double counting of previous,
-                let pl = polarity * crate::intersect::intersect_all_approx(&cs);
+                let pl = polarity * intersect(&cs);
                 if pl.is_nan() {
                     panic!("Circle intersection NaN!");
                 }
                 acc += pl;
+
+                if !next_combination(&mut idx, n) {
+                    break;
+                }
             }
         }
 
@@ -465,6 +510,185 @@ fn polygon_area(vertices: &[Vector2<f64>]) -> f64 {
     (area / 2.0).abs()
 }
 
+/// z component of `(a - o) x (b - o)`: positive when `o`, `a`, `b` turn anticlockwise.
+fn cross(o: &Vector2<f64>, a: &Vector2<f64>, b: &Vector2<f64>) -> f64 {
+    (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x)
+}
+
+/// The convex hull of `points` (Andrew's monotone chain), anticlockwise from the lowest leftmost point.
+/// Points on an edge are left out, so collinear points give just the two ends, and one distinct point
+/// gives itself.
+pub fn convex_hull(points: &[Vector2<f64>]) -> Vec<Vector2<f64>> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    sorted.dedup();
+    if sorted.len() < 3 {
+        return sorted;
+    }
+
+    let mut hull: Vec<Vector2<f64>> = Vec::with_capacity(2 * sorted.len());
+    // Lower hull left to right, dropping points that don't turn anticlockwise.
+    for p in sorted.iter() {
+        while hull.len() >= 2 && cross(&hull[hull.len() - 2], &hull[hull.len() - 1], p) <= 0.0 {
+            hull.pop();
+        }
+        hull.push(*p);
+    }
+    // Upper hull right to left, keeping the lower hull intact.
+    let lower = hull.len() + 1;
+    for p in sorted.iter().rev().skip(1) {
+        while hull.len() >= lower && cross(&hull[hull.len() - 2], &hull[hull.len() - 1], p) <= 0.0 {
+            hull.pop();
+        }
+        hull.push(*p);
+    }
+    // The last point is the first again.
+    hull.pop();
+    hull
+}
+
+/// Area of the convex hull of `points`, 0 for fewer than three non-collinear points.
+pub fn hull_area(points: &[Vector2<f64>]) -> f64 {
+    polygon_area(&convex_hull(points))
+}
+
+/// Relative slack when checking that a point lies inside a candidate enclosing circle.
+const ENCLOSE_EPSILON: f64 = 1e-9;
+
+fn encloses(circle: &Circle, p: &Vector2<f64>) -> bool {
+    circle.origin.metric_distance(p) <= circle.r * (1.0 + ENCLOSE_EPSILON) + ENCLOSE_EPSILON
+}
+
+/// The circle with `a` and `b` at either end of a diameter.
+fn circle_from_two(a: &Vector2<f64>, b: &Vector2<f64>) -> Circle {
+    Circle { origin: (a + b) * 0.5, r: a.metric_distance(b) * 0.5 }
+}
+
+/// The circumcircle of `a`, `b` and `c`. Collinear points have none, so the widest pair's circle is used.
+fn circle_from_three(a: &Vector2<f64>, b: &Vector2<f64>, c: &Vector2<f64>) -> Circle {
+    let (ab, ac) = (b - a, c - a);
+    let d = 2.0 * (ab.x * ac.y - ab.y * ac.x);
+    if d.abs() < 1e-12 * ab.norm_squared().max(ac.norm_squared()) {
+        return [circle_from_two(a, b), circle_from_two(a, c), circle_from_two(b, c)]
+            .into_iter()
+            .max_by(|x, y| x.r.partial_cmp(&y.r).unwrap())
+            .unwrap();
+    }
+    let offset = Vector2::new(
+        ac.y * ab.norm_squared() - ab.y * ac.norm_squared(),
+        ab.x * ac.norm_squared() - ac.x * ab.norm_squared(),
+    ) / d;
+    Circle { origin: a + offset, r: offset.norm() }
+}
+
+/// The smallest circle containing every point, or `None` for no points. Uses the iterative form of Welzl's
+/// algorithm without shuffling, so the result is deterministic but the worst case is cubic in the number
+/// of points.
+pub fn min_enclosing_circle(points: &[Vector2<f64>]) -> Option<Circle> {
+    let mut circle = Circle { origin: *points.first()?, r: 0.0 };
+    for (i, p) in points.iter().enumerate().skip(1) {
+        if encloses(&circle, p) {
+            continue;
+        }
+        // `p` must be on the boundary of the circle for the first i + 1 points.
+        circle = Circle { origin: *p, r: 0.0 };
+        for (j, q) in points[..i].iter().enumerate() {
+            if encloses(&circle, q) {
+                continue;
+            }
+            // And so must `q`.
+            circle = circle_from_two(p, q);
+            for s in &points[..j] {
+                if !encloses(&circle, s) {
+                    circle = circle_from_three(p, q, s);
+                }
+            }
+        }
+    }
+    Some(circle)
+}
+
+/// An axis-aligned box, in the same units as the circles.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BBox {
+    pub min: Vector2<f64>,
+    pub max: Vector2<f64>,
+}
+
+impl BBox {
+    /// The smallest box containing every circle, or `None` for no circles.
+    pub fn around(circles: &[Circle]) -> Option<BBox> {
+        let first = circles.first()?;
+        let r = Vector2::new(first.r, first.r);
+        Some(circles.iter().fold(BBox { min: first.origin - r, max: first.origin + r }, |b, c| {
+            let r = Vector2::new(c.r, c.r);
+            BBox { min: b.min.inf(&(c.origin - r)), max: b.max.sup(&(c.origin + r)) }
+        }))
+    }
+
+    /// The smallest box containing both boxes.
+    pub fn union(&self, other: &BBox) -> BBox {
+        BBox { min: self.min.inf(&other.min), max: self.max.sup(&other.max) }
+    }
+
+    pub fn area(&self) -> f64 {
+        let d = self.max - self.min;
+        d.x.max(0.0) * d.y.max(0.0)
+    }
+}
+
+/// Whether the centre of each cell of a `samples` x `samples` grid over `bbox` lies in no circle, row by row.
+fn uncovered_cells(circles: &[Circle], bbox: &BBox, samples: usize) -> Vec<bool> {
+    (0..samples * samples).map(|i| {
+        let p = crate::intersect::grid_centre(bbox, samples, samples, i);
+        !circles.iter().any(|c| c.contains(&p))
+    }).collect()
+}
+
+/// Approximate area inside `bbox` covered by no circle - places no catchment reaches. Sampled at the
+/// centres of a `samples` x `samples` grid.
+pub fn coverage_gaps(circles: &[Circle], bbox: BBox, samples: usize) -> f64 {
+    crate::intersect::sample_grid(bbox, samples, samples, |p| !circles.iter().any(|c| c.contains(&p)))
+}
+
+/// The separate gaps found by `coverage_gaps`, as each one's area and centroid, largest first. Cells are
+/// joined through their edges.
+pub fn coverage_gap_regions(circles: &[Circle], bbox: BBox, samples: usize) -> Vec<(f64, Vector2<f64>)> {
+    let mut uncovered = uncovered_cells(circles, &bbox, samples);
+    let centre = |i: usize| crate::intersect::grid_centre(&bbox, samples, samples, i);
+
+    let mut regions = Vec::new();
+    for start in 0..uncovered.len() {
+        if !uncovered[start] {
+            continue;
+        }
+        // Flood fill, clearing cells as they're visited.
+        uncovered[start] = false;
+        let mut stack = vec![start];
+        let (mut count, mut sum) = (0usize, Vector2::zeros());
+        while let Some(i) = stack.pop() {
+            count += 1;
+            sum += centre(i);
+            let (x, y) = (i % samples, i / samples);
+            let neighbours = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < samples).then(|| i + 1),
+                (y > 0).then(|| i - samples),
+                (y + 1 < samples).then(|| i + samples),
+            ];
+            for j in neighbours.into_iter().flatten() {
+                if uncovered[j] {
+                    uncovered[j] = false;
+                    stack.push(j);
+                }
+            }
+        }
+        regions.push((bbox.area() * count as f64 / (samples * samples) as f64, sum / count as f64));
+    }
+    regions.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    regions
+}
+
 #[test]
 pub fn circle_test() {
     let a = Circle::intersect_all(&[
@@ -481,33 +705,120 @@ pub fn segment_check() {
     assert!(a > 65.35 && a < 65.45);
 }
 
+/// Tolerance for comparing exact intersection areas: 0.1% of the sampled estimate.
+#[cfg(test)]
+fn approx_tolerance(circles: &[Circle]) -> f64 {
+    1e-3 * crate::intersect::intersect_all_approx(circles)
+}
+
 #[test]
 pub fn circle_check() {
-    let c1 = Circle::new(0.0, 0.0, 1.0);
-    let c2 = Circle::new(0.5, 0.0, 0.7);
-    let c3 = Circle::new(0.0, -0.5, 0.8);
-    let c4 = Circle::new(0.0, 0.5, 0.8);
-    let a = Circle::intersect_all(&[c1, c2, c3, c4]);
+    let circles = [
+        Circle::new(0.0, 0.0, 1.0),
+        Circle::new(0.5, 0.0, 0.7),
+        Circle::new(0.0, -0.5, 0.8),
+        Circle::new(0.0, 0.5, 0.8),
+    ];
+    let a = Circle::intersect_all(&circles);
+    let approx = crate::intersect::intersect_all_approx(&circles);
 
-    assert!(a > 3.666 && a < 0.3668);
+    assert!(a > 0.3666 && a < 0.3668);
+    assert!((a - approx).abs() < approx_tolerance(&circles), "exact {} vs approx {}", a, approx);
 }
 
 #[test]
 pub fn super_circle() {
-    let a = Circle::intersect_all(&[
+    let circles = vec![
         Circle::new(0.0, 0.0, 1.0),
         Circle::new(0.5, 0.0, 0.7),
         Circle::new(0.0, -0.5, 0.8),
         Circle::new(0.0, 0.5, 0.8),
-    ]);
-    let b = Circle::intersect_all(&[
+    ];
+    let a = Circle::intersect_all(&circles);
+
+    // This circle contains the last one, and so the whole existing intersection.
+    let mut with_super = circles.clone();
+    with_super.push(Circle::new(0.0, 0.5, 0.9));
+    let b = Circle::intersect_all(&with_super);
+
+    assert!(a > 0.0);
+    assert!((a - b).abs() < approx_tolerance(&circles), "{} changed to {}", a, b);
+}
+
+#[test]
+fn test_min_enclosing_circle() {
+    assert!(min_enclosing_circle(&[]).is_none());
+
+    let single = min_enclosing_circle(&[Vector2::new(383.8, 398.1)]).unwrap();
+    assert_eq!(single.origin, Vector2::new(383.8, 398.1));
+    assert_eq!(single.r, 0.0);
+
+    let pair = min_enclosing_circle(&[Vector2::new(0.0, 0.0), Vector2::new(4.0, 2.0)]).unwrap();
+    assert!((pair.origin - Vector2::new(2.0, 1.0)).norm() < 1e-12);
+    assert!((pair.r - 5f64.sqrt()).abs() < 1e-12);
+
+    // An acute triangle needs its circumcircle, here centred at (2, 1) with radius 5.
+    let triangle = [Vector2::new(7.0, 1.0), Vector2::new(2.0, 6.0), Vector2::new(-2.0, -2.0)];
+    let c = min_enclosing_circle(&triangle).unwrap();
+    assert!((c.origin - Vector2::new(2.0, 1.0)).norm() < 1e-12, "{}", c);
+    assert!((c.r - 5.0).abs() < 1e-12);
+
+    // Interior points, including one on an edge, don't change the answer.
+    let mut points = triangle.to_vec();
+    points.extend([Vector2::new(2.0, 1.0), Vector2::new(4.5, 3.5), Vector2::new(0.0, 3.0)]);
+    let c2 = min_enclosing_circle(&points).unwrap();
+    assert!((c2.origin - c.origin).norm() < 1e-9 && (c2.r - c.r).abs() < 1e-9);
+    assert!(points.iter().all(|p| encloses(&c2, p)));
+}
+
+#[test]
+fn test_to_polygon() {
+    let circle = Circle::new(383.8, 398.1, 1.2);
+
+    let corrected = crate::intersect::polygon_area(&circle.to_polygon(64, PolygonFit::EqualArea));
+    assert!((corrected - circle.area()).abs() < 1e-3 * circle.area(), "{} vs {}", corrected, circle.area());
+
+    let inscribed = circle.to_polygon(64, PolygonFit::Inscribed);
+    assert_eq!(inscribed.len(), 64);
+    assert!(inscribed.iter().all(|p| ((p - circle.origin).norm() - circle.r).abs() < 1e-12));
+    assert!(crate::intersect::polygon_area(&inscribed) < corrected);
+}
+
+#[test]
+fn test_total_intersection_unchanged() {
+    // The previous implementation, materializing every combination.
+    fn reference(circle: &Circle, others: &[Circle]) -> f64 {
+        let mut acc = 0.0;
+        for c in 1..=others.len() {
+            let polarity = if c % 2 == 0 { -1.0 } else { 1.0 };
+            for combs in others.to_vec().into_iter().combinations(c) {
+                let mut cs = combs.to_vec();
+                cs.push(*circle);
+                acc += polarity * Circle::intersect_all(&cs);
+            }
+        }
+        acc
+    }
+
+    let circles = [
         Circle::new(0.0, 0.0, 1.0),
         Circle::new(0.5, 0.0, 0.7),
         Circle::new(0.0, -0.5, 0.8),
         Circle::new(0.0, 0.5, 0.8),
         Circle::new(0.0, 0.5, 0.9),
-    ]);
-    assert_eq!(a, b);
+    ];
+    for (i, circle) in circles.iter().enumerate() {
+        let others: Vec<Circle> = circles.iter().enumerate().filter_map(|(j, c)| if i != j { Some(*c) } else { None }).collect();
+        assert_eq!(circle.total_intersection(&others), reference(circle, &others));
+    }
+    assert_eq!(circles[0].total_intersection(&[]), 0.0);
+
+    let mut idx = vec![0, 1];
+    let mut seen = vec![idx.clone()];
+    while next_combination(&mut idx, 4) {
+        seen.push(idx.clone());
+    }
+    assert_eq!(seen, (0..4).combinations(2).collect::<Vec<_>>());
 }
 
 pub struct CircleRecord {
@@ -572,6 +883,20 @@ pub struct CircleRecord {
 //     acc
 // }
 
+/// Advances `idx`, a strictly increasing set of indices into `0..n`, to the next combination in
+/// lexicographic order (the order itertools' `combinations` uses). Returns false after the last one.
+fn next_combination(idx: &mut [usize], n: usize) -> bool {
+    let k = idx.len();
+    let Some(i) = (0..k).rev().find(|&i| idx[i] < n - k + i) else {
+        return false;
+    };
+    idx[i] += 1;
+    for j in i + 1..k {
+        idx[j] = idx[j - 1] + 1;
+    }
+    true
+}
+
 // Outputs the new radius of the target circle to take up the specified area that does not intersect with any other circle.
 #[derive(Debug, Copy, Clone)]
 pub struct RadialArea {
@@ -581,28 +906,55 @@ pub struct RadialArea {
 
 use geo_booleanop::boolean::BooleanOp;
 
-pub fn scale_to_exclusive_area(circles: &[Circle], radial: &RadialArea, mut delta: f64, epsilon: f64, max_iter: usize) -> Option<Circle> {
+/// How the area a circle shares with its neighbours is measured while scaling.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IntersectionMethod {
+    /// Inclusion-exclusion over exact intersection areas (see `Circle::total_intersection`). The cost grows
+    /// exponentially with the number of neighbours.
+    Exact,
+    /// Grid sampling with `samples` points per side (see `intersect::overlap`). Cost is fixed per circle and
+    /// the error shrinks as `samples` grows.
+    Sampled { samples: usize },
+}
+
+impl Default for IntersectionMethod {
+    fn default() -> Self {
+        Self::Exact
+    }
+}
+
+impl IntersectionMethod {
+    /// The area of `circle` shared with any of `others`.
+    pub fn overlap(&self, circle: &Circle, others: &[Circle]) -> f64 {
+        match *self {
+            Self::Exact => circle.total_intersection(others),
+            Self::Sampled { samples } => crate::intersect::overlap(*circle, others, samples),
+        }
+    }
+}
+
+pub fn scale_to_exclusive_area(circles: &[Circle], radial: &RadialArea, delta: f64, epsilon: f64, max_iter: usize, intersection: IntersectionMethod) -> Option<Circle> {
+    scale_to_exclusive_area_with_residual(circles, radial, delta, epsilon, max_iter, intersection).map(|(c, _)| c)
+}
+
+/// As `scale_to_exclusive_area`, but also returns the achieved `|a_total - target|`. This is below
+/// `epsilon` unless the search ran out of iterations.
+pub fn scale_to_exclusive_area_with_residual(circles: &[Circle], radial: &RadialArea, mut delta: f64, epsilon: f64, max_iter: usize, intersection: IntersectionMethod) -> Option<(Circle, f64)> {
     let mut r = (radial.area / PI).sqrt();
     let mut a_prev = None;
     let mut circs = None;
     let mut out = None;
     for _ in 0..max_iter {
         let circle = Circle { r, origin: radial.origin };
-        out = Some(circle);
         let ints = circle.intersects_many(circles);
-       // let intersection = circle.total_intersection(&ints);
-        let int_approx = crate::intersect::overlap(circle, &ints, 1500);//circle.total_intersection_approx(&ints);
-
-        //let a_total = circle.area() - intersection;
-        let a_approx = circle.area() - int_approx;
-
-        //println!("target: {}, curr: {} ({}), r: {}, ints: {}, inter: {} ({})", radial.area, a_total, a_approx, r, ints.len(), intersection, int_approx);
-        //assert!(intersection >= 0.0);
+        let a_total = circle.area() - intersection.overlap(&circle, &ints);
 
-        let a_total = a_approx;
+        //println!("target: {}, curr: {}, r: {}, ints: {}", radial.area, a_total, r, ints.len());
+        let residual = (a_total - radial.area).abs();
+        out = Some((circle, residual));
 
-        if (a_total - radial.area).abs() < epsilon {
-            return Some(circle);
+        if residual < epsilon {
+            return out;
         }
 
 
@@ -627,17 +979,207 @@ pub fn scale_to_exclusive_area(circles: &[Circle], radial: &RadialArea, mut delt
     out
 }
 
-pub fn scale_all(radials: &[RadialArea], delta: f64, epsilon: f64, max_iter: usize) -> Option<Vec<Circle>> {
+/// Centres closer than this (km) are treated as coincident, e.g. two schools sharing a postcode.
+const COINCIDENT_EPSILON: f64 = 1e-6;
+/// How far (km) a coincident centre is moved away from the one before it.
+const NUDGE_DIST: f64 = 0.005;
+
+/// Moves any radial whose centre coincides with an earlier one a few metres away, so the intersection
+/// code never sees two circles with the same centre. The direction depends only on the radial's index,
+/// so the result is deterministic. Returns the radials and how many were moved.
+pub fn separate_coincident(radials: &[RadialArea]) -> (Vec<RadialArea>, usize) {
+    // Successive multiples of the golden angle are well spread around the circle.
+    let golden_angle = PI * (3.0 - 5f64.sqrt());
+
+    let mut out: Vec<RadialArea> = Vec::with_capacity(radials.len());
+    let mut nudged = 0;
+    for (i, radial) in radials.iter().enumerate() {
+        let mut radial = *radial;
+        let mut step = 0;
+        while out.iter().any(|x| x.origin.metric_distance(&radial.origin) < COINCIDENT_EPSILON) {
+            step += 1;
+            let angle = golden_angle * (i + step) as f64;
+            radial.origin += Vector2::new(angle.cos(), angle.sin()) * NUDGE_DIST;
+        }
+        if step > 0 {
+            nudged += 1;
+        }
+        out.push(radial);
+    }
+    (out, nudged)
+}
+
+/// Places each circle in order against the ones before it. Returns every circle with its residual
+/// `|a_total - target|`, so poorly converged catchments can be audited. Coincident centres are first
+/// separated by `separate_coincident`.
+pub fn scale_all(radials: &[RadialArea], delta: f64, epsilon: f64, max_iter: usize, intersection: IntersectionMethod) -> Option<Vec<(Circle, f64)>> {
+    let (radials, nudged) = separate_coincident(radials);
+    if nudged > 0 {
+        println!("Nudged {} circles off coincident centres", nudged);
+    }
+
     let mut circles = Vec::with_capacity(radials.len());
+    let mut residuals = Vec::with_capacity(radials.len());
     let len = radials.len();
-    for (i, radial) in radials.into_iter().enumerate() {
-        circles.push(scale_to_exclusive_area(&circles, radial, delta, epsilon, max_iter)?);
+    for (i, radial) in radials.iter().enumerate() {
+        let (circle, residual) = scale_to_exclusive_area_with_residual(&circles, radial, delta, epsilon, max_iter, intersection)?;
+        circles.push(circle);
+        residuals.push(residual);
         println!("Radial {}/{}: o={}, a={}", i, len, radial.origin, radial.area);
     }
 
+    Some(circles.into_iter().zip(residuals).collect())
+}
+
+/// Above this fraction of the total target area, `validate_area_conservation` logs a warning.
+const CONSERVATION_WARN: f64 = 0.01;
+
+/// The area each circle adds beyond the circles before it - what `scale_all` scales it to.
+pub fn sequential_exclusive_areas(circles: &[Circle]) -> Vec<f64> {
+    circles.iter().enumerate().map(|(i, circle)| {
+        let ints = circle.intersects_many(&circles[..i]);
+        circle.area() - crate::intersect::overlap(*circle, &ints, 1500)
+    }).collect()
+}
+
+/// Checks the output of `scale_all` in aggregate: each circle is given its target as the area it adds
+/// beyond the circles before it, so those areas (`exclusive`, from `sequential_exclusive_areas`, together
+/// the union of the circles) should sum to the total target area. Returns the absolute difference between
+/// the two sums.
+pub fn validate_area_conservation(exclusive: &[f64], radials: &[RadialArea]) -> f64 {
+    let covered: f64 = exclusive.iter().sum();
+    let target: f64 = radials.iter().map(|x| x.area).sum();
+
+    let discrepancy = (covered - target).abs();
+    if discrepancy > CONSERVATION_WARN * target {
+        println!("Scaled circles cover {} against a target of {} ({} off)", covered, target, discrepancy);
+    }
+    discrepancy
+}
+
+/// Grows every circle together towards its target exclusive area. Each pass recomputes the exclusive
+/// areas from the previous pass's radii, so the result does not depend on the order of `radials`.
+pub fn scale_all_balanced(radials: &[RadialArea], epsilon: f64, max_iter: usize, intersection: IntersectionMethod) -> Option<Vec<Circle>> {
+    let mut circles = scale_all_simple(radials);
+
+    for _ in 0..max_iter {
+        let exclusive: Vec<f64> = (0..circles.len()).map(|i| {
+            let others: Vec<Circle> = circles.iter().enumerate().filter_map(|(j, c)| if i != j { Some(*c) } else { None }).collect();
+            let ints = circles[i].intersects_many(&others);
+            circles[i].area() - intersection.overlap(&circles[i], &ints)
+        }).collect();
+
+        let mut converged = true;
+        for ((circle, radial), a) in circles.iter_mut().zip(radials.iter()).zip(exclusive) {
+            if (a - radial.area).abs() >= epsilon {
+                converged = false;
+                // Damped, since the neighbours are growing at the same time.
+                let ratio = if a > 0.0 { radial.area / a } else { 2.0 };
+                circle.r *= ratio.powf(0.25);
+            }
+
+            if !circle.r.is_finite() {
+                return None;
+            }
+        }
+
+        if converged {
+            return Some(circles);
+        }
+    }
+
+    println!("Balanced scaling did not converge after {} iterations", max_iter);
     Some(circles)
 }
 
+/// Above this many neighbours the inclusion-exclusion in `total_intersection` gets too expensive, so
+/// `exclusive_areas` falls back to the sampled `intersect::overlap`.
+const EXACT_OVERLAP_MAX: usize = 6;
+
+/// The area of each circle not shared with any other circle in the set.
+pub fn exclusive_areas(circles: &[Circle]) -> Vec<f64> {
+    circles.iter().enumerate().map(|(i, circle)| {
+        let others: Vec<Circle> = circles.iter().enumerate().filter_map(|(j, c)| if i != j { Some(*c) } else { None }).collect();
+        let ints = circle.intersects_many(&others);
+        let shared = if ints.len() <= EXACT_OVERLAP_MAX {
+            circle.total_intersection(&ints)
+        } else {
+            crate::intersect::overlap(*circle, &ints, 1500)
+        };
+        circle.area() - shared
+    }).collect()
+}
+
+/// Gives every radial its naive circle without resolving overlaps - a quick preview of `scale_all`.
+pub fn scale_all_simple(radials: &[RadialArea]) -> Vec<Circle> {
+    radials.iter().map(Circle::from_radial_simple).collect()
+}
+
+/// Summary of a set of solved radii, for sanity checking a run.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RadiusStats {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    /// Circles whose allocation residual was still at or above the tolerance, i.e. the solver gave up on them.
+    pub unconverged: usize,
+}
+
+impl fmt::Display for RadiusStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} radii: min {:.3}, max {:.3}, mean {:.3}, median {:.3}, {} unconverged", self.count, self.min, self.max, self.mean, self.median, self.unconverged)
+    }
+}
+
+/// Summary statistics of the finite radii of `scaled`, as (circle, residual) pairs like `scale_all` returns, `None`
+/// when there are none. A max far above the median usually means a degenerate allocation; any circle whose residual
+/// is not below `epsilon` (including a NaN one) is counted as unconverged.
+pub fn radius_stats(scaled: &[(Circle, f64)], epsilon: f64) -> Option<RadiusStats> {
+    let radii: Vec<f64> = scaled.iter().map(|(c, _)| c.r).filter(|r| r.is_finite()).collect();
+    let mut median = crate::Scaler::new();
+    for r in &radii {
+        median.add(*r as f32, 1.0);
+    }
+    Some(RadiusStats {
+        count: radii.len(),
+        min: radii.iter().copied().reduce(f64::min)?,
+        max: radii.iter().copied().reduce(f64::max)?,
+        mean: radii.iter().sum::<f64>() / radii.len() as f64,
+        median: median.median()? as f64,
+        unconverged: scaled.iter().filter(|(_, residual)| !(*residual < epsilon)).count(),
+    })
+}
+
+/// How target areas are allocated to circles.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ScaleMethod {
+    /// Place circles one at a time in order, each against the ones before it (see `scale_all`).
+    Sequential,
+    /// Grow all circles together (see `scale_all_balanced`).
+    Balanced,
+    /// Ignore overlaps entirely (see `scale_all_simple`).
+    Simple,
+}
+
+/// Allocates each radial its circle by `method`, measuring overlaps by `intersection`.
+pub fn scale(radials: &[RadialArea], method: ScaleMethod, intersection: IntersectionMethod, delta: f64, epsilon: f64, max_iter: usize) -> Option<Vec<Circle>> {
+    assert!(radials.iter().all(|x| x.area.is_finite() && x.area > 0.0), "Radial areas must be finite and positive");
+    match method {
+        ScaleMethod::Sequential => {
+            let scaled = scale_all(radials, delta, epsilon, max_iter, intersection)?;
+            let unconverged = scaled.iter().filter(|(_, residual)| *residual >= epsilon).count();
+            if unconverged > 0 {
+                println!("{} of {} circles did not converge to within {}", unconverged, scaled.len(), epsilon);
+            }
+            Some(scaled.into_iter().map(|(c, _)| c).collect())
+        }
+        ScaleMethod::Balanced => scale_all_balanced(radials, epsilon, max_iter, intersection),
+        ScaleMethod::Simple => Some(scale_all_simple(radials)),
+    }
+}
+
 #[test]
 fn test_groups() {
     let gs = Circle::group(&[
@@ -693,7 +1235,7 @@ fn test_area_scale() {
         Circle::new(20.0, 0.5, 0.9),
     ];
 
-    let c = scale_to_exclusive_area(gs, &RadialArea { origin: Vector2::new(0.0, 0.0), area: PI }, 1.0, 0.001, 200);
+    let c = scale_to_exclusive_area(gs, &RadialArea { origin: Vector2::new(0.0, 0.0), area: PI }, 1.0, 0.001, 200, IntersectionMethod::Sampled { samples: 1500 });
 
     println!("intersect_exclusive: {}", c.unwrap().r);
 }
@@ -707,7 +1249,215 @@ fn test_scale_all() {
         RadialArea { origin: Vector2::new(-0.4, 0.5), area: PI },
     ];
 
-    let c = scale_all(gs, 1.0, 0.001, 200);
+    let c = scale_all(gs, 1.0, 0.001, 200, IntersectionMethod::Sampled { samples: 1500 });
 
     println!("scale_all: {:?}", c);
-}
\ No newline at end of file
+
+    let scaled = c.unwrap();
+    let stats = radius_stats(&scaled, 0.001).unwrap();
+    assert_eq!(stats.count, 4);
+    assert_eq!(stats.unconverged, 0, "{}", stats);
+    // Each unit area overlaps others, so every circle grows past its unobstructed radius of 1.
+    assert!(stats.min >= 1.0 && stats.max < 3.0, "{}", stats);
+    assert!(stats.mean > stats.min && stats.mean < stats.max, "{}", stats);
+    assert!(stats.median >= stats.min && stats.median <= stats.max, "{}", stats);
+    // A tolerance the solver could not reach flags every circle it stopped short on.
+    let worst = scaled.iter().map(|(_, residual)| *residual).fold(0.0, f64::max);
+    assert_eq!(radius_stats(&scaled, worst / 2.0).unwrap().unconverged, scaled.iter().filter(|(_, r)| *r >= worst / 2.0).count());
+    assert!(radius_stats(&scaled, 0.0).unwrap().unconverged >= 1);
+    assert_eq!(radius_stats(&[], 0.001), None);
+
+    // A failed radius is left out of every statistic alike, but still counts as unconverged.
+    let scaled = [(Circle::new(0.0, 0.0, 1.0), 0.0), (Circle::new(0.0, 0.0, f64::NAN), f64::NAN), (Circle::new(0.0, 0.0, 2.0), 0.0), (Circle::new(0.0, 0.0, 4.0), 0.5)];
+    assert_eq!(radius_stats(&scaled, 0.001), Some(RadiusStats { count: 3, min: 1.0, max: 4.0, mean: 7.0 / 3.0, median: 2.0, unconverged: 2 }));
+    assert_eq!(radius_stats(&scaled[..3], 0.001).map(|x| x.median), Some(1.5));
+    assert_eq!(radius_stats(&scaled[1..2], 0.001), None);
+}
+
+#[test]
+fn test_coincident_radials() {
+    let gs = &[
+        RadialArea { origin: Vector2::new(383.8, 398.1), area: 2.0 },
+        RadialArea { origin: Vector2::new(383.8, 398.1), area: 1.5 },
+        RadialArea { origin: Vector2::new(383.8, 398.1), area: 1.0 },
+    ];
+
+    let (separated, nudged) = separate_coincident(gs);
+    assert_eq!(nudged, 2);
+    assert_eq!(separated[0].origin, gs[0].origin);
+    assert_eq!(separate_coincident(gs).0.iter().map(|x| x.origin).collect::<Vec<_>>(), separated.iter().map(|x| x.origin).collect::<Vec<_>>());
+
+    let scaled = scale_all(&gs[..2], 0.5, 0.001, 1000, IntersectionMethod::Sampled { samples: 1500 }).unwrap();
+    let d = scaled[0].0.distance(&scaled[1].0);
+    assert!(d > COINCIDENT_EPSILON && d <= NUDGE_DIST + 1e-12, "{}", d);
+    for (c, residual) in scaled.iter() {
+        assert!(c.r.is_finite() && c.r > 0.0);
+        assert!(*residual < 0.001, "residual {} for {}", residual, c);
+    }
+}
+
+#[test]
+fn test_area_conservation() {
+    let gs = &[
+        RadialArea { origin: Vector2::new(0.0, 0.0), area: PI },
+        RadialArea { origin: Vector2::new(0.2, 0.7), area: PI },
+        RadialArea { origin: Vector2::new(1.2, -0.3), area: PI },
+        RadialArea { origin: Vector2::new(-0.4, 0.5), area: PI },
+    ];
+
+    let circles: Vec<Circle> = scale_all(gs, 1.0, 0.001, 200, IntersectionMethod::Sampled { samples: 1500 }).unwrap().into_iter().map(|(c, _)| c).collect();
+    assert!(validate_area_conservation(&sequential_exclusive_areas(&circles), gs) < 0.001 * gs.len() as f64);
+
+    // The naive circles overlap, so they cover less than the target.
+    assert!(validate_area_conservation(&sequential_exclusive_areas(&scale_all_simple(gs)), gs) > 1.0);
+}
+
+#[test]
+fn test_scale_all_residuals() {
+    // Far enough apart that none of the circles touch.
+    let gs = &[
+        RadialArea { origin: Vector2::new(0.0, 0.0), area: PI },
+        RadialArea { origin: Vector2::new(10.0, 0.0), area: 2.0 * PI },
+        RadialArea { origin: Vector2::new(0.0, 10.0), area: 0.5 * PI },
+    ];
+
+    let scaled = scale_all(gs, 1.0, 0.001, 200, IntersectionMethod::Sampled { samples: 1500 }).unwrap();
+
+    assert_eq!(scaled.len(), gs.len());
+    for ((c, residual), radial) in scaled.iter().zip(gs.iter()) {
+        assert!(*residual < 0.001, "residual {} for {}", residual, c);
+        assert!((c.area() - radial.area).abs() < 0.001);
+    }
+}
+
+#[test]
+fn test_intersection_methods() {
+    let gs = &[
+        RadialArea { origin: Vector2::new(0.0, 0.0), area: PI },
+        RadialArea { origin: Vector2::new(0.8, 0.6), area: PI },
+        RadialArea { origin: Vector2::new(-0.5, 0.9), area: 0.5 * PI },
+    ];
+
+    let exact = scale_all(gs, 1.0, 0.001, 200, IntersectionMethod::default()).unwrap();
+    let sampled = scale_all(gs, 1.0, 0.001, 200, IntersectionMethod::Sampled { samples: 400 }).unwrap();
+
+    // 400 samples a side puts the sampled overlap within about half a percent of the exact area.
+    for ((e, _), (s, _)) in exact.iter().zip(sampled.iter()) {
+        assert!((e.r - s.r).abs() < 0.01, "exact {} vs sampled {}", e, s);
+    }
+    assert!(exact[1].0.r > 1.0 && exact[2].0.r > (0.5f64).sqrt());
+}
+
+#[test]
+fn test_scale_all_balanced() {
+    let gs = &[
+        RadialArea { origin: Vector2::new(0.5, 0.5), area: PI },
+        RadialArea { origin: Vector2::new(-0.5, 0.5), area: PI },
+        RadialArea { origin: Vector2::new(-0.5, -0.5), area: PI },
+        RadialArea { origin: Vector2::new(0.5, -0.5), area: PI },
+    ];
+
+    let cs = scale_all_balanced(gs, 0.01, 200, IntersectionMethod::Sampled { samples: 1500 }).unwrap();
+
+    for c in cs.iter() {
+        assert!((c.r - cs[0].r).abs() < 1e-3, "asymmetric radii: {:?}", cs);
+        // Overlaps mean each circle must be larger than its unobstructed radius.
+        assert!(c.r > 1.0);
+    }
+}
+
+#[test]
+fn test_scale_all_simple() {
+    let radials = [
+        RadialArea { origin: Vector2::new(0.0, 0.0), area: 2.0 },
+        RadialArea { origin: Vector2::new(0.5, 0.0), area: 0.7 },
+    ];
+
+    let circles = scale_all_simple(&radials);
+    assert_eq!(circles.len(), 2);
+    for (circle, radial) in circles.iter().zip(radials.iter()) {
+        assert_eq!(circle.origin, radial.origin);
+        assert!((circle.area() - radial.area).abs() < 1e-12);
+    }
+}
+
+#[test]
+fn test_exclusive_areas() {
+    let areas = exclusive_areas(&[
+        Circle::new(0.0, 0.0, 1.0),
+        Circle::new(1.0, 0.0, 1.0),
+        Circle::new(10.0, 0.0, 1.0),
+    ]);
+
+    // Lens of two unit circles a unit apart: 2 acos(1/2) - sqrt(3)/2.
+    let lens = 2.0 * (0.5f64).acos() - 3f64.sqrt() / 2.0;
+    assert_eq!(areas.len(), 3);
+    assert!((areas[0] - (PI - lens)).abs() < 1e-6, "{}", areas[0]);
+    assert!((areas[1] - (PI - lens)).abs() < 1e-6, "{}", areas[1]);
+    assert!((areas[2] - PI).abs() < 1e-12);
+}
+
+#[test]
+fn test_circle_from_radial() {
+    let radial = RadialArea { origin: Vector2::new(383.8, 398.1), area: 2.5 };
+    let circle = Circle::from(&radial);
+
+    assert_eq!(circle.origin, radial.origin);
+    assert!((circle.area() - radial.area).abs() < 1e-12);
+    assert_eq!(circle.to_string(), format!("Circle(383.800, 398.100, r={:.3}, area=2.500)", circle.r));
+}
+
+#[test]
+fn test_coverage_gaps() {
+    let circles = [
+        Circle { origin: Vector2::new(2.0, 2.0), r: 1.0 },
+        Circle { origin: Vector2::new(7.0, 7.0), r: 1.0 },
+    ];
+    let bbox = BBox { min: Vector2::new(0.0, 0.0), max: Vector2::new(10.0, 10.0) };
+
+    let gap = coverage_gaps(&circles, bbox, 200);
+    let expected = 100.0 - 2.0 * PI;
+    assert!((gap - expected).abs() < 0.1, "{}", gap);
+
+    // One gap surrounding both circles, centred near the middle of the box.
+    let regions = coverage_gap_regions(&circles, bbox, 200);
+    assert_eq!(regions.len(), 1);
+    assert!((regions[0].0 - gap).abs() < 1e-9);
+    assert!((regions[0].1 - Vector2::new(5.0, 5.0)).norm() < 0.2);
+
+    // Covering the box leaves nothing.
+    let all = [Circle { origin: Vector2::new(5.0, 5.0), r: 7.1 }];
+    assert_eq!(coverage_gaps(&all, bbox, 50), 0.0);
+    assert!(coverage_gap_regions(&all, bbox, 50).is_empty());
+
+    // A ring splits the gap in two: inside and outside.
+    let ring: Vec<Circle> = (0..36).map(|i| {
+        let a = i as f64 * PI / 18.0;
+        Circle { origin: Vector2::new(5.0 + 3.0 * a.cos(), 5.0 + 3.0 * a.sin()), r: 0.6 }
+    }).collect();
+    let regions = coverage_gap_regions(&ring, bbox, 200);
+    assert_eq!(regions.len(), 2);
+    assert!((regions[1].1 - Vector2::new(5.0, 5.0)).norm() < 0.05);
+
+    let b = BBox::around(&circles).unwrap();
+    assert_eq!((b.min, b.max), (Vector2::new(1.0, 1.0), Vector2::new(8.0, 8.0)));
+}
+
+#[test]
+fn test_convex_hull() {
+    let v = Vector2::new;
+    // A square with points inside and on an edge.
+    let points = [v(0.0, 0.0), v(2.0, 2.0), v(1.0, 1.0), v(2.0, 0.0), v(0.0, 2.0), v(1.0, 0.0), v(0.5, 1.5)];
+    let hull = convex_hull(&points);
+    assert_eq!(hull, vec![v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)]);
+    assert_eq!(hull_area(&points), 4.0);
+
+    // Collinear points give the two ends and no area.
+    let line = [v(1.0, 1.0), v(3.0, 3.0), v(0.0, 0.0), v(2.0, 2.0)];
+    assert_eq!(convex_hull(&line), vec![v(0.0, 0.0), v(3.0, 3.0)]);
+    assert_eq!(hull_area(&line), 0.0);
+
+    // Repeated points collapse.
+    assert_eq!(convex_hull(&[v(1.0, 1.0), v(1.0, 1.0)]), vec![v(1.0, 1.0)]);
+    assert!(convex_hull(&[]).is_empty());
+}
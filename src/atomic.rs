@@ -1,11 +1,14 @@
 use csv::{ReaderBuilder, StringRecord, Writer};
+use encoding_rs::Encoding;
 use geo_rust::{get_postcode_location, Country, GeoLocation, PostalData};
+use rayon::prelude::*;
 use regex::Regex;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{clone, collections::HashMap, error::Error, fs::File, io::{self, Write}, path::Path, process, sync::{Arc, Mutex}};
+use std::{clone, collections::{HashMap, HashSet}, error::Error, fs::File, io::{self, Write}, path::{Path, PathBuf}, process, cmp::Ordering as CmpOrdering, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, OnceLock}, time::{Duration, Instant}};
 use nalgebra::Vector2;
-use proj::Proj;
-use crate::{first_letters, load_regions, AggregatePSchoolRecord, AggregateSchoolRecord, Scaler, CUM_RPI_DEFL};
+use crate::error::ScaError;
+use crate::manifest::Manifest;
+use crate::{csv_reader, first_letters, Phase, PhaseSet, INDEPENDENT_SCHOOL_TYPES, TARGET_SCHOOL_TYPES, to_bng_km, load_regions, new_transform, normalize_postcode, real_price, rpi_deflator, AggregatePSchoolRecord, AggregateSchoolRecord, GeoScaler, Scaler};
 
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -32,7 +35,12 @@ pub struct ProcessedPcodeRecord {
     pub after_covid: u32,
     pub price: f32,
     pub priceper: Option<f32>,
+    pub price_per_room: Option<f32>,
+    /// `priceper` or `price_per_room` is outside the bounds for the sale's LAD and year.
+    #[serde(default)]
+    pub outlier: bool,
     pub rpi_defl: Option<f32>,
+    pub price_real: Option<f32>, // price / rpi_defl, in 2017 prices
     pub tfarea: Option<f32>,
     pub numberrooms: Option<u32>,
     pub classt: Option<u32>,
@@ -47,6 +55,9 @@ pub struct ProcessedPcodeRecord {
     pub nearest_town_dist: Option<f64>,
     pub nearest_admin_name: Option<String>,
     pub nearest_town_popn: Option<u32>,
+    // For postcodes between two towns.
+    pub second_nearest_town_name: Option<String>,
+    pub second_nearest_town_dist: Option<f64>,
 
     pub nearest_city_name: Option<String>,
     pub nearest_city_dist: Option<f64>,
@@ -57,6 +68,9 @@ pub struct ProcessedPcodeRecord {
     // The year used to get the edu data.
     pub sec_est_year: Option<u32>,
     pub prim_est_year: Option<u32>,
+    // 1 if the edu data is from the target year, 0 if it fell back to an earlier year.
+    pub sec_year_exact: Option<u32>,
+    pub prim_year_exact: Option<u32>,
 
     // Secondary
     pub closest_sec_urn: Option<String>,
@@ -75,6 +89,24 @@ pub struct ProcessedPcodeRecord {
     pub weighted_sec_of_sixthform: Option<f32>,
     pub weighted_sec_gcseg2: Option<f32>,
     pub weighted_sec_gcseg2_dis: Option<f32>,
+    // The same without the closest secondary, with `excl_nearest`.
+    pub weighted_sec_of_overall_excl_nearest: Option<f32>,
+    pub weighted_sec_of_educ_excl_nearest: Option<f32>,
+    pub weighted_sec_of_behaviour_excl_nearest: Option<f32>,
+    pub weighted_sec_of_sixthform_excl_nearest: Option<f32>,
+    pub weighted_sec_gcseg2_excl_nearest: Option<f32>,
+    pub weighted_sec_gcseg2_dis_excl_nearest: Option<f32>,
+
+    pub sec_pct_outstanding: Option<f32>,
+    pub sec_pct_good: Option<f32>,
+    pub sec_pct_requires_improvement: Option<f32>,
+    pub sec_pct_inadequate: Option<f32>,
+    pub sec_catchment_centroid_lat: Option<f64>,
+    pub sec_catchment_centroid_lng: Option<f64>,
+
+    // Independent schools, with `include_independent`.
+    pub closest_indep_name: Option<String>,
+    pub closest_indep_dist: Option<f32>,
 
     pub best_sec_gcseg2: Option<f32>,
     pub best_sec_gcseg2_dis: Option<f32>,
@@ -103,6 +135,11 @@ pub struct ProcessedPcodeRecord {
     pub weighted_prim_rwm_ta: Option<f32>,
     pub weighted_prim_rwm_ta_dis: Option<f32>,
 
+    pub prim_pct_outstanding: Option<f32>,
+    pub prim_pct_good: Option<f32>,
+    pub prim_pct_requires_improvement: Option<f32>,
+    pub prim_pct_inadequate: Option<f32>,
+
     pub best_prim_rwm_ta: Option<f32>,
     pub best_prim_rwm_ta_dis: Option<f32>,
     pub best_prim_of_overall: Option<u32>,
@@ -123,7 +160,92 @@ pub struct ProcessedPcodeRecord {
     pub v2_prim_dis: Option<f32>,
 }
 
+/// Price summary of all sales in a single postcode and year.
 #[derive(serde::Serialize, serde::Deserialize)]
+pub struct PostcodePriceSummary {
+    pub pcode: String,
+    pub year: u32,
+    pub n: u32,
+    pub mean_price: Option<f32>,
+    pub median_price: Option<f32>,
+    pub mean_priceper: Option<f32>,
+    pub median_priceper: Option<f32>,
+    pub mean_price_defl: Option<f32>,
+}
+
+/// School metrics around a single postcode, independent of any sales.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct PostcodeSchoolRow {
+    pub pcode: String,
+    pub lat: f64,
+    pub lng: f64,
+
+    // The year used to get the edu data.
+    pub sec_est_year: Option<u32>,
+    pub prim_est_year: Option<u32>,
+    // 1 if the edu data is from the target year, 0 if it fell back to an earlier year.
+    pub sec_year_exact: Option<u32>,
+    pub prim_year_exact: Option<u32>,
+
+    // Secondary
+    pub closest_sec_urn: Option<String>,
+    pub closest_sec_dist: Option<f32>,
+
+    pub weighted_sec_of_overall: Option<f32>,
+    pub weighted_sec_of_educ: Option<f32>,
+    pub weighted_sec_of_behaviour: Option<f32>,
+    pub weighted_sec_of_sixthform: Option<f32>,
+    pub weighted_sec_gcseg2: Option<f32>,
+    pub weighted_sec_gcseg2_dis: Option<f32>,
+    // The same without the closest secondary, with `excl_nearest`.
+    pub weighted_sec_of_overall_excl_nearest: Option<f32>,
+    pub weighted_sec_of_educ_excl_nearest: Option<f32>,
+    pub weighted_sec_of_behaviour_excl_nearest: Option<f32>,
+    pub weighted_sec_of_sixthform_excl_nearest: Option<f32>,
+    pub weighted_sec_gcseg2_excl_nearest: Option<f32>,
+    pub weighted_sec_gcseg2_dis_excl_nearest: Option<f32>,
+
+    pub sec_pct_outstanding: Option<f32>,
+    pub sec_pct_good: Option<f32>,
+    pub sec_pct_requires_improvement: Option<f32>,
+    pub sec_pct_inadequate: Option<f32>,
+    pub sec_catchment_centroid_lat: Option<f64>,
+    pub sec_catchment_centroid_lng: Option<f64>,
+
+    // Independent schools, with `include_independent`.
+    pub closest_indep_name: Option<String>,
+    pub closest_indep_dist: Option<f32>,
+
+    pub best_sec_gcseg2: Option<f32>,
+    pub best_sec_gcseg2_dis: Option<f32>,
+    pub best_sec_of_overall: Option<u32>,
+
+    // Primary
+    pub closest_prim_urn: Option<String>,
+    pub closest_prim_dist: Option<f32>,
+
+    pub weighted_prim_of_overall: Option<f32>,
+    pub weighted_prim_of_educ: Option<f32>,
+    pub weighted_prim_of_behaviour: Option<f32>,
+    pub weighted_prim_rwm_ta: Option<f32>,
+    pub weighted_prim_rwm_ta_dis: Option<f32>,
+
+    pub prim_pct_outstanding: Option<f32>,
+    pub prim_pct_good: Option<f32>,
+    pub prim_pct_requires_improvement: Option<f32>,
+    pub prim_pct_inadequate: Option<f32>,
+
+    pub best_prim_rwm_ta: Option<f32>,
+    pub best_prim_rwm_ta_dis: Option<f32>,
+    pub best_prim_of_overall: Option<u32>,
+
+    pub sec_interact: Option<f32>,
+    pub sec_interact_dis: Option<f32>,
+    pub prim_interact: Option<f32>,
+    pub prim_interact_dis: Option<f32>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct RegionalProcessedPcodeRecord {
     pub year: u32,
     pub id: String,
@@ -131,7 +253,12 @@ pub struct RegionalProcessedPcodeRecord {
     pub after_covid: u32,
     pub price: f32,
     pub priceper: Option<f32>,
+    pub price_per_room: Option<f32>,
+    /// `priceper` or `price_per_room` is outside the bounds for the sale's LAD and year.
+    #[serde(default)]
+    pub outlier: bool,
     pub rpi_defl: Option<f32>,
+    pub price_real: Option<f32>, // price / rpi_defl, in 2017 prices
     pub tfarea: Option<f32>,
     pub numberrooms: Option<u32>,
     pub classt: Option<u32>,
@@ -140,6 +267,7 @@ pub struct RegionalProcessedPcodeRecord {
     pub lad: Option<String>,
     pub region: Option<String>,
     pub pcode_area: Option<String>,
+    pub country: String,
     pub lat: Option<f64>,
     pub lng: Option<f64>,
 
@@ -150,6 +278,9 @@ pub struct RegionalProcessedPcodeRecord {
     pub nearest_town_dist: Option<f64>,
     pub nearest_admin_name: Option<String>,
     pub nearest_town_popn: Option<u32>,
+    // For postcodes between two towns.
+    pub second_nearest_town_name: Option<String>,
+    pub second_nearest_town_dist: Option<f64>,
 
     pub nearest_city_name: Option<String>,
     pub nearest_city_dist: Option<f64>,
@@ -160,6 +291,9 @@ pub struct RegionalProcessedPcodeRecord {
     // The year used to get the edu data.
     pub sec_est_year: Option<u32>,
     pub prim_est_year: Option<u32>,
+    // 1 if the edu data is from the target year, 0 if it fell back to an earlier year.
+    pub sec_year_exact: Option<u32>,
+    pub prim_year_exact: Option<u32>,
 
     // Secondary
     pub closest_sec_urn: Option<String>,
@@ -178,6 +312,24 @@ pub struct RegionalProcessedPcodeRecord {
     pub weighted_sec_of_sixthform: Option<f32>,
     pub weighted_sec_gcseg2: Option<f32>,
     pub weighted_sec_gcseg2_dis: Option<f32>,
+    // The same without the closest secondary, with `excl_nearest`.
+    pub weighted_sec_of_overall_excl_nearest: Option<f32>,
+    pub weighted_sec_of_educ_excl_nearest: Option<f32>,
+    pub weighted_sec_of_behaviour_excl_nearest: Option<f32>,
+    pub weighted_sec_of_sixthform_excl_nearest: Option<f32>,
+    pub weighted_sec_gcseg2_excl_nearest: Option<f32>,
+    pub weighted_sec_gcseg2_dis_excl_nearest: Option<f32>,
+
+    pub sec_pct_outstanding: Option<f32>,
+    pub sec_pct_good: Option<f32>,
+    pub sec_pct_requires_improvement: Option<f32>,
+    pub sec_pct_inadequate: Option<f32>,
+    pub sec_catchment_centroid_lat: Option<f64>,
+    pub sec_catchment_centroid_lng: Option<f64>,
+
+    // Independent schools, with `include_independent`.
+    pub closest_indep_name: Option<String>,
+    pub closest_indep_dist: Option<f32>,
 
     pub best_sec_gcseg2: Option<f32>, // selected by school with highest best_sec_gcseg2_dis
     pub best_sec_gcseg2_dis: Option<f32>,
@@ -200,6 +352,11 @@ pub struct RegionalProcessedPcodeRecord {
     pub weighted_prim_rwm_ta: Option<f32>,
     pub weighted_prim_rwm_ta_dis: Option<f32>,
 
+    pub prim_pct_outstanding: Option<f32>,
+    pub prim_pct_good: Option<f32>,
+    pub prim_pct_requires_improvement: Option<f32>,
+    pub prim_pct_inadequate: Option<f32>,
+
     pub best_prim_rwm_ta: Option<f32>, // selected by school with highest best_prim_rwm_ta_dis
     pub best_prim_rwm_ta_dis: Option<f32>,
     pub best_prim_of_overall: Option<u32>,
@@ -220,6 +377,7 @@ pub struct RegionalProcessedPcodeRecord {
 impl RegionalProcessedPcodeRecord {
     pub fn new(record: ProcessedPcodeRecord, region: Option<String>, pcode_area: Option<String>) -> Self {
         Self {
+            country: country(&record.pcode, region.as_deref()).to_owned(),
             region,
             pcode_area,
             lat: record.lat,
@@ -232,7 +390,10 @@ impl RegionalProcessedPcodeRecord {
             after_covid: record.after_covid,
             price: record.price,
             priceper: record.priceper,
+            price_per_room: record.price_per_room,
+            outlier: record.outlier,
             rpi_defl: record.rpi_defl,
+            price_real: record.price_real,
             tfarea: record.tfarea,
             numberrooms: record.numberrooms,
             classt: record.classt,
@@ -243,12 +404,16 @@ impl RegionalProcessedPcodeRecord {
             nearest_town_name: record.nearest_town_name,
             nearest_admin_name: record.nearest_admin_name,
             nearest_town_popn: record.nearest_town_popn,
+            second_nearest_town_name: record.second_nearest_town_name,
+            second_nearest_town_dist: record.second_nearest_town_dist,
             nearest_city_name: record.nearest_city_name,
             nearest_city_dist: record.nearest_city_dist,
             nearest_city_popn: record.nearest_city_popn,
             dist_london: record.dist_london,
             sec_est_year: record.sec_est_year,
             prim_est_year: record.prim_est_year,
+            sec_year_exact: record.sec_year_exact,
+            prim_year_exact: record.prim_year_exact,
             closest_sec_urn: record.closest_sec_urn,
             closest_sec_name: record.closest_sec_name,
             closest_sec_pcode: record.closest_sec_pcode,
@@ -264,6 +429,20 @@ impl RegionalProcessedPcodeRecord {
             weighted_sec_of_sixthform: record.weighted_sec_of_sixthform,
             weighted_sec_gcseg2: record.weighted_sec_gcseg2,
             weighted_sec_gcseg2_dis: record.weighted_sec_gcseg2_dis,
+            weighted_sec_of_overall_excl_nearest: record.weighted_sec_of_overall_excl_nearest,
+            weighted_sec_of_educ_excl_nearest: record.weighted_sec_of_educ_excl_nearest,
+            weighted_sec_of_behaviour_excl_nearest: record.weighted_sec_of_behaviour_excl_nearest,
+            weighted_sec_of_sixthform_excl_nearest: record.weighted_sec_of_sixthform_excl_nearest,
+            weighted_sec_gcseg2_excl_nearest: record.weighted_sec_gcseg2_excl_nearest,
+            weighted_sec_gcseg2_dis_excl_nearest: record.weighted_sec_gcseg2_dis_excl_nearest,
+            sec_pct_outstanding: record.sec_pct_outstanding,
+            sec_pct_good: record.sec_pct_good,
+            sec_pct_requires_improvement: record.sec_pct_requires_improvement,
+            sec_pct_inadequate: record.sec_pct_inadequate,
+            sec_catchment_centroid_lat: record.sec_catchment_centroid_lat,
+            sec_catchment_centroid_lng: record.sec_catchment_centroid_lng,
+            closest_indep_name: record.closest_indep_name,
+            closest_indep_dist: record.closest_indep_dist,
             best_sec_gcseg2: record.best_sec_gcseg2,
             best_sec_gcseg2_dis: record.best_sec_gcseg2_dis,
             best_sec_of_overall: record.best_sec_of_overall,
@@ -281,6 +460,10 @@ impl RegionalProcessedPcodeRecord {
             weighted_prim_of_behaviour: record.weighted_prim_of_behaviour,
             weighted_prim_rwm_ta: record.weighted_prim_rwm_ta,
             weighted_prim_rwm_ta_dis: record.weighted_prim_rwm_ta_dis,
+            prim_pct_outstanding: record.prim_pct_outstanding,
+            prim_pct_good: record.prim_pct_good,
+            prim_pct_requires_improvement: record.prim_pct_requires_improvement,
+            prim_pct_inadequate: record.prim_pct_inadequate,
             best_prim_rwm_ta: record.best_prim_rwm_ta,
             best_prim_rwm_ta_dis: record.best_prim_rwm_ta_dis,
             best_prim_of_overall: record.best_prim_of_overall,
@@ -304,6 +487,9 @@ pub struct TownRecord {
     admin_name: String,
     population: u32,
     population_proper: u32,
+    /// "town" or "city" when the source labels its places; otherwise the population decides.
+    #[serde(default)]
+    place_type: Option<String>,
 }
 
 #[derive(Clone)]
@@ -312,7 +498,14 @@ pub struct Town {
     loc: GeoLocation,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Towns and cities, searched separately for the closest of each.
+#[derive(Clone, Default)]
+pub struct Places {
+    pub towns: Vec<Town>,
+    pub cities: Vec<Town>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GeoRecord {
     pcode: String,
     lat: f64,
@@ -326,7 +519,7 @@ pub struct RegionRecord {
     region: String,
 }
 
-pub fn parse_cities<P: AsRef<Path>>(path: P) -> Result<Vec<Town>, Box<dyn Error>> {
+pub fn parse_cities<P: AsRef<Path>>(path: P) -> Result<Vec<Town>, ScaError> {
 
     let mut cities = Vec::new();
 
@@ -351,7 +544,317 @@ pub fn parse_cities<P: AsRef<Path>>(path: P) -> Result<Vec<Town>, Box<dyn Error>
     Ok(cities)
 }  
 
-pub fn parse_postcodes<P: AsRef<Path>>(path: P, region_map: &HashMap<String, String>, year_range: std::ops::Range<u32>) -> Result<HashMap<String, Vec<(PcodeRecord, Option<String>)>>, Box<dyn Error>> {
+/// Default population above which an unlabelled place counts as a city.
+pub const CITY_POPULATION: u32 = 100_000;
+
+/// Loads towns and cities from one file. Places with a `place_type` column of "city" or "town" are
+/// split on that; the rest are cities when their population is at least `city_population`.
+pub fn parse_places<P: AsRef<Path>>(path: P, city_population: u32) -> Result<Places, ScaError> {
+    let mut places = Places::default();
+
+    for place in parse_cities(path)? {
+        let is_city = match place.record.place_type.as_deref().map(|x| x.trim().to_lowercase()) {
+            Some(t) if t == "city" => true,
+            Some(t) if t == "town" => false,
+            _ => place.record.population >= city_population,
+        };
+        if is_city {
+            places.cities.push(place);
+        } else {
+            places.towns.push(place);
+        }
+    }
+
+    Ok(places)
+}
+
+/// The closest place to `loc` and its distance in km.
+fn closest_place<'a>(loc: &GeoLocation, places: &'a [Town]) -> Option<(&'a Town, f64)> {
+    two_closest_places(loc, places)[0]
+}
+
+/// The closest and second closest places to `loc`, with their distances in km.
+fn two_closest_places<'a>(loc: &GeoLocation, places: &'a [Town]) -> [Option<(&'a Town, f64)>; 2] {
+    let mut closest: [Option<(&Town, f64)>; 2] = [None, None];
+    for place in places {
+        let dist = haversine_km(loc, &place.loc);
+        if closest[0].map(|(_, x)| dist < x).unwrap_or(true) {
+            closest[1] = closest[0];
+            closest[0] = Some((place, dist));
+        } else if closest[1].map(|(_, x)| dist < x).unwrap_or(true) {
+            closest[1] = Some((place, dist));
+        }
+    }
+    closest
+}
+
+/// Sale, location and area columns.
+const MINIMAL_COLUMNS: [&str; 14] = [
+    "year", "id", "pcode", "price", "priceper", "price_real", "tfarea", "numberrooms", "propertytype",
+    "lad", "region", "country", "lat", "lng",
+];
+
+/// The main school columns, on top of `MINIMAL_COLUMNS`.
+const SCHOOL_COLUMNS: [&str; 12] = [
+    "closest_sec_urn", "closest_sec_dist", "closest_sec_gcseg2", "weighted_sec_gcseg2", "weighted_sec_of_overall", "best_sec_gcseg2",
+    "closest_prim_urn", "closest_prim_dist", "closest_prim_rwm_ta", "weighted_prim_rwm_ta", "weighted_prim_of_overall", "best_prim_rwm_ta",
+];
+
+/// Which columns of `RegionalProcessedPcodeRecord` the atomic output keeps. `process_sales` adds `pcode` to a
+/// `Custom` set without it, as resuming from a checkpoint relies on it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ColumnSet {
+    #[default]
+    Full,
+    /// Sale, location and area columns.
+    Minimal,
+    /// `Minimal` plus the closest, weighted and best school columns.
+    Schools,
+    /// These columns, in this order.
+    Custom(Vec<String>),
+}
+
+impl ColumnSet {
+    /// The columns to write, in order, or `None` for all of them.
+    pub fn columns(&self) -> Option<Vec<&str>> {
+        match self {
+            Self::Full => None,
+            Self::Minimal => Some(MINIMAL_COLUMNS.to_vec()),
+            Self::Schools => Some(MINIMAL_COLUMNS.iter().chain(SCHOOL_COLUMNS.iter()).copied().collect()),
+            Self::Custom(columns) => Some(columns.iter().map(|x| x.as_str()).collect()),
+        }
+    }
+
+    /// This set, with `pcode` first if it's a `Custom` set without it. The presets all include it.
+    pub fn with_pcode(&self) -> Self {
+        match self {
+            Self::Custom(columns) if !columns.iter().any(|x| x == "pcode") => {
+                Self::Custom(std::iter::once("pcode".to_owned()).chain(columns.iter().cloned()).collect())
+            }
+            _ => self.clone(),
+        }
+    }
+}
+
+/// Decimal places to round the float columns of the output to. `None` keeps full precision.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Precision {
+    /// The distance columns (`PCODE_DISTANCE_COLUMNS`).
+    pub distances: Option<usize>,
+    /// Every other float column: prices, locations, school metrics and proportions.
+    pub values: Option<usize>,
+}
+
+impl Precision {
+    pub fn is_full(&self) -> bool {
+        self.distances.is_none() && self.values.is_none()
+    }
+
+    fn decimals(&self, column: &str) -> Option<usize> {
+        if PCODE_DISTANCE_COLUMNS.contains(&column) {
+            self.distances
+        } else {
+            self.values
+        }
+    }
+}
+
+/// `field` rounded to `decimals` places if it is a float, e.g. "1.2384717" to "1.24" for 2. Whole numbers
+/// ("2019", "100000.0") and anything else are left as they are, rather than padded with zeros.
+pub fn round_field(field: &str, decimals: usize) -> String {
+    match field.parse::<f64>() {
+        Ok(x) if x.is_finite() && x.fract() != 0.0 => format!("{:.*}", decimals, x),
+        _ => field.to_owned(),
+    }
+}
+
+/// Every column of a full output row, in order.
+fn full_columns() -> Vec<String> {
+    static COLUMNS: OnceLock<Arc<Vec<String>>> = OnceLock::new();
+    let columns = cached(&COLUMNS, || {
+        let mut buf = Writer::from_writer(Vec::new());
+        buf.serialize(RegionalProcessedPcodeRecord::default()).expect("default row serializes");
+        let buf = buf.into_inner().expect("in-memory writer");
+        let mut rdr = ReaderBuilder::new().from_reader(buf.as_slice());
+        rdr.headers().expect("serialized header").iter().map(str::to_owned).collect()
+    });
+    columns.to_vec()
+}
+
+/// Writes `record` keeping only the columns of `set`, with its floats rounded to `precision`. `Full` at full
+/// precision serializes it as is (writing the header on the first row); otherwise the header must already
+/// have been written, as `open_output` does with `AtomicConfig::output_columns`.
+pub fn write_columns<W: Write, T: Serialize>(writer: &mut Writer<W>, record: &T, set: &ColumnSet, precision: Precision) -> Result<(), ScaError> {
+    let columns = set.columns();
+    if columns.is_none() && precision.is_full() {
+        writer.serialize(record)?;
+        return Ok(());
+    }
+
+    // Round trip through an in-memory CSV to get each field as it would be written.
+    let mut buf = Writer::from_writer(Vec::new());
+    buf.serialize(record)?;
+    let buf = buf.into_inner().map_err(|e| ScaError::Io(e.into_error()))?;
+    let mut rdr = ReaderBuilder::new().from_reader(buf.as_slice());
+    let headers = rdr.headers()?.clone();
+    let row = rdr.records().next().ok_or_else(|| ScaError::Missing("serialized row".to_owned()))??;
+
+    let columns = columns.unwrap_or_else(|| headers.iter().collect());
+    let mut out = StringRecord::with_capacity(0, columns.len());
+    for column in columns {
+        let i = headers.iter().position(|x| x == column).ok_or_else(|| ScaError::Missing(format!("output column {}", column)))?;
+        match precision.decimals(column) {
+            Some(decimals) => out.push_field(&round_field(&row[i], decimals)),
+            None => out.push_field(&row[i]),
+        }
+    }
+    writer.write_record(&out)?;
+    Ok(())
+}
+
+/// Options for the postcode aggregation run.
+#[derive(Clone, Debug)]
+pub struct AtomicConfig {
+    /// Keep Welsh postcodes (flagged through the `country` column) rather than dropping them.
+    pub include_wales: bool,
+    /// Only use sixth-form providing secondaries (a sixth form Ofsted grade or a 16-19 school type)
+    /// for the closest, weighted and best secondary metrics.
+    pub post16_only: bool,
+    /// When set, only schools whose `school_type` is in the set count towards the closest, weighted
+    /// and best school metrics.
+    pub type_filter: Option<HashSet<String>>,
+    /// Which schools enter the weighted and best school metrics.
+    pub weight_scope: WeightScope,
+    /// Schools (by URN) left out of the closest, weighted and best school metrics, e.g. the school at
+    /// the postcode itself.
+    pub exclude_urns: HashSet<String>,
+    /// Unit of the distance columns in the output.
+    pub distance_unit: DistanceUnit,
+    /// Which columns of the output to write.
+    pub columns: ColumnSet,
+    /// Compute the weighted and best school metrics (and Ofsted grade shares). Without them only the
+    /// closest schools are found, which is much quicker.
+    pub compute_weighted: bool,
+    /// Rivers, motorways and the like that school distances have to go around. Only used where both the
+    /// postcode and the school have a BNG position.
+    pub barriers: Vec<Barrier>,
+    /// Postcodes to list the schools behind the weighted metrics for, in `contributors.csv`. Empty for a
+    /// normal run.
+    pub debug_postcodes: HashSet<String>,
+    /// Flags sales outside these bounds in the `outlier` column. Not flagged when `None`.
+    pub outliers: Option<OutlierBounds>,
+    /// The lower and upper percentiles (0 to 100, e.g. `(1.0, 99.0)`) of each LAD-year's sales that
+    /// `process_sales` builds `outliers` from. Nothing is flagged when `None`.
+    pub outlier_percentiles: Option<(f32, f32)>,
+    /// How the disadvantaged score of the best school is picked when the best score is tied.
+    pub best_tie_break: TieBreak,
+    /// Encoding of the sales file read by `parse_postcodes`.
+    pub encoding: &'static Encoding,
+    /// Each school's weight is multiplied by this for every year its record (`year`) predates the sale. Only
+    /// a school list mixing years has its weighted metrics moved towards the fresher schools; the per-year
+    /// lists `run_atomic` builds hold a single year each, so there this has no effect on results and only
+    /// shows in the weights of `contributors.csv`.
+    pub stale_decay: Option<f32>,
+    /// Find the closest independent school (`closest_indep_*`) among the secondaries. They never enter the
+    /// state school metrics either way.
+    pub include_independent: bool,
+    /// Treat selective state (grammar) schools like the other state secondaries in the closest, weighted and
+    /// best metrics, rather than skipping them. Selective independent schools stay out.
+    pub include_selective: bool,
+    /// Bytes buffered before the output is written to disk.
+    pub output_buffer: usize,
+    /// Flush the output every this many rows (per thread), so a crash loses less. Checkpoints flush too.
+    /// `0` leaves it to the buffer and checkpoints.
+    pub flush_every: usize,
+    /// Which phases to load and scan. The columns of a phase left out stay empty.
+    pub phases: PhaseSet,
+    /// Rounding of the float columns in the output. Full precision by default.
+    pub precision: Precision,
+    /// Also compute the weighted secondary metrics without the closest school (`weighted_sec_*_excl_nearest`),
+    /// to see how much one dominant neighbour drives them.
+    pub excl_nearest: bool,
+    /// Also write a GeoJSON of each debug postcode's links to its contributing schools, per year, to this
+    /// directory (see `debug_links_geojson`).
+    pub debug_links_dir: Option<PathBuf>,
+    /// Parse and process the sales this many years at a time, rather than the whole range at once. Only
+    /// one window of sales is held in memory, at the cost of reading the sales file once per window.
+    pub year_window: Option<u32>,
+}
+
+/// Default `AtomicConfig::output_buffer`, large enough that writing whole rows doesn't bottleneck the threads.
+pub const DEFAULT_OUTPUT_BUFFER: usize = 1 << 20;
+
+impl Default for AtomicConfig {
+    fn default() -> Self {
+        Self {
+            include_wales: false,
+            post16_only: false,
+            type_filter: None,
+            weight_scope: WeightScope::WithinDist(MAX_DIST),
+            exclude_urns: HashSet::new(),
+            distance_unit: DistanceUnit::Km,
+            columns: ColumnSet::Full,
+            compute_weighted: true,
+            barriers: Vec::new(),
+            debug_postcodes: HashSet::new(),
+            outliers: None,
+            outlier_percentiles: None,
+            best_tie_break: TieBreak::Ofsted,
+            encoding: encoding_rs::UTF_8,
+            stale_decay: None,
+            include_independent: false,
+            include_selective: false,
+            output_buffer: DEFAULT_OUTPUT_BUFFER,
+            flush_every: 10_000,
+            phases: PhaseSet::Both,
+            year_window: None,
+            debug_links_dir: None,
+            precision: Precision::default(),
+            excl_nearest: false,
+        }
+    }
+}
+
+impl AtomicConfig {
+    /// Whether a school of this type passes `type_filter`.
+    pub fn type_allowed(&self, school_type: &str) -> bool {
+        self.type_filter.as_ref().map(|x| x.contains(school_type)).unwrap_or(true)
+    }
+
+    /// The weight multiplier for a school record from `school_year` in a scan for a sale in `sale_year`.
+    fn stale_weight(&self, sale_year: Option<u32>, school_year: u32) -> f32 {
+        match (self.stale_decay, sale_year) {
+            (Some(decay), Some(year)) => decay.powi(year.saturating_sub(school_year) as i32),
+            _ => 1.0,
+        }
+    }
+
+    /// The schools standing in for `year` (see `schools_for_year`), or none when `phase` isn't computed.
+    fn phase_schools<'a, T>(&self, phase: Phase, map: &'a HashMap<u32, Vec<T>>, year: u32, year_range: &std::ops::Range<u32>) -> Option<(u32, &'a Vec<T>)> {
+        schools_for_year(map, year, year_range).filter(|_| self.phases.includes(phase))
+    }
+
+    /// The columns to open the output with. A rounded `Full` output is listed out, as its rows are written
+    /// field by field rather than serialized with their header.
+    pub fn output_columns(&self) -> ColumnSet {
+        if self.columns == ColumnSet::Full && !self.precision.is_full() {
+            ColumnSet::Custom(full_columns())
+        } else {
+            self.columns.clone()
+        }
+    }
+
+    /// How far out a school can still matter beyond the closest one, if there is a limit.
+    fn reach(&self) -> Option<f32> {
+        if self.compute_weighted {
+            self.weight_scope.reach()
+        } else {
+            Some(0.0)
+        }
+    }
+}
+
+pub fn parse_postcodes<P: AsRef<Path>>(path: P, region_map: &HashMap<String, String>, year_range: std::ops::Range<u32>, config: &AtomicConfig) -> Result<HashMap<String, Vec<(PcodeRecord, Option<String>)>>, ScaError> {
     let mut pcodes: HashMap<String, Vec<(PcodeRecord, Option<String>)>> = HashMap::new();
 
     // let mut rdr = ReaderBuilder::new()
@@ -371,17 +874,16 @@ pub fn parse_postcodes<P: AsRef<Path>>(path: P, region_map: &HashMap<String, Str
     //     }
     // }
 
-    let mut rdr = ReaderBuilder::new()
-    //.has_headers(true)
-    //.flexible(true)
-    .from_path(path)?;
+    let mut rdr = csv_reader(path, config.encoding)?;
     let mut iter = rdr.deserialize::<PcodeRecord>();
 
     for result in iter {
         match result {
-            Ok(record) => {
+            Ok(mut record) => {
+                // Group by the normalized postcode, so spacing and case variants of one postcode share a group.
+                record.postcode = normalize_postcode(&record.postcode);
                 if year_range.contains(&record.year) {
-                    if valid_region(&record.postcode) {
+                    if config.include_wales || valid_region(&record.postcode) {
                         let lad = region_map.get(&record.postcode);
                         if let Some(v) = pcodes.get_mut(&record.postcode) {
                             v.push((record, lad.cloned()));
@@ -397,10 +899,307 @@ pub fn parse_postcodes<P: AsRef<Path>>(path: P, region_map: &HashMap<String, Str
         }
     }
 
+    let mut duplicates = 0;
+    for records in pcodes.values_mut() {
+        duplicates += dedup_by_id(records);
+    }
+    println!("Removed {} duplicate transactions", duplicates);
+
     Ok(pcodes)
 }
 
-pub fn load_school_data<P: AsRef<Path>, S: DeserializeOwned>(path: P) -> Result<Vec<S>, Box<dyn Error>> {
+/// Removes records sharing a transaction `id` (e.g. corrections in the PPD), keeping the last occurrence.
+/// Returns the number of records removed.
+pub fn dedup_by_id(records: &mut Vec<(PcodeRecord, Option<String>)>) -> usize {
+    let len = records.len();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    // Walk backwards so that the last occurrence is the one kept.
+    let mut kept: Vec<(PcodeRecord, Option<String>)> = records
+        .drain(..)
+        .rev()
+        .filter(|(record, _)| seen.insert(record.id.clone()))
+        .collect();
+    kept.reverse();
+    *records = kept;
+
+    len - records.len()
+}
+
+/// Summarises sale prices per postcode per year. Output is ordered by postcode then year.
+pub fn aggregate_pcode_prices(pcodes: &HashMap<String, Vec<(PcodeRecord, Option<String>)>>, year_range: std::ops::Range<u32>) -> Vec<PostcodePriceSummary> {
+    let mut summaries = Vec::new();
+
+    for (pcode, records) in pcodes.iter() {
+        let mut by_year: HashMap<u32, (u32, Scaler, Scaler, Scaler)> = HashMap::new();
+        for (record, _) in records {
+            if !year_range.contains(&record.year) {
+                continue;
+            }
+            let (n, price, priceper, price_defl) = by_year.entry(record.year).or_insert_with(|| (0, Scaler::new(), Scaler::new(), Scaler::new()));
+            *n += 1;
+            price.add(record.price, 1.0);
+            if let Some(x) = record.priceper {
+                priceper.add(x, 1.0);
+            }
+            if let Some(x) = real_price(record.price, rpi_deflator(record.year)) {
+                price_defl.add(x, 1.0);
+            }
+        }
+
+        for (year, (n, price, priceper, price_defl)) in by_year {
+            summaries.push(PostcodePriceSummary {
+                pcode: pcode.clone(),
+                year,
+                n,
+                mean_price: price.ave(),
+                median_price: price.median(),
+                mean_priceper: priceper.ave(),
+                median_priceper: priceper.median(),
+                mean_price_defl: price_defl.ave(),
+            });
+        }
+    }
+
+    summaries.sort_by(|a, b| a.pcode.cmp(&b.pcode).then(a.year.cmp(&b.year)));
+    summaries
+}
+
+/// Price divided by the number of rooms, where there are any.
+pub fn price_per_room(price: f32, rooms: Option<u32>) -> Option<f32> {
+    rooms.filter(|x| *x > 0).map(|x| price / x as f32)
+}
+
+/// Percentile `p` (0 to 100) of `sorted`, interpolating linearly between neighbouring values.
+fn percentile(sorted: &[f32], p: f32) -> Option<f32> {
+    let last = sorted.len().checked_sub(1)?;
+    let rank = (p / 100.0).clamp(0.0, 1.0) * last as f32;
+    let (i, frac) = (rank.floor() as usize, rank.fract());
+    Some(sorted[i] + frac * (sorted[(i + 1).min(last)] - sorted[i]))
+}
+
+/// Per LAD and year ranges of `priceper` and price per room, outside which a sale is flagged as an outlier.
+/// Sales with no LAD are grouped under `UNKNOWN_LAD`.
+#[derive(Clone, Debug, Default)]
+pub struct OutlierBounds {
+    /// `[priceper, price_per_room]` bounds, each `None` without any values.
+    by_lad_year: HashMap<(String, u32), [Option<(f32, f32)>; 2]>,
+}
+
+impl OutlierBounds {
+    /// The `lower` and `upper` percentiles of each LAD-year's `priceper` and price per room, over all of
+    /// `postcodes`. Built before anything is written, so pass in every sale even when resuming.
+    pub fn from_postcodes(postcodes: &HashMap<String, Vec<(PcodeRecord, Option<String>)>>, lower: f32, upper: f32) -> Self {
+        let mut values: HashMap<(String, u32), [Vec<f32>; 2]> = HashMap::new();
+        for (record, lad) in postcodes.values().flatten() {
+            let key = (lad.clone().unwrap_or_else(|| UNKNOWN_LAD.to_owned()), record.year);
+            let [priceper, per_room] = values.entry(key).or_default();
+            priceper.extend(record.priceper);
+            per_room.extend(price_per_room(record.price, record.numberrooms.as_deref().and_then(parse_rooms)));
+        }
+
+        let bounds = |mut x: Vec<f32>| {
+            x.retain(|v| v.is_finite());
+            x.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            Some((percentile(&x, lower)?, percentile(&x, upper)?))
+        };
+        let by_lad_year = values.into_iter().map(|(k, [a, b])| (k, [bounds(a), bounds(b)])).collect();
+        Self { by_lad_year }
+    }
+
+    /// Whether `priceper` or `price_per_room` lies outside the bounds for `lad` in `year`.
+    pub fn is_outlier(&self, lad: Option<&str>, year: u32, priceper: Option<f32>, price_per_room: Option<f32>) -> bool {
+        let Some(bounds) = self.by_lad_year.get(&(lad.unwrap_or(UNKNOWN_LAD).to_owned(), year)) else {
+            return false;
+        };
+        let outside = |x: Option<f32>, b: Option<(f32, f32)>| matches!((x, b), (Some(x), Some((lo, hi))) if x < lo || x > hi);
+        outside(priceper, bounds[0]) || outside(price_per_room, bounds[1])
+    }
+}
+
+/// Sale and school summary of all sales in a single LAD and year.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LadYearSummary {
+    pub lad: String,
+    pub year: u32,
+    pub n: u32,
+    pub mean_price_real: Option<f32>,
+    pub mean_closest_sec_gcseg2: Option<f32>,
+}
+
+/// The group of sales without a LAD in `summarize_by_lad`.
+pub const UNKNOWN_LAD: &str = "Unknown";
+
+/// Summarises the processed sales by `(lad, year)`, ordered by LAD then year.
+pub fn summarize_by_lad(records: &[RegionalProcessedPcodeRecord]) -> Vec<LadYearSummary> {
+    let mut by_lad: HashMap<(&str, u32), (u32, Scaler, Scaler)> = HashMap::new();
+    for record in records {
+        let lad = record.lad.as_deref().unwrap_or(UNKNOWN_LAD);
+        let (n, price_real, gcseg2) = by_lad.entry((lad, record.year)).or_insert_with(|| (0, Scaler::new(), Scaler::new()));
+        *n += 1;
+        if let Some(x) = record.price_real {
+            price_real.add(x, 1.0);
+        }
+        if let Some(x) = record.closest_sec_gcseg2 {
+            gcseg2.add(x, 1.0);
+        }
+    }
+
+    let mut summaries: Vec<LadYearSummary> = by_lad
+        .into_iter()
+        .map(|((lad, year), (n, price_real, gcseg2))| LadYearSummary {
+            lad: lad.to_owned(),
+            year,
+            n,
+            mean_price_real: price_real.ave(),
+            mean_closest_sec_gcseg2: gcseg2.ave(),
+        })
+        .collect();
+    summaries.sort_by(|a, b| a.lad.cmp(&b.lad).then(a.year.cmp(&b.year)));
+    summaries
+}
+
+/// Writes `summarize_by_lad` of the processed sales in `input` to `out`.
+pub fn write_lad_summary<P1: AsRef<Path>, P2: AsRef<Path>>(input: P1, out: P2) -> Result<(), ScaError> {
+    let records: Vec<RegionalProcessedPcodeRecord> = load_school_data(input)?;
+    let mut writer = Writer::from_path(out)?;
+    for summary in summarize_by_lad(&records) {
+        writer.serialize(&summary)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// A column whose value differs between two rows with the same key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnChange {
+    pub column: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The differences between two output CSVs joined by key. Keys are the values of the key columns.
+#[derive(Debug, Default, PartialEq)]
+pub struct DiffReport {
+    /// Keys only in the new file, in its order.
+    pub added: Vec<Vec<String>>,
+    /// Keys only in the old file, in its order.
+    pub removed: Vec<Vec<String>>,
+    /// Matched rows with at least one differing column, in the old file's order.
+    pub changed: Vec<(Vec<String>, Vec<ColumnChange>)>,
+    /// Columns only in the new or old file, which are not compared.
+    pub added_columns: Vec<String>,
+    pub removed_columns: Vec<String>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} added, {} removed, {} changed", self.added.len(), self.removed.len(), self.changed.len())?;
+        for column in &self.added_columns {
+            writeln!(f, "+ column {}", column)?;
+        }
+        for column in &self.removed_columns {
+            writeln!(f, "- column {}", column)?;
+        }
+        for key in &self.added {
+            writeln!(f, "+ {}", key.join(","))?;
+        }
+        for key in &self.removed {
+            writeln!(f, "- {}", key.join(","))?;
+        }
+        for (key, changes) in &self.changed {
+            for change in changes {
+                writeln!(f, "~ {} {}: {:?} -> {:?}", key.join(","), change.column, change.old, change.new)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a CSV into its header and rows keyed by `key_columns`. A repeated key keeps its first row.
+fn keyed_rows<P: AsRef<Path>>(path: P, key_columns: &[&str]) -> Result<(StringRecord, Vec<(Vec<String>, StringRecord)>), ScaError> {
+    let mut rdr = ReaderBuilder::new().from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    let key_idx = key_columns
+        .iter()
+        .map(|k| headers.iter().position(|h| h == *k).ok_or_else(|| ScaError::Missing(format!("key column {}", k))))
+        .collect::<Result<Vec<usize>, _>>()?;
+
+    let mut seen = HashSet::new();
+    let mut rows = Vec::new();
+    for record in rdr.records() {
+        let record = record?;
+        let key: Vec<String> = key_idx.iter().map(|&i| record.get(i).unwrap_or("").to_owned()).collect();
+        if seen.insert(key.clone()) {
+            rows.push((key, record));
+        }
+    }
+    Ok((headers, rows))
+}
+
+/// Compares two `RegionalProcessedPcodeRecord` outputs, e.g. before and after a code change, joining rows by
+/// `key_columns` (usually `["id"]`). Values are compared as written, so `0.5` and `0.50` differ. Works on any
+/// column subset, comparing the columns the files share.
+pub fn diff_outputs<P1: AsRef<Path>, P2: AsRef<Path>>(a: P1, b: P2, key_columns: &[&str]) -> Result<DiffReport, ScaError> {
+    let (old_headers, old_rows) = keyed_rows(a, key_columns)?;
+    let (new_headers, new_rows) = keyed_rows(b, key_columns)?;
+
+    let mut report = DiffReport::default();
+    report.added_columns = new_headers.iter().filter(|h| !old_headers.iter().any(|x| x == *h)).map(str::to_owned).collect();
+    report.removed_columns = old_headers.iter().filter(|h| !new_headers.iter().any(|x| x == *h)).map(str::to_owned).collect();
+    // (column, old index, new index) of the shared columns.
+    let shared: Vec<(&str, usize, usize)> = old_headers
+        .iter()
+        .enumerate()
+        .filter_map(|(i, h)| Some((h, i, new_headers.iter().position(|x| x == h)?)))
+        .collect();
+
+    let new_by_key: HashMap<&[String], &StringRecord> = new_rows.iter().map(|(k, r)| (k.as_slice(), r)).collect();
+    let old_keys: HashSet<&[String]> = old_rows.iter().map(|(k, _)| k.as_slice()).collect();
+
+    for (key, old) in &old_rows {
+        let Some(new) = new_by_key.get(key.as_slice()) else {
+            report.removed.push(key.clone());
+            continue;
+        };
+        let changes: Vec<ColumnChange> = shared
+            .iter()
+            .filter_map(|&(column, i, j)| {
+                let (old, new) = (old.get(i).unwrap_or(""), new.get(j).unwrap_or(""));
+                (old != new).then(|| ColumnChange { column: column.to_owned(), old: old.to_owned(), new: new.to_owned() })
+            })
+            .collect();
+        if !changes.is_empty() {
+            report.changed.push((key.clone(), changes));
+        }
+    }
+    report.added = new_rows.iter().filter(|(k, _)| !old_keys.contains(k.as_slice())).map(|(k, _)| k.clone()).collect();
+
+    Ok(report)
+}
+
+/// Loads the schools of `phase` from `path`, or none when `config.phases` leaves it out, in which case the file
+/// needn't exist. The file is recorded in `manifest` when it is read.
+fn load_phase_schools<S: DeserializeOwned>(path: &str, phase: Phase, config: &AtomicConfig, manifest: Option<&mut Manifest>) -> Result<Vec<S>, ScaError> {
+    if !config.phases.includes(phase) {
+        println!("Skipping {} schools", phase.name());
+        return Ok(Vec::new());
+    }
+    if let Some(manifest) = manifest {
+        manifest.add_input(path);
+    }
+    let schools: Vec<S> = load_school_data(path)?;
+    println!("Loaded {} {} schools", schools.len(), phase.name());
+    Ok(schools)
+}
+
+pub fn load_school_data<P: AsRef<Path>, S: DeserializeOwned>(path: P) -> Result<Vec<S>, ScaError> {
     let mut rdr = ReaderBuilder::new()
     //.has_headers(true)
     //.flexible(true)
@@ -415,6 +1214,7 @@ pub struct GeoData<W: Write> {
     map: HashMap<String, GeoRecord>,
 }
 
+#[derive(Clone)]
 pub struct CGeoData {
     map: HashMap<String, GeoRecord>
 }
@@ -442,7 +1242,7 @@ pub struct CGeoData {
 //     Ok(GeoData { writer, map })
 // }
 
-pub fn load_geo_data<P: AsRef<Path>>(path: P) -> Result<CGeoData, Box<dyn Error>> {
+pub fn load_geo_data<P: AsRef<Path>>(path: P) -> Result<CGeoData, ScaError> {
     
     let map = {
         let mut map = HashMap::new();
@@ -453,7 +1253,7 @@ pub fn load_geo_data<P: AsRef<Path>>(path: P) -> Result<CGeoData, Box<dyn Error>
             let mut iter = rdr.deserialize::<GeoRecord>();
             for record in iter {
                 if let Ok(record) = record {
-                    map.insert(record.pcode.clone(), record);
+                    map.insert(normalize_postcode(&record.pcode), record);
                 }
             }
         }
@@ -463,7 +1263,7 @@ pub fn load_geo_data<P: AsRef<Path>>(path: P) -> Result<CGeoData, Box<dyn Error>
     Ok(CGeoData { map })
 }
 
-pub fn load_regional_data<P: AsRef<Path>>(path: P) -> Result<HashMap<String, RegionRecord>, Box<dyn Error>> {
+pub fn load_regional_data<P: AsRef<Path>>(path: P) -> Result<HashMap<String, RegionRecord>, ScaError> {
     let mut map = HashMap::new();
     if let Ok(mut rdr) = ReaderBuilder::new()
     //.has_headers(true)
@@ -495,11 +1295,30 @@ pub fn load_regional_data<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Reg
 //     }
 // }
 
+static POSTAL_DATA: OnceLock<Arc<Vec<PostalData>>> = OnceLock::new();
+
+/// Returns the value in `cell`, calling `load` for it the first time.
+fn cached<T>(cell: &OnceLock<Arc<T>>, load: impl FnOnce() -> T) -> Arc<T> {
+    cell.get_or_init(|| Arc::new(load())).clone()
+}
+
+/// The geonames UK postcode data. It is downloaded and parsed on first use, then shared for the rest of
+/// the process.
+pub fn postal_data() -> Arc<Vec<PostalData>> {
+    cached(&POSTAL_DATA, || geo_rust::get_postal_data(Country::UnitedKingdomFull))
+}
+
+/// Loads `postal_data` now, e.g. before spawning the threads that use it.
+pub fn preload_postal_data() {
+    postal_data();
+}
+
 pub fn geo_data(pcode: &str, map: &CGeoData, geonames_data: &[PostalData]) -> Option<GeoLocation> {
-    if let Some(v) = map.map.get(pcode.trim()) {
+    let pcode = normalize_postcode(pcode);
+    if let Some(v) = map.map.get(&pcode) {
         Some(GeoLocation { latitude: v.lat, longitude: v.long })
     } else {
-        if let Some(d) = get_postcode_location(pcode.trim(), geonames_data) {
+        if let Some(d) = get_postcode_location(&pcode, geonames_data) {
             Some(d)
         } else {
             None
@@ -507,296 +1326,942 @@ pub fn geo_data(pcode: &str, map: &CGeoData, geonames_data: &[PostalData]) -> Op
     }
 }
 
+/// Locates all of `postcodes` up front, as `geo_data` would one at a time, so the aggregation threads only
+/// look in `cache`. Postcodes missing from it are looked up in `geonames_data` in parallel and added. Returns
+/// how many of the (normalized) postcodes have a location, those found in neither being left out.
+pub fn geocode_all(postcodes: &[String], geonames_data: &[PostalData], cache: &mut CGeoData) -> usize {
+    let pcodes: HashSet<String> = postcodes.iter().map(|x| normalize_postcode(x)).collect();
+    let missing: Vec<&String> = pcodes.iter().filter(|x| !cache.map.contains_key(*x)).collect();
+    let found: Vec<GeoRecord> = missing
+        .par_iter()
+        .filter_map(|pcode| get_postcode_location(pcode, geonames_data).map(|d| GeoRecord { pcode: (*pcode).clone(), lat: d.latitude, long: d.longitude }))
+        .collect();
+    println!("Geocoded {} of {} postcodes missing from geo data", found.len(), missing.len());
+    for record in found {
+        cache.map.insert(record.pcode.clone(), record);
+    }
+
+    pcodes.iter().filter(|x| cache.map.contains_key(*x)).count()
+}
+
+/// Default radius of the catchment used for the weighted school metrics, in km.
 const MAX_DIST: f32 = 5.0;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+/// Slack on the bounding box prefilter, since BNG and haversine distances differ slightly.
+const PREFILTER_MARGIN: f64 = 1.01;
 const LONDON: GeoLocation = GeoLocation { latitude: 51.5072, longitude: -0.1275 };
 
-pub fn aggregate_pdata(writer: Arc<Mutex<Writer<File>>>, pcodes: HashMap<String, Vec<(PcodeRecord, Option<String>)>>, sec_map:Arc<HashMap<u32, Vec<AggregateSchoolRecord>>>, prim_map: Arc<HashMap<u32, Vec<AggregatePSchoolRecord>>>, towns: Arc<Vec<Town>>, cities: Arc<Vec<Town>>, geo_map: Arc<CGeoData>, regional_data: Arc<HashMap<String, RegionRecord>>, year_range: std::ops::Range<u32>) -> Result<(), Box<dyn Error>> {
-    let geonames_data = geo_rust::get_postal_data(Country::UnitedKingdomFull);
-    
-    //let mut writer = Writer::from_path(path)?;
+/// Great circle distance between two locations in km.
+pub fn haversine_km(a: &GeoLocation, b: &GeoLocation) -> f64 {
+    let d_lat = (b.latitude - a.latitude).to_radians();
+    let d_lng = (b.longitude - a.longitude).to_radians();
 
-    //let mut processed_records: Vec<ProcessedPcodeRecord> = Vec::new();
-    let len = pcodes.len();
+    let h = (d_lat / 2.0).sin().powi(2)
+        + a.latitude.to_radians().cos() * b.latitude.to_radians().cos() * (d_lng / 2.0).sin().powi(2);
 
-    let to_bng = Proj::new_known_crs("EPSG:4326", "EPSG:27700", None)
-        .expect("Failed to create transformation");
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+/// A line segment (in BNG km) that can't be crossed directly, such as a river or motorway.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Barrier {
+    pub a: Vector2<f64>,
+    pub b: Vector2<f64>,
+}
+
+impl Barrier {
+    /// Whether the segment from `p` to `q` strictly crosses the barrier. Touching an end doesn't count.
+    fn crosses(&self, p: Vector2<f64>, q: Vector2<f64>) -> bool {
+        let side = |o: Vector2<f64>, d: Vector2<f64>, x: Vector2<f64>| (d - o).perp(&(x - o));
+        side(self.a, self.b, p) * side(self.a, self.b, q) < 0.0 && side(p, q, self.a) * side(p, q, self.b) < 0.0
+    }
+}
+
+/// Distance in km between BNG positions `a` and `b` (in km), where each barrier the straight line crosses
+/// adds the detour around its nearer end.
+pub fn effective_distance(a: Vector2<f64>, b: Vector2<f64>, barriers: &[Barrier]) -> f64 {
+    let direct = a.metric_distance(&b);
+    let detour: f64 = barriers
+        .iter()
+        .filter(|x| x.crosses(a, b))
+        .map(|x| {
+            let around = |end: Vector2<f64>| a.metric_distance(&end) + end.metric_distance(&b);
+            around(x.a).min(around(x.b)) - direct
+        })
+        .sum();
+    direct + detour
+}
 
-    for (i, (pcode, records)) in pcodes.into_iter().enumerate() {
-        if i % 1000 == 0 {
-            println!("Parsing {} of {} pcodes ({} records)", i, len, records.len());
+/// Distance in km from the postcode to a school: the effective distance around `barriers` when there are
+/// any and both BNG positions are known, otherwise the haversine distance.
+fn school_distance(loc: &GeoLocation, pos: Option<(f64, f64)>, school_loc: &GeoLocation, school_x: Option<f64>, school_y: Option<f64>, barriers: &[Barrier]) -> f64 {
+    match (pos, school_x, school_y) {
+        (Some((x, y)), Some(school_x), Some(school_y)) if !barriers.is_empty() => {
+            effective_distance(Vector2::new(x, y), Vector2::new(school_x, school_y), barriers)
         }
-        let pc_loc =  geo_data(&pcode, &geo_map, &geonames_data);
+        _ => haversine_km(loc, school_loc),
+    }
+}
 
-        let mut closest_town: Option<Town> = None;
-        let mut closest_town_dist: Option<f64> = None;
+/// The school list to use for a sale in `year`: that year's if we have it, otherwise the most recent
+/// earlier year within `year_range`.
+pub fn schools_for_year<'a, T>(map: &'a HashMap<u32, Vec<T>>, year: u32, year_range: &std::ops::Range<u32>) -> Option<(u32, &'a Vec<T>)> {
+    if let Some(x) = map.get(&year) {
+        return Some((year, x));
+    }
+    let mut y = year.checked_sub(1)?;
+    while year_range.contains(&y) {
+        if let Some(x) = map.get(&y) {
+            return Some((y, x));
+        }
+        y = y.checked_sub(1)?;
+    }
+    None
+}
 
-        let mut closest_city: Option<Town> = None;
-        let mut closest_city_dist: Option<f64> = None;
+/// Whether the school data used came from the target year itself (1) or an earlier one (0).
+fn year_exact(est_year: Option<u32>, year: u32) -> Option<u32> {
+    est_year.map(|y| (y == year) as u32)
+}
 
-        let mut dist_london: Option<f64> = None;
-        
-        let mut lat = None;
-        let mut lng = None;
+/// Weight of the schools at each Ofsted grade (1 = outstanding to 4 = inadequate). Grades are ordinal, so
+/// the share at each grade says more than a weighted mean of them.
+#[derive(Default)]
+pub struct GradeShares {
+    weights: [f32; 4],
+}
 
-        if let Some(loc) = &pc_loc {
-            lat = Some(loc.latitude);
-            lng = Some(loc.longitude);
+impl GradeShares {
+    /// Grades outside 1-4 are ignored.
+    pub fn add(&mut self, grade: u32, w: f32) {
+        if (1..=4).contains(&grade) && w > 0.0 {
+            self.weights[grade as usize - 1] += w;
+        }
+    }
 
-            let pos = if let (Some(lat), Some(long)) = (lat, lng) {
-                to_bng
-                    .convert((long, lat))
-                    .map(|(x, y)|(x / 1000.0, y / 1000.0)) // Convert to kms
-                    .ok()
-            } else {
-                None
-            };
+    /// Percentage of the weight at each grade, or `None` if nothing was added.
+    pub fn pct(&self) -> Option<[f32; 4]> {
+        let total: f32 = self.weights.iter().sum();
+        if total > 0.0 {
+            Some(self.weights.map(|w| 100.0 * w / total))
+        } else {
+            None
+        }
+    }
+}
 
-            dist_london = Some(loc.distance(&LONDON));
-            // Find closest
-            for town in towns.iter() {
-                let dist = loc.distance(&town.loc);
-                if closest_town_dist.map(|x| dist < x).unwrap_or(true) {
-                    // Update
-                    closest_town_dist = Some(dist);
-                    closest_town = Some(town.clone());
-                }
-            }
+/// Secondary school metrics around a single location.
+#[derive(Default)]
+pub struct SecondaryMetrics {
+    pub closest: Option<AggregateSchoolRecord>,
+    /// In km, kept in f64 so comparisons near `MAX_DIST` match the haversine. Cast to f32 in the output.
+    pub closest_dist: Option<f64>,
+
+    pub weighted_of_overall: Option<f32>,
+    pub weighted_of_educ: Option<f32>,
+    pub weighted_of_behaviour: Option<f32>,
+    pub weighted_of_sixthform: Option<f32>,
+    pub weighted_gcseg2: Option<f32>,
+    pub weighted_gcseg2_dis: Option<f32>,
+    /// The weighted `of_overall`, `of_educ`, `of_behaviour`, `of_sixthform`, `gcseg2` and `gcseg2_dis` without the
+    /// closest school, with `excl_nearest`.
+    pub weighted_excl_nearest: [Option<f32>; 6],
+    /// Weighted percentage of schools rated outstanding, good, requires improvement and inadequate overall.
+    pub of_overall_pct: Option<[f32; 4]>,
+    /// Weighted centre of the schools in the weighted metrics.
+    pub catchment_centroid: Option<GeoLocation>,
+    /// The closest independent school, with `include_independent`. Independent schools are in no other metric.
+    pub closest_indep: Option<AggregateSchoolRecord>,
+    pub closest_indep_dist: Option<f64>,
+
+    pub best_gcseg2: Option<f32>,
+    pub best_gcseg2_dis: Option<f32>,
+    pub best_of_overall: Option<u32>,
+
+    pub interact: Option<f32>,
+    pub interact_dis: Option<f32>,
+    pub interact_best: Option<String>,
+    pub interact_dis_best: Option<String>,
+}
 
-            for city in cities.iter() {
-                let dist = loc.distance(&city.loc);
-                if closest_city_dist.map(|x| dist < x).unwrap_or(true) {
-                    // Update
-                    closest_city_dist = Some(dist);
-                    closest_city = Some(city.clone());
-                }
-            }
+/// Primary school metrics around a single location.
+#[derive(Default)]
+pub struct PrimaryMetrics {
+    pub closest: Option<AggregatePSchoolRecord>,
+    /// In km, kept in f64 so comparisons near `MAX_DIST` match the haversine. Cast to f32 in the output.
+    pub closest_dist: Option<f64>,
+
+    pub weighted_of_overall: Option<f32>,
+    pub weighted_of_educ: Option<f32>,
+    pub weighted_of_behaviour: Option<f32>,
+    pub weighted_rwm_ta: Option<f32>,
+    pub weighted_rwm_ta_dis: Option<f32>,
+    /// Weighted percentage of schools rated outstanding, good, requires improvement and inadequate overall.
+    pub of_overall_pct: Option<[f32; 4]>,
+
+    pub best_rwm_ta: Option<f32>,
+    pub best_rwm_ta_dis: Option<f32>,
+    pub best_of_overall: Option<u32>,
+
+    pub interact: Option<f32>,
+    pub interact_dis: Option<f32>,
+    pub interact_best: Option<String>,
+    pub interact_dis_best: Option<String>,
+}
 
-            let (region, pcode_area) = if let Some(area_code) = first_letters(&pcode) {
-                regional_data.get(&area_code).map_or((None, None), |x| (Some(x.region.clone()), Some(x.area_name.clone())))
-            } else {
-                (None, None)
-            };
-        
-            for (j, (record, lad)) in records.into_iter().enumerate() {
-                let mut closest_sec_dist: Option<f32> = None;
-                let mut closest_prim_dist: Option<f32> = None;
+/// The secondary and primary metrics around a single location.
+#[derive(Default)]
+pub struct SchoolContext {
+    pub sec: SecondaryMetrics,
+    pub prim: PrimaryMetrics,
+}
 
-                let mut closest_sec: Option<AggregateSchoolRecord> = None;
-                let mut closest_prim: Option<AggregatePSchoolRecord> = None;
+/// The closest, weighted and best school metrics for any location, as `aggregate_pdata` computes them for
+/// a sale, weighting schools within `max_dist` km. `sec` and `prim` should be a single year's schools.
+/// Builds a new BNG transform on each call, so batches are better served by `scan_secondary`/`scan_primary`.
+pub fn nearest_schools_for_location(loc: GeoLocation, sec: &[AggregateSchoolRecord], prim: &[AggregatePSchoolRecord], max_dist: f32) -> SchoolContext {
+    let config = AtomicConfig { weight_scope: WeightScope::WithinDist(max_dist), ..AtomicConfig::default() };
+    let pos = new_transform("EPSG:4326", "EPSG:27700")
+        .ok()
+        .and_then(|to_bng| to_bng_km(&to_bng, loc.latitude, loc.longitude));
+
+    SchoolContext {
+        sec: scan_secondary(&loc, pos, sec, &config),
+        prim: scan_primary(&loc, pos, prim, &config),
+    }
+}
 
-                let mut weighted_sec_of_educ: Scaler = Scaler::new();
-                let mut weighted_sec_of_behaviour: Scaler = Scaler::new();
-                let mut weighted_sec_gcseg2: Scaler = Scaler::new();
-                let mut weighted_sec_gcseg2_dis: Scaler = Scaler::new();
-                let mut weighted_sec_of_overall: Scaler = Scaler::new();
-                let mut weighted_sec_of_sixthform: Scaler = Scaler::new();
+/// Cheap axis-aligned check (in BNG km) that a school is further than both `reach` and the closest school
+/// so far, so can be skipped before computing the haversine distance. Never skips without both positions.
+fn outside_bounds(pos: Option<(f64, f64)>, school_x: Option<f64>, school_y: Option<f64>, closest_dist: Option<f64>, reach: f32) -> bool {
+    if let (Some((x, y)), Some(school_x), Some(school_y), Some(closest_dist)) = (pos, school_x, school_y, closest_dist) {
+        let bound = closest_dist.max(f64::from(reach)) * PREFILTER_MARGIN;
+        (school_x - x).abs() > bound || (school_y - y).abs() > bound
+    } else {
+        false
+    }
+}
 
-                let mut weighted_prim_of_educ: Scaler = Scaler::new();
-                let mut weighted_prim_of_behaviour: Scaler = Scaler::new();
-                let mut weighted_prim_rwm_ta: Scaler = Scaler::new();
-                let mut weighted_prim_rwm_ta_dis: Scaler = Scaler::new();
-                let mut weighted_prim_of_overall: Scaler = Scaler::new();
+/// Whether a school at `dist` replaces the closest found so far. Equidistant schools go to the lower URN, so
+/// the choice doesn't depend on the order of the school list.
+fn is_closer(dist: f64, urn: &str, closest: Option<(f64, &str)>) -> bool {
+    match closest {
+        None => true,
+        Some((closest_dist, closest_urn)) => {
+            dist < closest_dist || (dist == closest_dist && urn_cmp(urn, closest_urn) == CmpOrdering::Less)
+        }
+    }
+}
 
-                let mut best_sec_gcseg2: Option<f32> = None;
-                let mut best_sec_gcseg2_dis: Option<f32> = None;
-                let mut best_sec_of_overall: Option<u32> = None; // Separate to above
+/// Orders URNs numerically where possible.
+fn urn_cmp(a: &str, b: &str) -> CmpOrdering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
 
-                let mut best_prim_rwm_ta: Option<f32> = None;
-                let mut best_prim_rwm_ta_dis: Option<f32> = None;
-                let mut best_prim_of_overall: Option<u32> = None; // Separate to above
+const KM_PER_MILE: f64 = 1.609344;
 
+/// Unit of the distance columns in the output. Distances are always computed in km.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistanceUnit {
+    Km,
+    Miles,
+}
 
-                let mut sec_est_year: Option<u32> = None;
-                let mut prim_est_year: Option<u32> = None;
+impl DistanceUnit {
+    pub fn from_km(&self, km: f64) -> f64 {
+        match self {
+            Self::Km => km,
+            Self::Miles => km / KM_PER_MILE,
+        }
+    }
 
-                let mut sec_interact: Option<f32> = None;
-                let mut sec_interact_dis: Option<f32> = None;
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Km => "km",
+            Self::Miles => "miles",
+        }
+    }
 
-                let mut sec_interact_best: Option<String> = None;
-                let mut sec_interact_dis_best: Option<String> = None;
+    fn convert<T: Into<f64>>(&self, km: Option<T>) -> Option<f64> {
+        km.map(|x| self.from_km(x.into()))
+    }
 
-                let mut prim_interact: Option<f32> = None;
-                let mut prim_interact_dis: Option<f32> = None;
+    fn convert_f32<T: Into<f64>>(&self, km: Option<T>) -> Option<f32> {
+        self.convert(km).map(|x| x as f32)
+    }
+}
 
-                let mut prim_interact_best: Option<String> = None;
-                let mut prim_interact_dis_best: Option<String> = None;
+/// Writes the unit of each distance column next to an output CSV, as `column,unit` rows.
+pub fn write_distance_units<P: AsRef<Path>>(path: P, columns: &[&str], unit: DistanceUnit) -> Result<(), ScaError> {
+    let mut writer = Writer::from_path(path)?;
+    writer.write_record(["column", "unit"])?;
+    for column in columns {
+        writer.write_record([*column, unit.name()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
 
-                let rpi_defl = CUM_RPI_DEFL.get((record.year - 2017) as usize).copied();
-                let mut sec_list: Option<&Vec<AggregateSchoolRecord>> = None;
-                if let Some(x) = sec_map.get(&record.year) {
-                    sec_est_year = Some(record.year);
-                    sec_list = Some(x);
-                } else {
-                    let mut y = record.year - 1;
-                    while year_range.contains(&y) {
-                        if let Some(x) = sec_map.get(&y) {
-                            sec_est_year = Some(y);
-                            sec_list = Some(x);
-                            break;
-                        }
-                        y -= 1;
+/// The distance columns of `RegionalProcessedPcodeRecord`.
+const PCODE_DISTANCE_COLUMNS: [&str; 7] = ["nearest_town_dist", "second_nearest_town_dist", "nearest_city_dist", "dist_london", "closest_sec_dist", "closest_indep_dist", "closest_prim_dist"];
+
+/// How `best_gcseg2_dis`/`best_rwm_ta_dis` are chosen when several schools share the best score.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TieBreak {
+    /// Take the tied school with the best Ofsted overall grade, then the lowest URN.
+    #[default]
+    Ofsted,
+    /// Average the disadvantaged scores of all the tied schools.
+    AverageDis,
+}
+
+/// The best score among `schools` (higher is better) and the disadvantaged score that goes with it, with
+/// ties settled by `tie_break`. Each school is `(score, dis_score, of_overall, urn)`.
+fn best_with_ties(schools: &[(Option<f32>, Option<f32>, Option<u32>, &str)], tie_break: TieBreak) -> (Option<f32>, Option<f32>) {
+    let Some(best) = schools.iter().filter_map(|x| x.0).reduce(f32::max) else {
+        return (None, None);
+    };
+    let tied = schools.iter().filter(|x| x.0 == Some(best));
+
+    let dis = match tie_break {
+        TieBreak::Ofsted => tied
+            .min_by(|a, b| {
+                // Lower grades are better; ungraded schools go last.
+                a.2.unwrap_or(u32::MAX).cmp(&b.2.unwrap_or(u32::MAX)).then_with(|| urn_cmp(a.3, b.3))
+            })
+            .and_then(|x| x.1),
+        TieBreak::AverageDis => {
+            let mut dis = Scaler::new();
+            tied.filter_map(|x| x.1).for_each(|x| dis.add(x, 1.0));
+            dis.ave()
+        }
+    };
+    (Some(best), dis)
+}
+
+/// Which schools around a postcode enter the weighted and best school metrics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeightScope {
+    /// Every school within this many km, weighted linearly from 1 at the postcode down to 0 at the cutoff.
+    WithinDist(f32),
+    /// The K closest schools regardless of distance, weighted equally.
+    NearestK(usize),
+}
+
+impl WeightScope {
+    /// The distance beyond which no school is weighted, if there is one.
+    fn reach(&self) -> Option<f32> {
+        match *self {
+            WeightScope::WithinDist(d) => Some(d),
+            WeightScope::NearestK(_) => None,
+        }
+    }
+
+    /// Picks and weights the `(dist, urn, school)` candidates. Schools outside the scope are left out. The
+    /// cutoff is compared in f64, as the distances are computed.
+    fn weights<T>(&self, mut candidates: Vec<(f64, &str, T)>) -> Vec<(T, f32)> {
+        match *self {
+            WeightScope::WithinDist(d) => {
+                let d = f64::from(d);
+                candidates
+                    .into_iter()
+                    .filter(|(dist, _, _)| *dist < d)
+                    .map(|(dist, _, school)| (school, ((d - dist) / d) as f32))
+                    .collect()
+            }
+            WeightScope::NearestK(k) => {
+                candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(CmpOrdering::Equal).then_with(|| urn_cmp(a.1, b.1)));
+                candidates.into_iter().take(k).map(|(_, _, school)| (school, 1.0)).collect()
+            }
+        }
+    }
+}
+
+/// Whether a school is independent (private), by its type.
+pub fn is_independent(school_type: &str) -> bool {
+    INDEPENDENT_SCHOOL_TYPES.contains(&school_type)
+}
+
+/// Whether a secondary is a selective state (grammar) school. `is_state` is 0 for these, as they are left
+/// out of the catchments.
+pub fn is_grammar(school: &AggregateSchoolRecord) -> bool {
+    school.is_selective == 1 && TARGET_SCHOOL_TYPES.contains(&school.school_type.as_str())
+}
+
+/// Whether a secondary school provides post-16 education.
+pub fn is_post16(school: &AggregateSchoolRecord) -> bool {
+    school.of_sixthform.is_some() || school.school_type.ends_with("1619")
+}
+
+/// A school that fed into a postcode's weighted metrics, for debugging the weighting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contribution {
+    pub urn: String,
+    /// Distance in km.
+    pub dist: f64,
+    pub weight: f32,
+    /// `gcseg2` for secondaries, `rwm_ta` for primaries.
+    pub metric: Option<f32>,
+    pub location: Option<GeoLocation>,
+}
+
+/// Scans the secondary schools for the property at `loc` (`pos` is its BNG position in km).
+pub fn scan_secondary(loc: &GeoLocation, pos: Option<(f64, f64)>, schools: &[AggregateSchoolRecord], config: &AtomicConfig) -> SecondaryMetrics {
+    scan_secondary_traced(loc, pos, schools, config, None, None)
+}
+
+/// `scan_secondary` for a sale in `sale_year`, decaying each school's weight by its staleness (see
+/// `AtomicConfig::stale_decay`), also pushing each school that enters the weighted metrics onto `trace`.
+fn scan_secondary_traced(loc: &GeoLocation, pos: Option<(f64, f64)>, schools: &[AggregateSchoolRecord], config: &AtomicConfig, sale_year: Option<u32>, mut trace: Option<&mut Vec<Contribution>>) -> SecondaryMetrics {
+    let mut m = SecondaryMetrics::default();
+
+    let mut weighted_of_educ: Scaler = Scaler::new();
+    let mut weighted_of_behaviour: Scaler = Scaler::new();
+    let mut weighted_gcseg2: Scaler = Scaler::new();
+    let mut weighted_gcseg2_dis: Scaler = Scaler::new();
+    let mut weighted_of_overall: Scaler = Scaler::new();
+    let mut of_overall_grades = GradeShares::default();
+    let mut weighted_of_sixthform: Scaler = Scaler::new();
+    let mut centroid = GeoScaler::new();
+    let mut excl_nearest: [Scaler; 6] = Default::default();
+    // Schools that may enter the weighted metrics, with their distance.
+    let mut candidates: Vec<(f64, &str, (f64, &AggregateSchoolRecord))> = Vec::new();
+
+    for school in schools.iter() {
+        // Assume that it is ordered by quality.
+
+        if let Some((x_km, y_km)) = pos {
+            if let (Some(r), Some(school_x), Some(school_y)) = (school.radius, school.x_km, school.y_km) {
+                let origin = Vector2::new(school_x, school_y);
+                let ppos = Vector2::new(x_km, y_km);
+                if origin.metric_distance(&ppos) < r {
+                    // We should go to this school.
+                    if m.interact.is_none() || m.interact < school.gcseg2 {
+                        m.interact = school.gcseg2;
+                        m.interact_best = Some(school.urn.clone());
+                    }
+                    if m.interact_dis.is_none() || m.interact_dis < school.gcseg2_dis {
+                        m.interact_dis = school.gcseg2_dis; // TODO: maybe get compute this by using a different ordering to get highest _dis score.
+                        m.interact_dis_best = Some(school.urn.clone());
                     }
                 }
-                if let Some(sec_list) = sec_list {
-                    for (i, school) in sec_list.iter().enumerate() {
-                        // Assume that it is ordered by quality.
-
-                        if let Some((x_km, y_km)) = pos {
-                            if let (Some(r), Some(school_x), Some(school_y)) = (school.radius, school.x_km, school.y_km) {
-                                let origin = Vector2::new(school_x, school_y);
-                                let ppos = Vector2::new(x_km, y_km);
-                                if origin.metric_distance(&ppos) < r {
-                                    // We should go to this school.
-                                    if sec_interact.is_none() || sec_interact < school.gcseg2 {
-                                        sec_interact = school.gcseg2;
-                                        sec_interact_best = Some(school.urn.clone());
-                                    }
-                                    if sec_interact_dis.is_none() || sec_interact_dis < school.gcseg2_dis {
-                                        sec_interact_dis = school.gcseg2_dis; // TODO: maybe get compute this by using a different ordering to get highest _dis score.
-                                        sec_interact_dis_best = Some(school.urn.clone());
-                                    }
-                                }
-                            }
-                        }
+            }
+        }
 
+        if config.include_independent && is_independent(&school.school_type) {
+            if let Some(school_loc) = school.location().filter(|_| !config.exclude_urns.contains(&school.urn)) {
+                let dist = school_distance(loc, pos, &school_loc, school.x_km, school.y_km, &config.barriers);
+                if is_closer(dist, &school.urn, m.closest_indep_dist.zip(m.closest_indep.as_ref().map(|x| x.urn.as_str()))) {
+                    m.closest_indep_dist = Some(dist);
+                    m.closest_indep = Some(school.clone());
+                }
+            }
+        }
 
-                        if school.is_state != 1 || school.is_selective == 1 {
-                            continue;
-                        }
-                        if let Some(school_loc) = school.location() {
-                            let dist = loc.distance(&school_loc) as f32;
-                            if closest_sec_dist.map(|x| dist < x).unwrap_or(true) {
-                                // Update
-                                closest_sec_dist = Some(dist);
-                                closest_sec = Some(school.clone());
-                            }
-
-                            let w = if dist >= MAX_DIST { 0.0 } else { (MAX_DIST - dist) / MAX_DIST };
-
-                            // Add weights.
-                            if w > 0.0 {
-                                if best_sec_gcseg2.map(|x| school.gcseg2 > Some(x)).unwrap_or(true) {
-                                    best_sec_gcseg2_dis = school.gcseg2_dis;
-                                    best_sec_gcseg2 = school.gcseg2;
-                                }
-
-                                if best_sec_of_overall.map(|x| school.of_overall < Some(x)).unwrap_or(true) {
-                                    best_sec_of_overall = school.of_overall;
-                                }
-
-                                if let Some(x) = school.of_educ {
-                                    weighted_sec_of_educ.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.of_behaviour {
-                                    weighted_sec_of_behaviour.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.gcseg2 {
-                                    weighted_sec_gcseg2.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.gcseg2_dis {
-                                    weighted_sec_gcseg2_dis.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.of_overall {
-                                    weighted_sec_of_overall.add(x as f32, w);
-                                }
-                                if let Some(x) = school.of_sixthform {
-                                    weighted_sec_of_sixthform.add(x as f32, w);
-                                }
-                            }
+        if (school.is_state != 1 || school.is_selective == 1) && !(config.include_selective && is_grammar(school)) {
+            continue;
+        }
+        if config.post16_only && !is_post16(school) {
+            continue;
+        }
+        if !config.type_allowed(&school.school_type) || config.exclude_urns.contains(&school.urn) {
+            continue;
+        }
+        if config.reach().map_or(false, |reach| outside_bounds(pos, school.x_km, school.y_km, m.closest_dist, reach)) {
+            continue;
+        }
+        if let Some(school_loc) = school.location() {
+            let dist = school_distance(loc, pos, &school_loc, school.x_km, school.y_km, &config.barriers);
+            if is_closer(dist, &school.urn, m.closest_dist.zip(m.closest.as_ref().map(|x| x.urn.as_str()))) {
+                // Update
+                m.closest_dist = Some(dist);
+                m.closest = Some(school.clone());
+            }
 
-                        }
+            if config.compute_weighted {
+                candidates.push((dist, school.urn.as_str(), (dist, school)));
+            }
+        }
+    }
+
+    let mut in_range = Vec::new();
+    for ((dist, school), w) in config.weight_scope.weights(candidates) {
+        let w = w * config.stale_weight(sale_year, school.year);
+        // Add weights.
+        if w > 0.0 {
+            if let Some(trace) = trace.as_mut() {
+                trace.push(Contribution { urn: school.urn.clone(), dist, weight: w, metric: school.gcseg2, location: school.location() });
+            }
+            if let Some(school_loc) = school.location() {
+                centroid.add(&school_loc, w);
+            }
+
+            in_range.push((school.gcseg2, school.gcseg2_dis, school.of_overall, school.urn.as_str()));
+
+            if m.best_of_overall.map(|x| school.of_overall < Some(x)).unwrap_or(true) {
+                m.best_of_overall = school.of_overall;
+            }
+
+            if let Some(x) = school.of_educ {
+                weighted_of_educ.add(x as f32, w);
+            }
+
+            if let Some(x) = school.of_behaviour {
+                weighted_of_behaviour.add(x as f32, w);
+            }
+
+            if let Some(x) = school.gcseg2 {
+                weighted_gcseg2.add(x as f32, w);
+            }
+
+            if let Some(x) = school.gcseg2_dis {
+                weighted_gcseg2_dis.add(x as f32, w);
+            }
+
+            if let Some(x) = school.of_overall {
+                weighted_of_overall.add(x as f32, w);
+                of_overall_grades.add(x, w);
+            }
+            if let Some(x) = school.of_sixthform {
+                weighted_of_sixthform.add(x as f32, w);
+            }
+
+            // The closest school is only known once every school has been scanned, so it can be left out here.
+            let nearest = m.closest.as_ref().map_or(false, |x| x.urn == school.urn);
+            if config.excl_nearest && !nearest {
+                let values = [school.of_overall.map(|x| x as f32), school.of_educ.map(|x| x as f32), school.of_behaviour.map(|x| x as f32), school.of_sixthform.map(|x| x as f32), school.gcseg2, school.gcseg2_dis];
+                for (scaler, x) in excl_nearest.iter_mut().zip(values) {
+                    if let Some(x) = x {
+                        scaler.add(x, w);
                     }
                 }
+            }
+        }
+    }
 
-                let mut prim_list: Option<&Vec<AggregatePSchoolRecord>> = None;
-                if let Some(x) = prim_map.get(&record.year) {
-                    prim_est_year = Some(record.year);
-                    prim_list = Some(x);
-                } else {
-                    let mut y: u32 = record.year - 1;
-                    while year_range.contains(&y) {
-                        if let Some(x) = prim_map.get(&y) {
-                            prim_est_year = Some(y);
-                            prim_list = Some(x);
-                            break;
-                        }
-                        y -= 1;
+    (m.best_gcseg2, m.best_gcseg2_dis) = best_with_ties(&in_range, config.best_tie_break);
+
+    m.weighted_of_educ = weighted_of_educ.ave();
+    m.weighted_of_behaviour = weighted_of_behaviour.ave();
+    m.weighted_gcseg2 = weighted_gcseg2.ave();
+    m.weighted_gcseg2_dis = weighted_gcseg2_dis.ave();
+    m.weighted_of_overall = weighted_of_overall.ave();
+    m.of_overall_pct = of_overall_grades.pct();
+    m.weighted_of_sixthform = weighted_of_sixthform.ave();
+    m.weighted_excl_nearest = excl_nearest.map(|x| x.ave());
+    m.catchment_centroid = centroid.centroid();
+    m
+}
+
+/// Scans the primary schools for the property at `loc` (`pos` is its BNG position in km).
+pub fn scan_primary(loc: &GeoLocation, pos: Option<(f64, f64)>, schools: &[AggregatePSchoolRecord], config: &AtomicConfig) -> PrimaryMetrics {
+    scan_primary_traced(loc, pos, schools, config, None, None)
+}
+
+/// `scan_primary` for a sale in `sale_year`, decaying each school's weight by its staleness (see
+/// `AtomicConfig::stale_decay`), also pushing each school that enters the weighted metrics onto `trace`.
+fn scan_primary_traced(loc: &GeoLocation, pos: Option<(f64, f64)>, schools: &[AggregatePSchoolRecord], config: &AtomicConfig, sale_year: Option<u32>, mut trace: Option<&mut Vec<Contribution>>) -> PrimaryMetrics {
+    let mut m = PrimaryMetrics::default();
+
+    let mut weighted_of_educ: Scaler = Scaler::new();
+    let mut weighted_of_behaviour: Scaler = Scaler::new();
+    let mut weighted_rwm_ta: Scaler = Scaler::new();
+    let mut weighted_rwm_ta_dis: Scaler = Scaler::new();
+    let mut weighted_of_overall: Scaler = Scaler::new();
+    let mut of_overall_grades = GradeShares::default();
+    // Schools that may enter the weighted metrics, with their distance.
+    let mut candidates: Vec<(f64, &str, (f64, &AggregatePSchoolRecord))> = Vec::new();
+
+    for school in schools.iter() {
+        if school.is_state != 1 {
+            continue;
+        }
+
+        if let Some((x_km, y_km)) = pos {
+            if let (Some(r), Some(school_x), Some(school_y)) = (school.radius, school.x_km, school.y_km) {
+                let origin = Vector2::new(school_x, school_y);
+                let ppos = Vector2::new(x_km, y_km);
+                if origin.metric_distance(&ppos) < r {
+                    // We should go to this school.
+                    if m.interact.is_none() || m.interact < school.rwm_ta {
+                        m.interact = school.rwm_ta;
+                        m.interact_best = Some(school.urn.clone());
+                    }
+                    if m.interact_dis.is_none() || m.interact_dis < school.rwm_ta_dis {
+                        m.interact_dis = school.rwm_ta_dis; // TODO: maybe get compute this by using a different ordering to get highest _dis score.
+                        m.interact_dis_best = Some(school.urn.clone());
                     }
                 }
+            }
+        }
 
-                if let Some(prim_list) = prim_list {
-                    for school in prim_list.iter() {
-                        if school.is_state != 1 {
-                            continue;
-                        }
+        if !config.type_allowed(&school.school_type) || config.exclude_urns.contains(&school.urn) {
+            continue;
+        }
+        if config.reach().map_or(false, |reach| outside_bounds(pos, school.x_km, school.y_km, m.closest_dist, reach)) {
+            continue;
+        }
+        if let Some(school_loc) = school.location() {
+            let dist = school_distance(loc, pos, &school_loc, school.x_km, school.y_km, &config.barriers);
+            if is_closer(dist, &school.urn, m.closest_dist.zip(m.closest.as_ref().map(|x| x.urn.as_str()))) {
+                // Update
+                m.closest_dist = Some(dist);
+                m.closest = Some(school.clone());
+            }
 
-                        if let Some((x_km, y_km)) = pos {
-                            if let (Some(r), Some(school_x), Some(school_y)) = (school.radius, school.x_km, school.y_km) {
-                                let origin = Vector2::new(school_x, school_y);
-                                let ppos = Vector2::new(x_km, y_km);
-                                if origin.metric_distance(&ppos) < r {
-                                    // We should go to this school.
-                                    if prim_interact.is_none() || prim_interact < school.rwm_ta {
-                                        prim_interact = school.rwm_ta;
-                                        prim_interact_best = Some(school.urn.clone());
-                                    }
-                                    if prim_interact_dis.is_none() || prim_interact_dis < school.rwm_ta_dis {
-                                        prim_interact_dis = school.rwm_ta_dis; // TODO: maybe get compute this by using a different ordering to get highest _dis score.
-                                        prim_interact_dis_best = Some(school.urn.clone());
-                                    }
-                                }
-                            }
-                        }
+            if config.compute_weighted {
+                candidates.push((dist, school.urn.as_str(), (dist, school)));
+            }
+        }
+    }
 
-                        if let Some(school_loc) = school.location() {
-                            let dist = loc.distance(&school_loc) as f32;
-                            if closest_prim_dist.map(|x| dist < x).unwrap_or(true) {
-                                // Update
-                                closest_prim_dist = Some(dist);
-                                closest_prim = Some(school.clone());
-                            }
-
-
-                            let w = if dist >= MAX_DIST { 0.0 } else { (MAX_DIST - dist) / MAX_DIST };
-                            
-                            // Add weights.
-                            if w > 0.0 {
-                                if best_prim_rwm_ta.map(|x| school.rwm_ta > Some(x)).unwrap_or(true) {
-                                    best_prim_rwm_ta_dis = school.rwm_ta_dis;
-                                    best_prim_rwm_ta = school.rwm_ta;
-                                }
-
-                                if best_prim_of_overall.map(|x| school.of_overall < Some(x)).unwrap_or(true) {
-                                    best_prim_of_overall = school.of_overall;
-                                }
-
-                                if let Some(x) = school.of_educ {
-                                    weighted_prim_of_educ.add(x as f32, w);
-                                }
-                                
-                                if let Some(x) = school.of_behaviour {
-                                    weighted_prim_of_behaviour.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.rwm_ta {
-                                    weighted_prim_rwm_ta.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.rwm_ta_dis {
-                                    weighted_prim_rwm_ta_dis.add(x as f32, w);
-                                }
-
-                                if let Some(x) = school.of_overall {
-                                    weighted_prim_of_overall.add(x as f32, w);
-                                }
-                            }
-                        }
-                    }
+    let mut in_range = Vec::new();
+    for ((dist, school), w) in config.weight_scope.weights(candidates) {
+        let w = w * config.stale_weight(sale_year, school.year);
+        // Add weights.
+        if w > 0.0 {
+            if let Some(trace) = trace.as_mut() {
+                trace.push(Contribution { urn: school.urn.clone(), dist, weight: w, metric: school.rwm_ta, location: school.location() });
+            }
+
+            in_range.push((school.rwm_ta, school.rwm_ta_dis, school.of_overall, school.urn.as_str()));
+
+            if m.best_of_overall.map(|x| school.of_overall < Some(x)).unwrap_or(true) {
+                m.best_of_overall = school.of_overall;
+            }
+
+            if let Some(x) = school.of_educ {
+                weighted_of_educ.add(x as f32, w);
+            }
+
+            if let Some(x) = school.of_behaviour {
+                weighted_of_behaviour.add(x as f32, w);
+            }
+
+            if let Some(x) = school.rwm_ta {
+                weighted_rwm_ta.add(x as f32, w);
+            }
+
+            if let Some(x) = school.rwm_ta_dis {
+                weighted_rwm_ta_dis.add(x as f32, w);
+            }
+
+            if let Some(x) = school.of_overall {
+                weighted_of_overall.add(x as f32, w);
+                of_overall_grades.add(x, w);
+            }
+        }
+    }
+
+    (m.best_rwm_ta, m.best_rwm_ta_dis) = best_with_ties(&in_range, config.best_tie_break);
+
+    m.weighted_of_educ = weighted_of_educ.ave();
+    m.weighted_of_behaviour = weighted_of_behaviour.ave();
+    m.weighted_rwm_ta = weighted_rwm_ta.ave();
+    m.weighted_rwm_ta_dis = weighted_rwm_ta_dis.ave();
+    m.weighted_of_overall = weighted_of_overall.ave();
+    m.of_overall_pct = of_overall_grades.pct();
+    m
+}
+
+/// The latest school list at or before `year`.
+fn latest_schools<T>(map: &HashMap<u32, Vec<T>>, year: u32) -> Option<(u32, &Vec<T>)> {
+    map.iter().filter(|(y, _)| **y <= year).max_by_key(|(y, _)| **y).map(|(y, x)| (*y, x))
+}
+
+/// A row of `contributors.csv`.
+#[derive(Debug, Serialize)]
+struct ContributorRow<'a> {
+    pcode: &'a str,
+    year: u32,
+    phase: &'static str,
+    urn: String,
+    dist_km: f32,
+    weight: f32,
+    metric: Option<f32>,
+}
+
+/// Widest link line in `debug_links_geojson`, for a school of weight 1.
+const LINK_MAX_WIDTH: f32 = 6.0;
+
+/// Writes a GeoJSON FeatureCollection of the postcode at `postcode_loc` as a point, with a LineString from
+/// it to each of `contributors` that has a location. Lines carry the school's URN, distance, weight and metric,
+/// and are styled (simplestyle `stroke-width`/`stroke-opacity`) by weight. Returns the number of lines.
+pub fn debug_links_geojson<P: AsRef<Path>>(postcode_loc: &GeoLocation, contributors: &[Contribution], path: P) -> Result<usize, ScaError> {
+    // GeoJSON positions are [longitude, latitude].
+    let point = |loc: &GeoLocation| serde_json::json!([loc.longitude, loc.latitude]);
+    let mut features = vec![serde_json::json!({
+        "type": "Feature",
+        "geometry": { "type": "Point", "coordinates": point(postcode_loc) },
+        "properties": { "kind": "postcode" },
+    })];
+    for c in contributors {
+        let Some(loc) = &c.location else { continue };
+        features.push(serde_json::json!({
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": [point(postcode_loc), point(loc)] },
+            "properties": {
+                "kind": "school",
+                "urn": c.urn,
+                "dist_km": c.dist,
+                "weight": c.weight,
+                "metric": c.metric,
+                "stroke-width": (c.weight * LINK_MAX_WIDTH).max(0.5),
+                "stroke-opacity": c.weight.clamp(0.2, 1.0),
+            },
+        }));
+    }
+    let lines = features.len() - 1;
+
+    let file = File::create(path)?;
+    serde_json::to_writer_pretty(file, &serde_json::json!({ "type": "FeatureCollection", "features": features })).map_err(io::Error::from)?;
+    Ok(lines)
+}
+
+/// Writes every school that feeds into the weighted metrics of each of `config.debug_postcodes`, for each
+/// year in `year_range`, to `path`. Returns the number of rows written.
+pub fn write_contributors<P: AsRef<Path>>(path: P, sec_map: &HashMap<u32, Vec<AggregateSchoolRecord>>, prim_map: &HashMap<u32, Vec<AggregatePSchoolRecord>>, geo: &CGeoData, year_range: std::ops::Range<u32>, config: &AtomicConfig) -> Result<usize, ScaError> {
+    let to_bng = new_transform("EPSG:4326", "EPSG:27700")?;
+    let mut writer = Writer::from_path(path)?;
+    let mut written = 0;
+
+    let mut pcodes: Vec<String> = config.debug_postcodes.iter().map(|x| normalize_postcode(x)).collect();
+    pcodes.sort();
+    for pcode in pcodes.iter() {
+        let Some(record) = geo.map.get(pcode) else {
+            println!("No location for debug postcode {}", pcode);
+            continue;
+        };
+        let loc = GeoLocation { latitude: record.lat, longitude: record.long };
+        let pos = to_bng_km(&to_bng, record.lat, record.long);
+
+        for year in year_range.clone() {
+            let mut sec = Vec::new();
+            if let Some((y, list)) = config.phase_schools(Phase::Secondary, sec_map, year, &year_range) {
+                scan_secondary_traced(&loc, pos, list, config, Some(year), Some(&mut sec));
+            }
+            let mut prim = Vec::new();
+            if let Some((y, list)) = config.phase_schools(Phase::Primary, prim_map, year, &year_range) {
+                scan_primary_traced(&loc, pos, list, config, Some(year), Some(&mut prim));
+            }
+
+            if let Some(dir) = &config.debug_links_dir {
+                let all: Vec<Contribution> = sec.iter().chain(prim.iter()).cloned().collect();
+                debug_links_geojson(&loc, &all, dir.join(format!("links_{}_{}.geojson", pcode.replace(' ', ""), year)))?;
+            }
+
+            for (phase, contributions) in [("sec", sec), ("prim", prim)] {
+                for c in contributions {
+                    writer.serialize(ContributorRow { pcode, year, phase, urn: c.urn, dist_km: c.dist as f32, weight: c.weight, metric: c.metric })?;
+                    written += 1;
                 }
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Computes the secondary and primary metrics once for every located postcode in `geo`, using the
+/// latest school data at or before `year`. Rows are ordered by postcode.
+pub fn build_postcode_school_table(sec_map: &HashMap<u32, Vec<AggregateSchoolRecord>>, prim_map: &HashMap<u32, Vec<AggregatePSchoolRecord>>, geo: &CGeoData, year: u32, config: &AtomicConfig) -> Result<Vec<PostcodeSchoolRow>, ScaError> {
+    let to_bng = new_transform("EPSG:4326", "EPSG:27700")?;
+
+    let sec = latest_schools(sec_map, year).filter(|_| config.phases.includes(Phase::Secondary));
+    let prim = latest_schools(prim_map, year).filter(|_| config.phases.includes(Phase::Primary));
+
+    let mut rows: Vec<PostcodeSchoolRow> = geo.map.values().map(|record| {
+        let loc = GeoLocation { latitude: record.lat, longitude: record.long };
+        let pos = to_bng_km(&to_bng, record.lat, record.long);
+
+        let s = sec.map(|(_, x)| scan_secondary(&loc, pos, x, config)).unwrap_or_default();
+        let p = prim.map(|(_, x)| scan_primary(&loc, pos, x, config)).unwrap_or_default();
+
+        PostcodeSchoolRow {
+            pcode: normalize_postcode(&record.pcode),
+            lat: record.lat,
+            lng: record.long,
+
+            sec_est_year: sec.map(|(y, _)| y),
+            prim_est_year: prim.map(|(y, _)| y),
+            sec_year_exact: year_exact(sec.map(|(y, _)| y), year),
+            prim_year_exact: year_exact(prim.map(|(y, _)| y), year),
+
+            closest_sec_urn: s.closest.map(|x| x.urn),
+            closest_sec_dist: config.distance_unit.convert_f32(s.closest_dist),
+            weighted_sec_of_overall: s.weighted_of_overall,
+            weighted_sec_of_educ: s.weighted_of_educ,
+            weighted_sec_of_behaviour: s.weighted_of_behaviour,
+            weighted_sec_of_sixthform: s.weighted_of_sixthform,
+            weighted_sec_gcseg2: s.weighted_gcseg2,
+            weighted_sec_gcseg2_dis: s.weighted_gcseg2_dis,
+            weighted_sec_of_overall_excl_nearest: s.weighted_excl_nearest[0],
+            weighted_sec_of_educ_excl_nearest: s.weighted_excl_nearest[1],
+            weighted_sec_of_behaviour_excl_nearest: s.weighted_excl_nearest[2],
+            weighted_sec_of_sixthform_excl_nearest: s.weighted_excl_nearest[3],
+            weighted_sec_gcseg2_excl_nearest: s.weighted_excl_nearest[4],
+            weighted_sec_gcseg2_dis_excl_nearest: s.weighted_excl_nearest[5],
+            sec_pct_outstanding: s.of_overall_pct.map(|x| x[0]),
+            sec_pct_good: s.of_overall_pct.map(|x| x[1]),
+            sec_pct_requires_improvement: s.of_overall_pct.map(|x| x[2]),
+            sec_pct_inadequate: s.of_overall_pct.map(|x| x[3]),
+            sec_catchment_centroid_lat: s.catchment_centroid.as_ref().map(|x| x.latitude),
+            sec_catchment_centroid_lng: s.catchment_centroid.as_ref().map(|x| x.longitude),
+            closest_indep_name: s.closest_indep.as_ref().map(|x| x.name.clone()),
+            closest_indep_dist: config.distance_unit.convert_f32(s.closest_indep_dist),
+            best_sec_gcseg2: s.best_gcseg2,
+            best_sec_gcseg2_dis: s.best_gcseg2_dis,
+            best_sec_of_overall: s.best_of_overall,
+
+            closest_prim_urn: p.closest.map(|x| x.urn),
+            closest_prim_dist: config.distance_unit.convert_f32(p.closest_dist),
+            weighted_prim_of_overall: p.weighted_of_overall,
+            weighted_prim_of_educ: p.weighted_of_educ,
+            weighted_prim_of_behaviour: p.weighted_of_behaviour,
+            weighted_prim_rwm_ta: p.weighted_rwm_ta,
+            weighted_prim_rwm_ta_dis: p.weighted_rwm_ta_dis,
+            prim_pct_outstanding: p.of_overall_pct.map(|x| x[0]),
+            prim_pct_good: p.of_overall_pct.map(|x| x[1]),
+            prim_pct_requires_improvement: p.of_overall_pct.map(|x| x[2]),
+            prim_pct_inadequate: p.of_overall_pct.map(|x| x[3]),
+            best_prim_rwm_ta: p.best_rwm_ta,
+            best_prim_rwm_ta_dis: p.best_rwm_ta_dis,
+            best_prim_of_overall: p.best_of_overall,
+
+            sec_interact: s.interact,
+            sec_interact_dis: s.interact_dis,
+            prim_interact: p.interact,
+            prim_interact_dis: p.interact_dis,
+        }
+    }).collect();
+
+    rows.sort_by(|a, b| a.pcode.cmp(&b.pcode));
+    Ok(rows)
+}
+
+/// Parses a room count, accepting whole numbers written as floats ("4.0"). Blank, negative, fractional or
+/// otherwise malformed counts give `None`.
+pub fn parse_rooms(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Ok(x) = s.parse::<u32>() {
+        return Some(x);
+    }
+    let x = s.parse::<f32>().ok()?;
+    if x.is_finite() && x >= 0.0 && x.fract() == 0.0 {
+        Some(x as u32)
+    } else {
+        None
+    }
+}
+
+/// A floor area, or `None` if it is zero, negative or not finite.
+pub fn clean_area(x: f32) -> Option<f32> {
+    if x.is_finite() && x > 0.0 {
+        Some(x)
+    } else {
+        None
+    }
+}
+
+/// Record counts from `aggregate_pdata`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AggregateCounts {
+    pub written: usize,
+    /// Records whose postcode could not be located.
+    pub dropped_no_geo: usize,
+    /// Written records whose `numberrooms` was present but unusable, so was blanked.
+    pub rooms_cleaned: usize,
+    /// Written records whose `tfarea` was present but not positive and finite, so was blanked.
+    pub tfarea_cleaned: usize,
+}
+
+impl std::ops::AddAssign for AggregateCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.written += other.written;
+        self.dropped_no_geo += other.dropped_no_geo;
+        self.rooms_cleaned += other.rooms_cleaned;
+        self.tfarea_cleaned += other.tfarea_cleaned;
+    }
+}
+
+/// Writes the aggregated rows for `pcodes`, returning how many records were written, dropped and cleaned.
+pub fn aggregate_pdata(writer: Arc<Mutex<Writer<File>>>, pcodes: impl IntoIterator<Item = (String, Vec<(PcodeRecord, Option<String>)>)>, sec_map:Arc<HashMap<u32, Vec<AggregateSchoolRecord>>>, prim_map: Arc<HashMap<u32, Vec<AggregatePSchoolRecord>>>, places: Arc<Places>, geo_map: Arc<CGeoData>, geonames_data: Arc<Vec<PostalData>>, regional_data: Arc<HashMap<String, RegionRecord>>, year_range: std::ops::Range<u32>, config: &AtomicConfig, checkpoint: Option<Arc<Mutex<Checkpoint>>>, progress: Option<Arc<Progress>>) -> Result<AggregateCounts, Box<dyn Error>> {
+    
+    //let mut writer = Writer::from_path(path)?;
+
+    //let mut processed_records: Vec<ProcessedPcodeRecord> = Vec::new();
+
+    let to_bng = new_transform("EPSG:4326", "EPSG:27700")?;
+
+    // Postcodes processed since the last checkpoint write.
+    let mut pending: Vec<String> = Vec::new();
+
+    let mut counts = AggregateCounts::default();
+
+    for (pcode, records) in pcodes.into_iter() {
+        let pc_loc =  geo_data(&pcode, &geo_map, &geonames_data);
+
+        let mut closest_town: Option<Town> = None;
+        let mut closest_town_dist: Option<f64> = None;
+        let mut second_town: Option<Town> = None;
+        let mut second_town_dist: Option<f64> = None;
+
+        let mut closest_city: Option<Town> = None;
+        let mut closest_city_dist: Option<f64> = None;
+
+        let mut dist_london: Option<f64> = None;
+        
+        let mut lat = None;
+        let mut lng = None;
+
+        if let Some(loc) = &pc_loc {
+            lat = Some(loc.latitude);
+            lng = Some(loc.longitude);
+
+            let pos = if let (Some(lat), Some(long)) = (lat, lng) {
+                to_bng_km(&to_bng, lat, long)
+            } else {
+                None
+            };
+
+            dist_london = Some(haversine_km(&loc, &LONDON));
+            // Find closest
+            let [town, second] = two_closest_places(&loc, &places.towns);
+            if let Some((town, dist)) = town {
+                closest_town = Some(town.clone());
+                closest_town_dist = Some(dist);
+            }
+            if let Some((town, dist)) = second {
+                second_town = Some(town.clone());
+                second_town_dist = Some(dist);
+            }
+
+            if let Some((city, dist)) = closest_place(&loc, &places.cities) {
+                closest_city = Some(city.clone());
+                closest_city_dist = Some(dist);
+            }
+
+            let (region, pcode_area) = if let Some(area_code) = first_letters(&pcode) {
+                regional_data.get(&area_code).map_or((None, None), |x| (Some(x.region.clone()), Some(x.area_name.clone())))
+            } else {
+                (None, None)
+            };
+        
+            for (j, (record, lad)) in records.into_iter().enumerate() {
+                let rpi_defl = rpi_deflator(record.year);
+
+                let (sec_est_year, sec) = match config.phase_schools(Phase::Secondary, &sec_map, record.year, &year_range) {
+                    Some((y, list)) => (Some(y), scan_secondary_traced(loc, pos, list, config, Some(record.year), None)),
+                    None => (None, SecondaryMetrics::default()),
+                };
+
+                let (prim_est_year, prim) = match config.phase_schools(Phase::Primary, &prim_map, record.year, &year_range) {
+                    Some((y, list)) => (Some(y), scan_primary_traced(loc, pos, list, config, Some(record.year), None)),
+                    None => (None, PrimaryMetrics::default()),
+                };
 
                 let age_band = match record.age_band.as_ref().map(|x| x.trim()) {
                     Some("England and Wales: 1900-1929") => Some(1900),
@@ -814,100 +2279,315 @@ pub fn aggregate_pdata(writer: Arc<Mutex<Writer<File>>>, pcodes: HashMap<String,
                 };
 
                 if (year_range.contains(&record.year)) {
+                    let numberrooms = record.numberrooms.as_deref().and_then(parse_rooms);
+                    if numberrooms.is_none() && record.numberrooms.as_deref().map_or(false, |x| !x.trim().is_empty()) {
+                        counts.rooms_cleaned += 1;
+                    }
+                    let tfarea = record.tfarea.and_then(clean_area);
+                    if tfarea.is_none() && record.tfarea.is_some() {
+                        counts.tfarea_cleaned += 1;
+                    }
+                    let price_per_room = price_per_room(record.price, numberrooms);
+                    let outlier = config.outliers.as_ref().map_or(false, |x| {
+                        x.is_outlier(lad.as_deref(), record.year, record.priceper, price_per_room)
+                    });
 
-                    writer.lock().unwrap().serialize(&RegionalProcessedPcodeRecord {
+                    let row = RegionalProcessedPcodeRecord {
                         id: record.id,
                         after_covid: (record.year >= 2021) as u32,
                         age_band: age_band,
                         classt: record.classt,
                         price: record.price,
-                        numberrooms: record.numberrooms.and_then(|x| x.parse::<u32>().ok()),
-                        tfarea: record.tfarea.and_then(|x| if x.is_normal() { Some(x) } else { None }),
+                        numberrooms,
+                        tfarea,
                         priceper: record.priceper,
+                        price_per_room,
+                        outlier,
                         year: record.year,
                         rpi_defl,
+                        price_real: real_price(record.price, rpi_defl),
                         propertytype: record.propertytype,
                         lad,
                         pcode: record.postcode,
                         lat,
                         lng,
+                        country: country(&pcode, region.as_deref()).to_owned(),
                         region: region.clone(),
                         pcode_area: pcode_area.clone(),
                         sec_est_year,
                         prim_est_year,
+                        sec_year_exact: year_exact(sec_est_year, record.year),
+                        prim_year_exact: year_exact(prim_est_year, record.year),
+
+                        x_km: pos.map(|(x, _)| x),
+                        y_km: pos.map(|(_, y)| y),
+
+                        dist_london: config.distance_unit.convert(dist_london),
+                        nearest_town_dist: config.distance_unit.convert(closest_town_dist),
+                        nearest_admin_name: closest_town.as_ref().map(|x| x.record.admin_name.clone()),
+                        nearest_town_popn: closest_town.as_ref().map(|x| x.record.population_proper),
+                        nearest_town_name: closest_town.as_ref().map(|x| x.record.city.clone()),
+                        second_nearest_town_name: second_town.as_ref().map(|x| x.record.city.clone()),
+                        second_nearest_town_dist: config.distance_unit.convert(second_town_dist),
+
+                        nearest_city_name: closest_city.as_ref().map(|x| x.record.city.clone()),
+                        nearest_city_popn: closest_city.as_ref().map(|x| x.record.population_proper),
+                        nearest_city_dist: config.distance_unit.convert(closest_city_dist),
+
+                        closest_prim_dist: config.distance_unit.convert_f32(prim.closest_dist),
+                        closest_prim_urn: prim.closest.as_ref().map(|x| x.urn.clone()),
+                        closest_prim_name: prim.closest.as_ref().map(|x| x.name.clone()),
+                        closest_prim_type: prim.closest.as_ref().map(|x| x.school_type.clone()),
+                        closest_prim_of_educ:  prim.closest.as_ref().and_then(|x| x.of_educ), 
+                        closest_prim_pcode: prim.closest.as_ref().map(|x| x.pcode.clone()), 
+                        closest_prim_rwm_ta: prim.closest.as_ref().and_then(|x| x.rwm_ta),
+                        closest_prim_rwm_ta_dis: prim.closest.as_ref().and_then(|x| x.rwm_ta_dis),
+                        closest_prim_of_overall: prim.closest.as_ref().and_then(|x| x.of_overall),
+                        weighted_prim_of_educ: prim.weighted_of_educ,
+                        weighted_prim_rwm_ta: prim.weighted_rwm_ta,
+                        weighted_prim_rwm_ta_dis: prim.weighted_rwm_ta_dis,
+                        weighted_prim_of_behaviour: prim.weighted_of_behaviour,
+                        weighted_prim_of_overall: prim.weighted_of_overall,
+                        prim_pct_outstanding: prim.of_overall_pct.map(|x| x[0]),
+                        prim_pct_good: prim.of_overall_pct.map(|x| x[1]),
+                        prim_pct_requires_improvement: prim.of_overall_pct.map(|x| x[2]),
+                        prim_pct_inadequate: prim.of_overall_pct.map(|x| x[3]),
+
+                        closest_sec_dist: config.distance_unit.convert_f32(sec.closest_dist),
+                        closest_sec_urn: sec.closest.as_ref().map(|x| x.urn.clone()),
+                        closest_sec_type: sec.closest.as_ref().map(|x| x.school_type.clone()),
+                        closest_sec_name: sec.closest.as_ref().map(|x| x.name.clone()),
+                        closest_sec_of_educ: sec.closest.as_ref().and_then(|x| x.of_educ),
+                        closest_sec_pcode: sec.closest.as_ref().map(|x| x.pcode.clone()),
+                        closest_sec_gcseg2: sec.closest.as_ref().and_then(|x| x.gcseg2),
+                        closest_sec_gcseg2_dis: sec.closest.as_ref().and_then(|x| x.gcseg2_dis),
+                        closest_sec_of_overall: sec.closest.as_ref().and_then(|x| x.of_overall),
+                        weighted_sec_gcseg2: sec.weighted_gcseg2,
+                        weighted_sec_gcseg2_dis: sec.weighted_gcseg2_dis,
+                        weighted_sec_of_overall_excl_nearest: sec.weighted_excl_nearest[0],
+                        weighted_sec_of_educ_excl_nearest: sec.weighted_excl_nearest[1],
+                        weighted_sec_of_behaviour_excl_nearest: sec.weighted_excl_nearest[2],
+                        weighted_sec_of_sixthform_excl_nearest: sec.weighted_excl_nearest[3],
+                        weighted_sec_gcseg2_excl_nearest: sec.weighted_excl_nearest[4],
+                        weighted_sec_gcseg2_dis_excl_nearest: sec.weighted_excl_nearest[5],
+                        weighted_sec_of_educ: sec.weighted_of_educ,
+                        weighted_sec_of_behaviour: sec.weighted_of_behaviour,
+                        weighted_sec_of_overall: sec.weighted_of_overall,
+                        weighted_sec_of_sixthform: sec.weighted_of_sixthform,
+                        sec_pct_outstanding: sec.of_overall_pct.map(|x| x[0]),
+                        sec_pct_good: sec.of_overall_pct.map(|x| x[1]),
+                        sec_pct_requires_improvement: sec.of_overall_pct.map(|x| x[2]),
+                        sec_pct_inadequate: sec.of_overall_pct.map(|x| x[3]),
+                        sec_catchment_centroid_lat: sec.catchment_centroid.as_ref().map(|x| x.latitude),
+                        sec_catchment_centroid_lng: sec.catchment_centroid.as_ref().map(|x| x.longitude),
+                        closest_indep_name: sec.closest_indep.as_ref().map(|x| x.name.clone()),
+                        closest_indep_dist: config.distance_unit.convert_f32(sec.closest_indep_dist),
+
+                        best_sec_gcseg2: sec.best_gcseg2,
+                        best_sec_gcseg2_dis: sec.best_gcseg2_dis,
+                        best_sec_of_overall: sec.best_of_overall,
+
+                        best_prim_of_overall: prim.best_of_overall,
+                        best_prim_rwm_ta: prim.best_rwm_ta,
+                        best_prim_rwm_ta_dis: prim.best_rwm_ta_dis,
+
+                        sec_interact: sec.interact,
+                        sec_interact_dis: sec.interact_dis,
+
+                        sec_interact_best: sec.interact_best,
+                        sec_interact_dis_best: sec.interact_dis_best,
+
+                        prim_interact: prim.interact,
+                        prim_interact_dis: prim.interact_dis,
+
+                        prim_interact_best: prim.interact_best,
+                        prim_interact_dis_best: prim.interact_dis_best
+                    };
+                    counts.written += 1;
+                    write_output(&mut writer.lock().unwrap(), &row, config, counts.written)?;
+                }
+            }
+        } else {
+            println!("No postcode location for: {}", &pcode);
+            counts.dropped_no_geo += records.len();
+        }
+
+        if let Some(checkpoint) = &checkpoint {
+            pending.push(pcode);
+            if pending.len() >= CHECKPOINT_INTERVAL {
+                commit_checkpoint(&writer, checkpoint, &mut pending)?;
+            }
+        }
+
+        if let Some(progress) = &progress {
+            progress.tick();
+        }
+    }
+
+    if let Some(checkpoint) = &checkpoint {
+        commit_checkpoint(&writer, checkpoint, &mut pending)?;
+    }
+
+    Ok(counts)
+}
+
+/// Postcodes completed across all aggregation threads, reported through a callback as `(done, total)`.
+pub struct Progress {
+    done: AtomicUsize,
+    total: usize,
+    callback: Box<dyn Fn(usize, usize) + Send + Sync>,
+}
+
+impl Progress {
+    pub fn new<F: Fn(usize, usize) + Send + Sync + 'static>(total: usize, callback: F) -> Self {
+        Self {
+            done: AtomicUsize::new(0),
+            total,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Marks one more postcode as done.
+    pub fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        (self.callback)(done, self.total);
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+}
+
+/// Formats a progress line with throughput and ETA.
+pub fn format_progress(done: usize, total: usize, elapsed: Duration) -> String {
+    let rate = done as f64 / elapsed.as_secs_f64().max(1e-3);
+    let eta = if rate > 0.0 { (total.saturating_sub(done) as f64 / rate).round() as u64 } else { 0 };
+    format!("{} of {} pcodes ({:.1}%), {:.0} pcodes/s, ETA {}m{:02}s", done, total, 100.0 * done as f64 / total.max(1) as f64, rate, eta / 60, eta % 60)
+}
+
+/// A progress callback that prints a line every `every` postcodes and once at the end.
+pub fn print_progress(every: usize) -> impl Fn(usize, usize) + Send + Sync {
+    let start = Instant::now();
+    move |done, total| {
+        if done % every == 0 || done == total {
+            println!("{}", format_progress(done, total, start.elapsed()));
+        }
+    }
+}
+
+/// Number of postcodes a thread processes between checkpoint writes.
+const CHECKPOINT_INTERVAL: usize = 500;
+
+/// Records which postcodes have been fully written, so an interrupted `run_atomic` can resume.
+pub struct Checkpoint {
+    done: HashSet<String>,
+    file: File,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint at `path`, loading any postcodes recorded by a previous run.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ScaError> {
+        let done = match std::fs::read_to_string(&path) {
+            Ok(s) => s.lines().filter(|x| !x.is_empty()).map(|x| x.to_owned()).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self { done, file })
+    }
+
+    pub fn contains(&self, pcode: &str) -> bool {
+        self.done.contains(pcode)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Marks the postcodes as done. Their rows must already be flushed to the output.
+    pub fn record(&mut self, pcodes: &[String]) -> io::Result<()> {
+        let mut buf = String::new();
+        for pcode in pcodes {
+            buf.push_str(pcode);
+            buf.push('\n');
+            self.done.insert(pcode.clone());
+        }
+        self.file.write_all(buf.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Flushes the output and then records `pending` in the checkpoint, so a recorded postcode always has its rows on disk.
+fn commit_checkpoint(writer: &Mutex<Writer<File>>, checkpoint: &Mutex<Checkpoint>, pending: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    // Hold the writer while recording so no other thread's partial rows can be flushed in between.
+    let mut writer = writer.lock().unwrap();
+    writer.flush()?;
+    checkpoint.lock().unwrap().record(pending)?;
+    pending.clear();
+    Ok(())
+}
+
+/// Writes `record` as the `written`th row of the output, flushing every `config.flush_every` rows.
+fn write_output<W: Write, T: Serialize>(writer: &mut Writer<W>, record: &T, config: &AtomicConfig, written: usize) -> Result<(), ScaError> {
+    write_columns(writer, record, &config.columns, config.precision)?;
+    if config.flush_every > 0 && written % config.flush_every == 0 {
+        writer.flush()?;
+    }
+    Ok(())
+}
 
-                        x_km: pos.map(|(x, _)| x),
-                        y_km: pos.map(|(_, y)| y),
+/// Starts a new output at `path`, headed by `columns` unless they are `Full`, buffering `buffer` bytes.
+fn new_output<P: AsRef<Path>>(path: P, columns: &ColumnSet, buffer: usize) -> Result<Writer<File>, ScaError> {
+    let mut writer = csv::WriterBuilder::new().buffer_capacity(buffer).from_path(path)?;
+    // Only `Full` rows are serialized with their own header.
+    if let Some(columns) = columns.columns() {
+        writer.write_record(&columns)?;
+    }
+    Ok(writer)
+}
 
-                        dist_london,
-                        nearest_town_dist: closest_town_dist,
-                        nearest_admin_name: closest_town.as_ref().map(|x| x.record.admin_name.clone()),
-                        nearest_town_popn: closest_town.as_ref().map(|x| x.record.population_proper),
-                        nearest_town_name: closest_town.as_ref().map(|x| x.record.city.clone()),
+/// Opens the output for `run_atomic`, buffering `buffer` bytes. With an empty checkpoint this starts a new file
+/// (headed by `columns` unless they are `Full`), otherwise any rows for
+/// postcodes missing from the checkpoint (written after the last checkpoint before a crash) are removed and the
+/// writer appends to what remains. Resuming fails if the existing header isn't `columns`, as the appended rows
+/// wouldn't line up with it.
+pub fn open_output<P: AsRef<Path>>(path: P, checkpoint: &Checkpoint, columns: &ColumnSet, buffer: usize) -> Result<Writer<File>, ScaError> {
+    if checkpoint.is_empty() || !path.as_ref().exists() {
+        return new_output(path, columns, buffer);
+    }
 
-                        nearest_city_name: closest_city.as_ref().map(|x| x.record.city.clone()),
-                        nearest_city_popn: closest_city.as_ref().map(|x| x.record.population_proper),
-                        nearest_city_dist: closest_city_dist,
-
-                        closest_prim_dist,
-                        closest_prim_urn: closest_prim.as_ref().map(|x| x.urn.clone()),
-                        closest_prim_name: closest_prim.as_ref().map(|x| x.name.clone()),
-                        closest_prim_type: closest_prim.as_ref().map(|x| x.school_type.clone()),
-                        closest_prim_of_educ:  closest_prim.as_ref().and_then(|x| x.of_educ), 
-                        closest_prim_pcode: closest_prim.as_ref().map(|x| x.pcode.clone()), 
-                        closest_prim_rwm_ta: closest_prim.as_ref().and_then(|x| x.rwm_ta),
-                        closest_prim_rwm_ta_dis: closest_prim.as_ref().and_then(|x| x.rwm_ta_dis),
-                        closest_prim_of_overall: closest_prim.as_ref().and_then(|x| x.of_overall),
-                        weighted_prim_of_educ: weighted_prim_of_educ.ave(),
-                        weighted_prim_rwm_ta: weighted_prim_rwm_ta.ave(),
-                        weighted_prim_rwm_ta_dis: weighted_prim_rwm_ta_dis.ave(),
-                        weighted_prim_of_behaviour: weighted_prim_of_behaviour.ave(),
-                        weighted_prim_of_overall: weighted_prim_of_overall.ave(),
-
-                        closest_sec_dist,
-                        closest_sec_urn: closest_sec.as_ref().map(|x| x.urn.clone()),
-                        closest_sec_type: closest_sec.as_ref().map(|x| x.school_type.clone()),
-                        closest_sec_name: closest_sec.as_ref().map(|x| x.name.clone()),
-                        closest_sec_of_educ: closest_sec.as_ref().and_then(|x| x.of_educ),
-                        closest_sec_pcode: closest_sec.as_ref().map(|x| x.pcode.clone()),
-                        closest_sec_gcseg2: closest_sec.as_ref().and_then(|x| x.gcseg2),
-                        closest_sec_gcseg2_dis: closest_sec.as_ref().and_then(|x| x.gcseg2_dis),
-                        closest_sec_of_overall: closest_sec.as_ref().and_then(|x| x.of_overall),
-                        weighted_sec_gcseg2: weighted_sec_gcseg2.ave(),
-                        weighted_sec_gcseg2_dis: weighted_sec_gcseg2_dis.ave(),
-                        weighted_sec_of_educ: weighted_sec_of_educ.ave(),
-                        weighted_sec_of_behaviour: weighted_sec_of_behaviour.ave(),
-                        weighted_sec_of_overall: weighted_sec_of_overall.ave(),
-                        weighted_sec_of_sixthform: weighted_sec_of_sixthform.ave(),
-
-                        best_sec_gcseg2,
-                        best_sec_gcseg2_dis,
-                        best_sec_of_overall,
-
-                        best_prim_of_overall, 
-                        best_prim_rwm_ta, 
-                        best_prim_rwm_ta_dis,
-
-                        sec_interact,
-                        sec_interact_dis,
-
-                        sec_interact_best,
-                        sec_interact_dis_best,
-
-                        prim_interact,
-                        prim_interact_dis,
-
-                        prim_interact_best,
-                        prim_interact_dis_best
-                    });
-                }
+    let tmp = path.as_ref().with_extension("resume");
+    {
+        let mut rdr = ReaderBuilder::new().from_path(&path)?;
+        let headers = rdr.headers()?.clone();
+        let expected = columns.columns().map_or_else(full_columns, |x| x.iter().map(|x| x.to_string()).collect());
+        if !headers.iter().eq(expected.iter()) {
+            return Err(ScaError::Missing(format!("{} columns matching the configured ones to resume", path.as_ref().display())));
+        }
+        let idx = headers.iter().position(|x| x == "pcode").ok_or_else(|| ScaError::Missing("pcode column in output".to_owned()))?;
+
+        let mut writer = Writer::from_path(&tmp)?;
+        writer.write_record(&headers)?;
+        for record in rdr.records() {
+            let record = record?;
+            if record.get(idx).map_or(false, |x| checkpoint.contains(x)) {
+                writer.write_record(&record)?;
             }
-        } else {
-            println!("No postcode location for: {}", &pcode);
         }
+        writer.flush()?;
     }
+    std::fs::rename(&tmp, &path)?;
 
-    Ok(())
+    let file = std::fs::OpenOptions::new().append(true).open(path)?;
+    Ok(csv::WriterBuilder::new().has_headers(false).buffer_capacity(buffer).from_writer(file))
 }
 
 pub fn add_region<P1: AsRef<Path>, P2: AsRef<Path>>(input: P1, out: P2, regional_data: &HashMap<String, RegionRecord>) -> Result<(), Box<dyn Error>> {
@@ -927,14 +2607,25 @@ pub fn add_region<P1: AsRef<Path>, P2: AsRef<Path>>(input: P1, out: P2, regional
                     (None, None)
                 };
                 let new_r = RegionalProcessedPcodeRecord::new(r, region, pcode_area);
-                writer.serialize(&new_r);
+                writer.serialize(&new_r)?;
             },
             Err(e) => println!("Failed to open record: {}", e),
         }
     }
+    writer.flush()?;
     Ok(())
 }
 
+/// Country of a postcode. Prefers the region lookup and falls back to the Welsh postcode areas.
+pub fn country(pcode: &str, region: Option<&str>) -> &'static str {
+    match region {
+        Some("Wales") => "Wales",
+        Some(_) => "England",
+        None if valid_region(pcode) => "England",
+        None => "Wales",
+    }
+}
+
 pub fn valid_region(pcode: &str) -> bool {
     !(pcode.starts_with("LL") || pcode.starts_with("SY") || pcode.starts_with("LD") || pcode.starts_with("SA") || pcode.starts_with("NP") || pcode.starts_with("CF"))
 }
@@ -953,37 +2644,46 @@ pub fn remove_wales<P1: AsRef<Path>, P2: AsRef<Path>>(input: P1, out: P2) -> Res
                 if r.pcode.starts_with("LL") || r.pcode.starts_with("SY") || r.pcode.starts_with("LD") || r.pcode.starts_with("SA") || r.pcode.starts_with("NP") || r.pcode.starts_with("CF") {
                     println!("removing: {}", &r.pcode);
                 } else {
-                    writer.serialize(r);
+                    writer.serialize(r)?;
                 }
             },
             Err(e) => println!("Failed to open record: {}", e),
         }
     }
+    writer.flush()?;
     Ok(())
 }
 
-pub fn run_atomic() -> Result<(), Box<dyn Error>> {
+pub fn run_atomic() -> Result<(), ScaError> {
     let regional_data = load_regional_data("areas.csv")?;
     //add_region("england_atomic.csv", "england_reg_atomic.csv", &regional_data);
     let year_range = 2019..2020;
     let regions = load_regions("postcodes.csv")?;
 
-    let postcodes = parse_postcodes("pdata.csv", &regions, year_range)?;
-    println!("Parsed {} postcodes", postcodes.len());
+    let config = AtomicConfig::default();
 
-    let sec_data: Vec<AggregateSchoolRecord> = load_school_data("all_sec.csv")?;
-    println!("Loaded {} sec schools", sec_data.len());
-
-    let prim_data: Vec<AggregatePSchoolRecord> = load_school_data("all_prim.csv")?;
-    println!("Loaded {} prim schools", prim_data.len());
+    let mut manifest = Manifest::new("run_atomic", &year_range);
+    for input in ["areas.csv", "postcodes.csv", "pdata.csv", "geo.csv"] {
+        manifest.add_input(input);
+    }
+    manifest.phases = config.phases.names();
 
-    let towns_data = parse_cities("towns.csv")?;
-    println!("Loaded {} towns", towns_data.len());
+    let sec_data: Vec<AggregateSchoolRecord> = load_phase_schools("all_sec.csv", Phase::Secondary, &config, Some(&mut manifest))?;
+    let prim_data: Vec<AggregatePSchoolRecord> = load_phase_schools("all_prim.csv", Phase::Primary, &config, Some(&mut manifest))?;
 
-    let cities_data = parse_cities("cities.csv")?;
-    println!("Loaded {} cities", cities_data.len());
+    // A single places.csv is split into towns and cities; otherwise fall back to the separate files.
+    let places = if Path::new("places.csv").exists() {
+        manifest.add_input("places.csv");
+        parse_places("places.csv", CITY_POPULATION)?
+    } else {
+        manifest.add_input("towns.csv");
+        manifest.add_input("cities.csv");
+        Places { towns: parse_cities("towns.csv")?, cities: parse_cities("cities.csv")? }
+    };
+    println!("Loaded {} towns", places.towns.len());
+    println!("Loaded {} cities", places.cities.len());
 
-    let mut geo_data = load_geo_data("geo.csv")?;
+    let geo_data = load_geo_data("geo.csv")?;
 
     let mut sec_map: HashMap<u32, Vec<AggregateSchoolRecord>> = HashMap::new();
     println!("Loading sec school geo data - may take some time...");
@@ -1007,45 +2707,1685 @@ pub fn run_atomic() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    let writer = Writer::from_path("full_atomic_async.csv")?;
+    write_distance_units("full_atomic_async.units.csv", &PCODE_DISTANCE_COLUMNS, config.distance_unit)?;
 
-    let writer_mx = Arc::new(Mutex::new(writer));
-    let sec_map = Arc::new(sec_map);
-    let prim_map = Arc::new(prim_map);
-    let towns_data = Arc::new(towns_data);
-    let cities_data = Arc::new(cities_data);
-    let geo_data = Arc::new(geo_data);
-    let regional_data = Arc::new(regional_data);
+    let mut inputs = AtomicInputs {
+        regions,
+        sec_map: Arc::new(sec_map),
+        prim_map: Arc::new(prim_map),
+        places: Arc::new(places),
+        geo_data: Arc::new(geo_data),
+        geonames_data: postal_data(),
+        regional_data: Arc::new(regional_data),
+    };
+    let paths = SalesPaths {
+        pdata: "pdata.csv".into(),
+        output: "full_atomic_async.csv".into(),
+        prices: "pcode_prices.csv".into(),
+        checkpoint: "checkpoint.txt".into(),
+    };
+    let SalesTotals { counts, records_read, chunks, failed, checkpoints } = process_sales(&paths, &mut inputs, year_range.clone(), &config)?;
+    manifest.records_read = records_read;
 
-    let mut current_map = HashMap::new();
-    let mut counter = 0;
-    let mut max = postcodes.len() / 6;
-    let fn_idx = postcodes.len() - 1;
+    println!("Wrote {}, dropped {} (no geocode)", counts.written, counts.dropped_no_geo);
+    if !config.debug_postcodes.is_empty() {
+        let n = write_contributors("contributors.csv", &inputs.sec_map, &inputs.prim_map, &inputs.geo_data, year_range.clone(), &config)?;
+        println!("Wrote {} contributing schools for {} debug postcodes", n, config.debug_postcodes.len());
+    }
+    println!("Blanked {} unusable numberrooms, {} unusable tfarea", counts.rooms_cleaned, counts.tfarea_cleaned);
+    // Only this run's rows when resuming from a checkpoint.
+    manifest.records_written = counts.written;
+    manifest.write("manifest.json")?;
+    if failed > 0 {
+        let checkpoints: Vec<String> = checkpoints.iter().map(|x| x.display().to_string()).collect();
+        println!("{} of {} chunks failed. Run incomplete - rerun to resume from {}", failed, chunks, checkpoints.join(", "));
+        return Err(ScaError::Missing(format!("output for {} of {} chunks (aggregation failed)", failed, chunks)));
+    }
 
-    let mut handles = Vec::new();
-    for (i, (k, v)) in postcodes.into_iter().enumerate() {
-        if counter < max && i < fn_idx {
-            current_map.insert(k, v);
-            counter += 1;
+    // The summary reads back whole records.
+    if config.columns == ColumnSet::Full {
+        write_lad_summary("full_atomic_async.csv", "lad_summary.csv")?;
+    }
+    // Finished, so the next run should start from scratch. Windowed runs have already removed theirs.
+    if Path::new("checkpoint.txt").exists() {
+        std::fs::remove_file("checkpoint.txt")?;
+    }
+
+    Ok(())
+}
+
+/// Everything `run_atomic` loads once and shares between the threads of every pass over the sales.
+pub struct AtomicInputs {
+    /// LAD of each postcode.
+    pub regions: HashMap<String, String>,
+    pub sec_map: Arc<HashMap<u32, Vec<AggregateSchoolRecord>>>,
+    pub prim_map: Arc<HashMap<u32, Vec<AggregatePSchoolRecord>>>,
+    pub places: Arc<Places>,
+    pub geo_data: Arc<CGeoData>,
+    pub geonames_data: Arc<Vec<PostalData>>,
+    pub regional_data: Arc<HashMap<String, RegionRecord>>,
+}
+
+/// The files `process_sales` reads and writes.
+pub struct SalesPaths {
+    pub pdata: PathBuf,
+    pub output: PathBuf,
+    pub prices: PathBuf,
+    pub checkpoint: PathBuf,
+}
+
+/// Totals over all passes of `process_sales`.
+#[derive(Debug, Default)]
+pub struct SalesTotals {
+    pub counts: AggregateCounts,
+    /// Sales parsed, after deduplication.
+    pub records_read: usize,
+    pub chunks: usize,
+    /// Chunks whose aggregation failed. The output is incomplete when this isn't zero.
+    pub failed: usize,
+    /// The checkpoints a rerun resumes from: `paths.checkpoint`, or one per window.
+    pub checkpoints: Vec<PathBuf>,
+}
+
+/// Splits `year_range` into consecutive windows of `window` years, or a single window covering it all.
+pub fn year_windows(year_range: &std::ops::Range<u32>, window: Option<u32>) -> Vec<std::ops::Range<u32>> {
+    let Some(window) = window.filter(|x| *x > 0) else {
+        return vec![year_range.clone()];
+    };
+    (year_range.start..year_range.end)
+        .step_by(window as usize)
+        .map(|start| start..(start + window).min(year_range.end))
+        .collect()
+}
+
+/// `path` with the start of `window` inserted before its extension, e.g. `checkpoint.2019.txt`.
+fn window_path(path: &Path, window: &std::ops::Range<u32>) -> PathBuf {
+    let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+    path.with_extension(format!("{}.{}", window.start, ext))
+}
+
+/// The file chunk `chunk` of the output at `path` is written to, e.g. `out.part0.csv` for `out.csv`.
+fn part_path(path: &Path, chunk: usize) -> PathBuf {
+    let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+    path.with_extension(format!("part{}.{}", chunk, ext))
+}
+
+/// The chunk files of the output at `path` left behind by an interrupted run, in chunk order.
+fn leftover_parts(path: &Path) -> Vec<PathBuf> {
+    (0..).map(|i| part_path(path, i)).take_while(|x| x.exists()).collect()
+}
+
+/// Parses, aggregates and writes the sales in `year_range`, one `config.year_window` at a time. Schools
+/// are still looked up over the whole of `year_range`, so a window's sales fall back to the same years
+/// as they would in a single pass. Duplicate transactions are only removed within a window.
+///
+/// With more than one window, each pass has its own output and checkpoint next to `paths`, so an interrupted
+/// run resumes within the pass it died in. Once every pass succeeds they are joined into `paths.output` and
+/// removed. Prices are written pass by pass, so are ordered by postcode within each window.
+///
+/// Each thread takes a run of postcodes in key order and writes them to its own chunk file (see `part_path`),
+/// and the chunks are appended to the output in order once the threads finish. So the output doesn't depend on
+/// the order the threads finished in, without holding any of it in memory.
+pub fn process_sales(paths: &SalesPaths, inputs: &mut AtomicInputs, year_range: std::ops::Range<u32>, config: &AtomicConfig) -> Result<SalesTotals, ScaError> {
+    let windows = year_windows(&year_range, config.year_window);
+    let mut totals = SalesTotals::default();
+    let mut passes = Vec::new();
+    // Each pass geocodes its postcodes into `geo_data` first, so the threads never search geonames.
+    let no_geonames: Arc<Vec<PostalData>> = Arc::new(Vec::new());
+
+    let mut price_writer = Writer::from_path(&paths.prices)?;
+    for window in windows.iter() {
+        let (output, checkpoint_path) = if windows.len() == 1 {
+            (paths.output.clone(), paths.checkpoint.clone())
         } else {
-            let writer_mx = writer_mx.clone();
-            let sec_map = sec_map.clone();
-            let prim_map = prim_map.clone();
-            let towns_data = towns_data.clone();
-            let cities_data = cities_data.clone();
-            let geo_data = geo_data.clone();
-            let regional_data = regional_data.clone();
-            handles.push(std::thread::spawn(move || {
-                aggregate_pdata(writer_mx, current_map, sec_map, prim_map, towns_data, cities_data, geo_data, regional_data, 2019..2020);
-            }));
-            counter = 0;
-            current_map = HashMap::new();
-        }     
+            println!("Processing sales for {}..{}", window.start, window.end);
+            (window_path(&paths.output, window), window_path(&paths.checkpoint, window))
+        };
+
+        let mut postcodes = parse_postcodes(&paths.pdata, &inputs.regions, window.clone(), config)?;
+        println!("Parsed {} postcodes", postcodes.len());
+        totals.records_read += postcodes.values().map(|x| x.len()).sum::<usize>();
+
+        // First pass over the prices, so bounds cover every sale even when resuming. Bounds are per year,
+        // so a window's are the same as the whole range's.
+        let mut config = config.clone();
+        config.columns = config.columns.with_pcode();
+        if let Some((lower, upper)) = config.outlier_percentiles {
+            config.outliers = Some(OutlierBounds::from_postcodes(&postcodes, lower, upper));
+        }
+
+        // The prices file is rewritten on every run, so it covers the postcodes already done as well.
+        for summary in aggregate_pcode_prices(&postcodes, window.clone()) {
+            price_writer.serialize(&summary)?;
+        }
+        price_writer.flush()?;
+
+        let checkpoint = Checkpoint::open(&checkpoint_path)?;
+        // The chunks of an interrupted run hold rows already checkpointed. Starting over, they are just dropped.
+        let leftover = leftover_parts(&output);
+        if !checkpoint.is_empty() {
+            append_outputs(&leftover, &output)?;
+        }
+        for part in leftover {
+            std::fs::remove_file(part)?;
+        }
+        if !checkpoint.is_empty() {
+            postcodes.retain(|k, _| !checkpoint.contains(k));
+            println!("Resuming: {} postcodes already done, {} remaining", checkpoint.len(), postcodes.len());
+        }
+
+        // The threads of the last pass have finished, so this doesn't copy the geo data.
+        let keys: Vec<String> = postcodes.keys().cloned().collect();
+        let located = geocode_all(&keys, &inputs.geonames_data, Arc::make_mut(&mut inputs.geo_data));
+        println!("Located {} of {} postcodes", located, keys.len());
+
+        // Only trims a resumed output (or starts a new one); the chunks are appended to it below.
+        open_output(&output, &checkpoint, &config.output_columns(), config.output_buffer)?.flush()?;
+
+        let checkpoint = Arc::new(Mutex::new(checkpoint));
+        let progress = Arc::new(Progress::new(postcodes.len(), print_progress(1000)));
+
+        let mut postcodes: Vec<(String, Vec<(PcodeRecord, Option<String>)>)> = postcodes.into_iter().collect();
+        postcodes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut current_chunk = Vec::new();
+        let mut counter = 0;
+        let max = (postcodes.len() / 6).max(1);
+        let fn_idx = postcodes.len().saturating_sub(1);
+
+        let mut handles = Vec::new();
+        let mut parts = Vec::new();
+        for (i, (k, v)) in postcodes.into_iter().enumerate() {
+            current_chunk.push((k, v));
+            counter += 1;
+            if counter >= max || i == fn_idx {
+                let part = part_path(&output, parts.len());
+                let writer_mx = Arc::new(Mutex::new(new_output(&part, &config.output_columns(), config.output_buffer)?));
+                parts.push((part, writer_mx.clone()));
+                let sec_map = inputs.sec_map.clone();
+                let prim_map = inputs.prim_map.clone();
+                let places = inputs.places.clone();
+                let geo_data = inputs.geo_data.clone();
+                let geonames_data = no_geonames.clone();
+                let regional_data = inputs.regional_data.clone();
+                let checkpoint = checkpoint.clone();
+                let config = config.clone();
+                let progress = progress.clone();
+                let year_range = year_range.clone();
+                handles.push(std::thread::spawn(move || {
+                    aggregate_pdata(writer_mx, current_chunk, sec_map, prim_map, places, geo_data, geonames_data, regional_data, year_range, &config, Some(checkpoint), Some(progress))
+                        .map_err(|e| e.to_string())
+                }));
+                counter = 0;
+                current_chunk = Vec::new();
+            }
+        }
+
+        totals.chunks += handles.len();
+        let (counts, failed) = join_chunks(handles);
+        // Failed chunks too: the rows they checkpointed are kept and the rest trimmed on resuming.
+        let parts: Vec<PathBuf> = parts
+            .into_iter()
+            .map(|(part, writer)| writer.lock().unwrap().flush().map(|_| part))
+            .collect::<Result<_, _>>()?;
+        append_outputs(&parts, &output)?;
+        for part in parts {
+            std::fs::remove_file(part)?;
+        }
+        totals.counts += counts;
+        totals.failed += failed;
+        passes.push((output, checkpoint_path));
+    }
+
+    totals.checkpoints = passes.iter().map(|(_, x)| x.clone()).collect();
+    if windows.len() > 1 && totals.failed == 0 {
+        let outputs: Vec<PathBuf> = passes.iter().map(|(x, _)| x.clone()).collect();
+        File::create(&paths.output)?;
+        append_outputs(&outputs, &paths.output)?;
+        for (output, checkpoint) in passes {
+            std::fs::remove_file(output)?;
+            std::fs::remove_file(checkpoint)?;
+        }
+    }
+
+    Ok(totals)
+}
+
+/// Appends the rows of the CSV files in `parts` to `path`, in order. A `path` without a header (new, or an empty
+/// `Full` output) gets the first one found. Every part must share its columns.
+fn append_outputs(parts: &[PathBuf], path: &Path) -> Result<(), ScaError> {
+    if parts.is_empty() {
+        return Ok(());
+    }
+    let mut header: Option<StringRecord> = None;
+    if path.exists() {
+        let headers = ReaderBuilder::new().from_path(path)?.headers()?.clone();
+        header = (!headers.is_empty()).then_some(headers);
+    }
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+    for part in parts {
+        let mut rdr = ReaderBuilder::new().from_path(part)?;
+        let headers = rdr.headers()?.clone();
+        // A `Full` part with no rows is empty, without even a header.
+        if headers.is_empty() {
+            continue;
+        }
+        match &header {
+            Some(x) if *x != headers => return Err(ScaError::Missing(format!("columns of {} matching {}", part.display(), path.display()))),
+            Some(_) => {}
+            None => {
+                writer.write_record(&headers)?;
+                header = Some(headers);
+            }
+        }
+        for record in rdr.records() {
+            writer.write_record(&record?)?;
+        }
     }
+    writer.flush()?;
+    Ok(())
+}
 
+/// Joins the aggregation threads, returning the summed counts of the chunks that finished and the number
+/// that failed, either with an error or by panicking.
+fn join_chunks(handles: Vec<std::thread::JoinHandle<Result<AggregateCounts, String>>>) -> (AggregateCounts, usize) {
+    let mut counts = AggregateCounts::default();
+    let mut failed = 0;
     for handle in handles {
-        handle.join();
+        match handle.join() {
+            Ok(Ok(c)) => counts += c,
+            Ok(Err(e)) => {
+                println!("Aggregation thread failed: {}", e);
+                failed += 1;
+            }
+            Err(_) => {
+                println!("Aggregation thread panicked");
+                failed += 1;
+            }
+        }
+    }
+    (counts, failed)
+}
+
+/// Writes the per-postcode school table for `year` to `pcode_schools.csv`, without any sale data.
+pub fn run_postcode_schools(year: u32) -> Result<(), ScaError> {
+    let config = AtomicConfig::default();
+
+    let sec_data: Vec<AggregateSchoolRecord> = load_phase_schools("all_sec.csv", Phase::Secondary, &config, None)?;
+    let prim_data: Vec<AggregatePSchoolRecord> = load_phase_schools("all_prim.csv", Phase::Primary, &config, None)?;
+
+    let geo_data = load_geo_data("geo.csv")?;
+
+    let mut sec_map: HashMap<u32, Vec<AggregateSchoolRecord>> = HashMap::new();
+    for sch in sec_data {
+        sec_map.entry(sch.year).or_default().push(sch);
     }
 
+    let mut prim_map: HashMap<u32, Vec<AggregatePSchoolRecord>> = HashMap::new();
+    for sch in prim_data {
+        prim_map.entry(sch.year).or_default().push(sch);
+    }
+
+    let rows = build_postcode_school_table(&sec_map, &prim_map, &geo_data, year, &config)?;
+
+    let mut writer = Writer::from_path("pcode_schools.csv")?;
+    write_distance_units("pcode_schools.units.csv", &["closest_sec_dist", "closest_prim_dist"], config.distance_unit)?;
+    for row in rows.iter() {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    println!("Wrote {} postcodes", rows.len());
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcode_record(id: &str, year: u32, price: f32, postcode: &str) -> PcodeRecord {
+        PcodeRecord {
+            id: id.to_owned(),
+            year,
+            propertytype: "D".to_owned(),
+            duration: "F".to_owned(),
+            priceper: None,
+            price,
+            postcode: postcode.to_owned(),
+            tfarea: None,
+            numberrooms: None,
+            classt: None,
+            age_band: None,
+        }
+    }
+
+    /// Runs a single 2019 sale at M1 1AA (in Manchester) through `aggregate_pdata` against 2019's `sec` and
+    /// `prim`, returning every output row, header first.
+    fn run_aggregate(config: &AtomicConfig, sec: Vec<AggregateSchoolRecord>, prim: Vec<AggregatePSchoolRecord>) -> Vec<csv::StringRecord> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let run = RUNS.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("scha_test_aggregate_{}.csv", run));
+        let checkpoint_path = std::env::temp_dir().join(format!("scha_test_aggregate_{}_checkpoint.txt", run));
+        let checkpoint = Checkpoint::open(&checkpoint_path).unwrap();
+
+        let pcodes = HashMap::from([("M1 1AA".to_owned(), vec![(pcode_record("{A}", 2019, 100000.0, "M1 1AA"), Some("Manchester".to_owned()))])]);
+        let geo_map = CGeoData {
+            map: HashMap::from([("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 })]),
+        };
+        let writer = Arc::new(Mutex::new(open_output(&path, &checkpoint, &config.output_columns(), DEFAULT_OUTPUT_BUFFER).unwrap()));
+        aggregate_pdata(
+            writer.clone(), pcodes, Arc::new(HashMap::from([(2019, sec)])), Arc::new(HashMap::from([(2019, prim)])),
+            Arc::new(Places::default()), Arc::new(geo_map), Arc::new(Vec::new()), Arc::new(HashMap::new()), 2019..2020, config, None, None,
+        ).unwrap();
+        writer.lock().unwrap().flush().unwrap();
+
+        let rows = ReaderBuilder::new().has_headers(false).from_path(&path).unwrap().records().map(|x| x.unwrap()).collect();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&checkpoint_path).ok();
+        rows
+    }
+
+    #[test]
+    fn test_dedup_by_id() {
+        let mut records = vec![
+            (pcode_record("{A}", 2019, 100000.0, "M1 1AA"), None),
+            (pcode_record("{B}", 2019, 150000.0, "M1 1AA"), None),
+            (pcode_record("{A}", 2019, 120000.0, "M1 1AA"), None),
+        ];
+
+        let removed = dedup_by_id(&mut records);
+
+        assert_eq!(removed, 1);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0.id, "{B}");
+        // The later correction wins.
+        assert_eq!(records[1].0.id, "{A}");
+        assert_eq!(records[1].0.price, 120000.0);
+    }
+
+    #[test]
+    fn test_aggregate_pcode_prices() {
+        let mut pcodes = HashMap::new();
+        let mut a = pcode_record("{A}", 2018, 100000.0, "M1 1AA");
+        a.priceper = Some(1000.0);
+        let mut b = pcode_record("{B}", 2018, 200000.0, "M1 1AA");
+        b.priceper = Some(2000.0);
+        let c = pcode_record("{C}", 2019, 300000.0, "M1 1AA");
+        pcodes.insert("M1 1AA".to_owned(), vec![(a, None), (b, None), (c, None)]);
+
+        let summaries = aggregate_pcode_prices(&pcodes, 2018..2019);
+
+        assert_eq!(summaries.len(), 1);
+        let s = &summaries[0];
+        assert_eq!(s.year, 2018);
+        assert_eq!(s.n, 2);
+        assert_eq!(s.mean_price, Some(150000.0));
+        assert_eq!(s.median_price, Some(150000.0));
+        assert_eq!(s.mean_priceper, Some(1500.0));
+        assert!((s.mean_price_defl.unwrap() - 150000.0 / 1.036).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_real_price() {
+        let defl = rpi_deflator(2018);
+        assert_eq!(defl, Some(1.036));
+        assert_eq!(real_price(103600.0, defl), Some(100000.0));
+
+        assert_eq!(rpi_deflator(2017), Some(1.0));
+        assert_eq!(real_price(103600.0, rpi_deflator(2016)), None);
+    }
+
+    #[test]
+    fn test_pre_base_year() {
+        use crate::{CUM_RPI_DEFL, RPI_BASE_YEAR};
+
+        assert_eq!(rpi_deflator(2015), None);
+        assert_eq!(rpi_deflator(0), None);
+        assert_eq!(rpi_deflator(RPI_BASE_YEAR + CUM_RPI_DEFL.len() as u32), None);
+
+        let mut pcodes = HashMap::new();
+        pcodes.insert("M1 1AA".to_owned(), vec![(pcode_record("{A}", 2015, 100000.0, "M1 1AA"), None)]);
+        let summaries = aggregate_pcode_prices(&pcodes, 2015..2016);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].mean_price, Some(100000.0));
+        assert_eq!(summaries[0].mean_price_defl, None);
+    }
+
+    #[test]
+    fn test_include_wales() {
+        let path = std::env::temp_dir().join("scha_test_include_wales.csv");
+        std::fs::write(&path, "id,year,propertytype,duration,priceper,price,postcode,tfarea,numberrooms,classt,CONSTRUCTION_AGE_BAND\n\
+            {A},2019,D,F,,100000,M1 1AA,,,,\n\
+            {B},2019,D,F,,100000,CF10 1AA,,,,\n").unwrap();
+
+        let regions = HashMap::new();
+        let dropped = parse_postcodes(&path, &regions, 2019..2020, &AtomicConfig::default()).unwrap();
+        assert!(!dropped.contains_key("CF10 1AA"));
+
+        let config = AtomicConfig { include_wales: true, ..Default::default() };
+        let kept = parse_postcodes(&path, &regions, 2019..2020, &config).unwrap();
+        assert!(kept.contains_key("M1 1AA"));
+        assert!(kept.contains_key("CF10 1AA"));
+
+        assert_eq!(country("CF10 1AA", None), "Wales");
+        assert_eq!(country("CF10 1AA", Some("Wales")), "Wales");
+        assert_eq!(country("M1 1AA", None), "England");
+        assert_eq!(country("SY1 1AA", Some("West Midlands")), "England");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_postcode_grouping() {
+        let path = std::env::temp_dir().join("scha_test_postcode_grouping.csv");
+        std::fs::write(&path, "id,year,propertytype,duration,priceper,price,postcode,tfarea,numberrooms,classt,CONSTRUCTION_AGE_BAND\n\
+            {A},2019,D,F,,100000,M1 1AA,,,,\n\
+            {B},2019,D,F,,120000,M11AA,,,,\n\
+            {C},2019,D,F,,130000, m1  1aa ,,,,\n").unwrap();
+
+        let pcodes = parse_postcodes(&path, &HashMap::new(), 2019..2020, &AtomicConfig::default()).unwrap();
+        assert_eq!(pcodes.len(), 1);
+        let group = &pcodes["M1 1AA"];
+        assert_eq!(group.iter().map(|(x, _)| x.id.as_str()).collect::<Vec<_>>(), ["{A}", "{B}", "{C}"]);
+        assert!(group.iter().all(|(x, _)| x.postcode == "M1 1AA"));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Row {
+        pcode: String,
+        price: u32,
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let dir = std::env::temp_dir().join("scha_test_checkpoint_resume");
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.csv");
+        let cp = dir.join("checkpoint.txt");
+        std::fs::remove_file(&cp).ok();
+
+        let all = ["A", "B", "C", "D"];
+        let row = |pcode: &str| Row { pcode: pcode.to_owned(), price: 100 };
+        let columns = ColumnSet::Custom(vec!["pcode".to_owned(), "price".to_owned()]);
+        let write = |writer: &mut Writer<File>, pcode: &str| write_columns(writer, &row(pcode), &columns, Precision::default()).unwrap();
+
+        // First run: A and B are checkpointed, C is written but the run dies before recording it.
+        {
+            let checkpoint = Checkpoint::open(&cp).unwrap();
+            let writer = Mutex::new(open_output(&out, &checkpoint, &columns, DEFAULT_OUTPUT_BUFFER).unwrap());
+            let checkpoint = Mutex::new(checkpoint);
+            for pcode in ["A", "B"] {
+                write(&mut writer.lock().unwrap(), pcode);
+            }
+            commit_checkpoint(&writer, &checkpoint, &mut vec!["A".to_owned(), "B".to_owned()]).unwrap();
+            write(&mut writer.lock().unwrap(), "C");
+            writer.lock().unwrap().flush().unwrap();
+        }
+
+        // Resume: only the postcodes missing from the checkpoint are processed.
+        {
+            let checkpoint = Checkpoint::open(&cp).unwrap();
+            assert_eq!(checkpoint.len(), 2);
+            let remaining: Vec<&str> = all.iter().copied().filter(|x| !checkpoint.contains(x)).collect();
+            assert_eq!(remaining, vec!["C", "D"]);
+
+            // Other columns than the output was started with can't be appended.
+            let before = std::fs::read(&out).unwrap();
+            assert!(open_output(&out, &checkpoint, &ColumnSet::Custom(vec!["price".to_owned(), "pcode".to_owned()]), DEFAULT_OUTPUT_BUFFER).is_err());
+            assert!(open_output(&out, &checkpoint, &ColumnSet::Full, DEFAULT_OUTPUT_BUFFER).is_err());
+            assert_eq!(std::fs::read(&out).unwrap(), before);
+
+            let mut writer = open_output(&out, &checkpoint, &columns, DEFAULT_OUTPUT_BUFFER).unwrap();
+            for pcode in remaining {
+                write(&mut writer, pcode);
+            }
+            writer.flush().unwrap();
+        }
+
+        let rows: Vec<Row> = load_school_data(&out).unwrap();
+        assert_eq!(rows, all.iter().map(|x| row(x)).collect::<Vec<_>>());
+
+        // Resuming a whole run: M1 1AA was checkpointed, M2 2BB wasn't. The prices still cover both.
+        let (pdata, mut inputs) = sales_fixture(&dir);
+        let paths = sales_paths(&dir, &pdata, "sales");
+        std::fs::remove_file(&paths.checkpoint).ok();
+        process_sales(&paths, &mut inputs, 2018..2021, &AtomicConfig::default()).unwrap();
+        let (output, prices) = (sorted_rows(&paths.output), sorted_rows(&paths.prices));
+        assert_eq!(prices.iter().filter(|x| x[0] == "M1 1AA").count(), 3);
+
+        std::fs::write(&paths.checkpoint, "M1 1AA\n").unwrap();
+        let totals = process_sales(&paths, &mut inputs, 2018..2021, &AtomicConfig::default()).unwrap();
+        assert_eq!(totals.counts.written, 2);
+        assert_eq!(sorted_rows(&paths.prices), prices);
+        assert_eq!(sorted_rows(&paths.output), output);
+
+        // Dying before the chunks were appended: the checkpointed rows are still picked up from them.
+        std::fs::rename(&paths.output, part_path(&paths.output, 0)).unwrap();
+        std::fs::write(&paths.output, "").unwrap();
+        std::fs::write(&paths.checkpoint, "M1 1AA\n").unwrap();
+        let totals = process_sales(&paths, &mut inputs, 2018..2021, &AtomicConfig::default()).unwrap();
+        assert_eq!(totals.counts.written, 2);
+        assert_eq!(sorted_rows(&paths.output), output);
+        assert!(leftover_parts(&paths.output).is_empty());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_flush_every() {
+        let dir = std::env::temp_dir().join("scha_test_flush_every");
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.csv");
+        let cp = dir.join("checkpoint.txt");
+        std::fs::remove_file(&cp).ok();
+
+        let config = AtomicConfig { flush_every: 3, ..AtomicConfig::default() };
+        let mut writer = open_output(&out, &Checkpoint::open(&cp).unwrap(), &config.columns, config.output_buffer).unwrap();
+        let on_disk = || load_school_data::<_, Row>(&out).unwrap().len();
+
+        for (i, pcode) in ["A", "B", "C", "D"].iter().enumerate() {
+            write_output(&mut writer, &Row { pcode: pcode.to_string(), price: 100 }, &config, i + 1).unwrap();
+            // Nothing reaches the file until the third row, and the fourth waits for the final flush.
+            assert_eq!(on_disk(), if i < 2 { 0 } else { 3 });
+        }
+        writer.flush().unwrap();
+        assert_eq!(on_disk(), 4);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Sales in 2018-2020 at two postcodes, written to `dir`, and the schools and locations to process them with.
+    fn sales_fixture(dir: &Path) -> (PathBuf, AtomicInputs) {
+        use crate::tests::aggregate_school;
+
+        std::fs::create_dir_all(dir).unwrap();
+        let pdata = dir.join("pdata.csv");
+        std::fs::write(&pdata, "id,year,propertytype,duration,priceper,price,postcode,tfarea,numberrooms,classt,CONSTRUCTION_AGE_BAND\n\
+            {A},2018,D,F,,100000,M1 1AA,,,,\n\
+            {B},2019,D,F,,120000,M1 1AA,,,,\n\
+            {C},2020,D,F,,130000,M1 1AA,,,,\n\
+            {D},2019,D,F,,90000,M2 2BB,,,,\n\
+            {E},2020,D,F,,95000,M2 2BB,,,,\n").unwrap();
+
+        let geo = |pcode: &str, lat: f64| (pcode.to_owned(), GeoRecord { pcode: pcode.to_owned(), lat, long: -2.2453 });
+        // No 2020 schools, so 2020 sales fall back to 2019's.
+        let inputs = AtomicInputs {
+            regions: HashMap::new(),
+            sec_map: Arc::new(HashMap::from([
+                (2018, vec![AggregateSchoolRecord { year: 2018, ..aggregate_school() }]),
+                (2019, vec![AggregateSchoolRecord { year: 2019, ..aggregate_school() }]),
+            ])),
+            prim_map: Arc::new(HashMap::new()),
+            places: Arc::new(Places::default()),
+            geo_data: Arc::new(CGeoData { map: HashMap::from([geo("M1 1AA", 53.4794), geo("M2 2BB", 53.4808)]) }),
+            geonames_data: Arc::new(Vec::new()),
+            regional_data: Arc::new(HashMap::new()),
+        };
+        (pdata, inputs)
+    }
+
+    fn sales_paths(dir: &Path, pdata: &Path, name: &str) -> SalesPaths {
+        SalesPaths {
+            pdata: pdata.to_owned(),
+            output: dir.join(format!("{}.csv", name)),
+            prices: dir.join(format!("{}_prices.csv", name)),
+            checkpoint: dir.join(format!("{}_checkpoint.txt", name)),
+        }
+    }
+
+    /// The rows of a CSV, sorted, for comparing outputs written in any order.
+    fn sorted_rows(path: &Path) -> Vec<Vec<String>> {
+        let mut rows: Vec<Vec<String>> = csv::Reader::from_path(path).unwrap().records().map(|x| x.unwrap().iter().map(str::to_owned).collect()).collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn test_year_windows() {
+        assert_eq!(year_windows(&(2017..2024), Some(3)), vec![2017..2020, 2020..2023, 2023..2024]);
+        assert_eq!(year_windows(&(2017..2024), None), vec![2017..2024]);
+        assert_eq!(year_windows(&(2017..2024), Some(0)), vec![2017..2024]);
+
+        let dir = std::env::temp_dir().join("scha_test_year_windows");
+        let (pdata, mut inputs) = sales_fixture(&dir);
+        let mut run = |name: &str, year_window: Option<u32>| {
+            let paths = sales_paths(&dir, &pdata, name);
+            std::fs::remove_file(&paths.checkpoint).ok();
+            let config = AtomicConfig { year_window, ..AtomicConfig::default() };
+            let totals = process_sales(&paths, &mut inputs, 2018..2021, &config).unwrap();
+            assert_eq!(totals.failed, 0);
+            (totals.counts, totals.records_read, sorted_rows(&paths.output), sorted_rows(&paths.prices))
+        };
+
+        let whole = run("whole", None);
+        let windowed = run("windowed", Some(1));
+        assert_eq!(whole.0.written, 5);
+        assert_eq!(windowed, whole);
+        // Only the joined output is left behind.
+        assert!(!dir.join("windowed.2019.csv").exists());
+        assert!(!dir.join("windowed_checkpoint.2019.txt").exists());
+
+        // Rerunning after 2018's window finished still writes its prices.
+        std::fs::write(dir.join("windowed_checkpoint.2018.txt"), "M1 1AA\n").unwrap();
+        let config = AtomicConfig { year_window: Some(1), ..AtomicConfig::default() };
+        let paths = sales_paths(&dir, &pdata, "windowed");
+        let totals = process_sales(&paths, &mut inputs, 2018..2021, &config).unwrap();
+        assert_eq!(totals.counts.written, 4);
+        assert_eq!(sorted_rows(&paths.prices), whole.3);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Each postcode gets its own thread in the fixture, so without the chunk files the rows would come out in
+    /// whichever order the threads finished.
+    #[test]
+    fn test_reproducible_sales() {
+        let dir = std::env::temp_dir().join("scha_test_reproducible_sales");
+        let (pdata, mut inputs) = sales_fixture(&dir);
+        let mut run = |name: &str| {
+            let paths = sales_paths(&dir, &pdata, name);
+            std::fs::remove_file(&paths.checkpoint).ok();
+            assert_eq!(process_sales(&paths, &mut inputs, 2018..2021, &AtomicConfig::default()).unwrap().chunks, 2);
+            assert!(leftover_parts(&paths.output).is_empty());
+            (std::fs::read(&paths.output).unwrap(), std::fs::read(&paths.prices).unwrap())
+        };
+
+        let first = run("first");
+        assert_eq!(run("second"), first);
+        let mut rdr = csv::Reader::from_reader(first.0.as_slice());
+        let idx = rdr.headers().unwrap().iter().position(|x| x == "pcode").unwrap();
+        let pcodes: Vec<String> = rdr.records().map(|x| x.unwrap()[idx].to_owned()).collect();
+        assert_eq!(pcodes[0], "M1 1AA");
+        assert_eq!(pcodes.last().unwrap(), "M2 2BB");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_post16_only() {
+        use crate::tests::aggregate_school;
+
+        // The closer school has no sixth form; the further one is a 16-19 school.
+        let schools = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.8), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), lat: Some(53.4894), school_type: "FD1619".to_owned(), gcseg2: Some(0.5), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "3".to_owned(), lat: Some(53.4994), of_sixthform: Some(1), gcseg2: Some(0.3), ..aggregate_school() },
+        ];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        let all = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(all.closest.map(|x| x.urn), Some("1".to_owned()));
+        assert_eq!(all.best_gcseg2, Some(0.8));
+
+        let config = AtomicConfig { post16_only: true, ..AtomicConfig::default() };
+        let post16 = scan_secondary(&loc, None, &schools, &config);
+        assert_eq!(post16.closest.map(|x| x.urn), Some("2".to_owned()));
+        assert_eq!(post16.best_gcseg2, Some(0.5));
+        assert_eq!(post16.weighted_of_sixthform, Some(1.0));
+        let w = post16.weighted_gcseg2.unwrap();
+        assert!(w > 0.3 && w < 0.5);
+    }
+
+    #[test]
+    fn test_haversine_km() {
+        let manchester = GeoLocation { latitude: 53.4808, longitude: -2.2426 };
+
+        let d = haversine_km(&LONDON, &manchester);
+        assert!((d - 262.0).abs() < 2.0, "London-Manchester was {} km", d);
+        assert_eq!(haversine_km(&manchester, &LONDON), d);
+        assert_eq!(haversine_km(&LONDON, &LONDON), 0.0);
+    }
+
+    #[test]
+    fn test_type_filter() {
+        use crate::tests::aggregate_school;
+
+        let schools = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), school_type: "AC".to_owned(), gcseg2: Some(0.9), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), lat: Some(53.4894), gcseg2: Some(0.6), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "3".to_owned(), lat: Some(53.4994), gcseg2: Some(0.4), ..aggregate_school() },
+        ];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        let config = AtomicConfig { type_filter: Some(HashSet::from(["CY".to_owned()])), ..AtomicConfig::default() };
+        let cy = scan_secondary(&loc, None, &schools, &config);
+        assert_eq!(cy.closest.map(|x| x.urn), Some("2".to_owned()));
+        assert_eq!(cy.best_gcseg2, Some(0.6));
+        let w = cy.weighted_gcseg2.unwrap();
+        assert!(w > 0.4 && w < 0.6);
+
+        let all = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(all.closest.map(|x| x.urn), Some("1".to_owned()));
+        assert_eq!(all.best_gcseg2, Some(0.9));
+    }
+
+    #[test]
+    fn test_exclude_urns() {
+        use crate::tests::aggregate_school;
+
+        let schools = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.9), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), lat: Some(53.4894), gcseg2: Some(0.6), ..aggregate_school() },
+        ];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        let config = AtomicConfig { exclude_urns: HashSet::from(["1".to_owned()]), ..AtomicConfig::default() };
+        let m = scan_secondary(&loc, None, &schools, &config);
+        assert_eq!(m.closest.map(|x| x.urn), Some("2".to_owned()));
+        assert_eq!(m.best_gcseg2, Some(0.6));
+        assert_eq!(m.weighted_gcseg2, Some(0.6));
+
+        let all = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(all.closest.map(|x| x.urn), Some("1".to_owned()));
+        assert_eq!(all.best_gcseg2, Some(0.9));
+    }
+
+    #[test]
+    fn test_progress() {
+        let last = Arc::new(AtomicUsize::new(0));
+        let seen = last.clone();
+        let progress = Arc::new(Progress::new(400, move |done, total| {
+            assert_eq!(total, 400);
+            seen.fetch_max(done, Ordering::Relaxed);
+        }));
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let progress = progress.clone();
+            std::thread::spawn(move || (0..100).for_each(|_| progress.tick()))
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(progress.done(), 400);
+        assert_eq!(last.load(Ordering::Relaxed), 400);
+
+        assert_eq!(format_progress(1000, 5000, Duration::from_secs(10)), "1000 of 5000 pcodes (20.0%), 100 pcodes/s, ETA 0m40s");
+    }
+
+    #[test]
+    fn test_aggregate_counts() {
+        let path = std::env::temp_dir().join("scha_test_aggregate_counts.csv");
+        let writer = Arc::new(Mutex::new(Writer::from_path(&path).unwrap()));
+
+        let geo_map = CGeoData {
+            map: HashMap::from([("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 })]),
+        };
+        let pcodes = HashMap::from([
+            ("M1 1AA".to_owned(), vec![
+                (PcodeRecord { numberrooms: Some("N/A".to_owned()), tfarea: Some(0.0), ..pcode_record("{A}", 2019, 100000.0, "M1 1AA") }, None),
+                (PcodeRecord { numberrooms: Some("4.0".to_owned()), tfarea: Some(85.5), ..pcode_record("{B}", 2019, 120000.0, "M1 1AA") }, None),
+            ]),
+            ("ZZ9 9ZZ".to_owned(), vec![(pcode_record("{C}", 2019, 90000.0, "ZZ9 9ZZ"), None)]),
+        ]);
+
+        let counts = aggregate_pdata(
+            writer.clone(), pcodes, Arc::new(HashMap::new()), Arc::new(HashMap::new()), Arc::new(Places::default()),
+            Arc::new(geo_map), Arc::new(Vec::new()), Arc::new(HashMap::new()), 2019..2020, &AtomicConfig::default(), None, None,
+        ).unwrap();
+        writer.lock().unwrap().flush().unwrap();
+
+        assert_eq!((counts.written, counts.dropped_no_geo), (2, 1));
+        assert_eq!((counts.rooms_cleaned, counts.tfarea_cleaned), (1, 1));
+        let rows = ReaderBuilder::new().from_path(&path).unwrap().records().count();
+        assert_eq!(rows, 2);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_parse_places() {
+        let path = std::env::temp_dir().join("scha_test_parse_places.csv");
+        std::fs::write(&path, "city,lat,lng,admin_name,population,population_proper,place_type
+Manchester,53.4794,-2.2453,Manchester,2705000,547627,
+Bolton,53.5780,-2.4290,Bolton,194189,194189,
+Altrincham,53.3838,-2.3547,Trafford,52419,52419,
+Ely,52.3990,0.2620,Cambridgeshire,20256,20256,city
+Reading,51.4542,-0.9731,Reading,318014,161780,town
+").unwrap();
+
+        let places = parse_places(&path, 100_000).unwrap();
+        let names = |x: &[Town]| x.iter().map(|t| t.record.city.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&places.cities), ["Manchester", "Bolton", "Ely"]);
+        assert_eq!(names(&places.towns), ["Altrincham", "Reading"]);
+
+        let places = parse_places(&path, 1_000_000).unwrap();
+        assert_eq!(names(&places.cities), ["Manchester", "Ely"]);
+
+        // The old per-file loader still reads a file without the place_type column.
+        std::fs::write(&path, "city,lat,lng,admin_name,population,population_proper
+Bolton,53.5780,-2.4290,Bolton,194189,194189
+").unwrap();
+        assert_eq!(parse_cities(&path).unwrap().len(), 1);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_postcode_school_table() {
+        use crate::tests::aggregate_school;
+
+        let geo = CGeoData {
+            map: HashMap::from([
+                ("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 }),
+                ("SW1A 1AA".to_owned(), GeoRecord { pcode: " sw1a1aa".to_owned(), lat: 51.5010, long: -0.1416 }),
+            ]),
+        };
+        let sec_map = HashMap::from([
+            (2017, vec![AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.4), year: 2017, ..aggregate_school() }]),
+            (2019, vec![AggregateSchoolRecord { urn: "2".to_owned(), gcseg2: Some(0.6), ..aggregate_school() }]),
+            (2021, vec![AggregateSchoolRecord { urn: "3".to_owned(), gcseg2: Some(0.8), year: 2021, ..aggregate_school() }]),
+        ]);
+
+        let rows = build_postcode_school_table(&sec_map, &HashMap::new(), &geo, 2020, &AtomicConfig::default()).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].pcode, "M1 1AA");
+        assert_eq!(rows[0].sec_est_year, Some(2019));
+        assert_eq!(rows[0].closest_sec_urn.as_deref(), Some("2"));
+        assert_eq!(rows[0].weighted_sec_gcseg2, Some(0.6));
+        assert_eq!(rows[0].prim_est_year, None);
+        assert_eq!(rows[0].closest_prim_urn, None);
+
+        // London is far outside the weighting radius of the Manchester school, but it is still the closest.
+        // Its postcode is written in the same normalised form as the sales output.
+        assert_eq!(rows[1].pcode, "SW1A 1AA");
+        assert_eq!(rows[1].closest_sec_urn.as_deref(), Some("2"));
+        assert!(rows[1].closest_sec_dist.unwrap() > 200.0);
+        assert_eq!(rows[1].weighted_sec_gcseg2, None);
+    }
+
+    #[test]
+    fn test_year_exact() {
+        use crate::tests::aggregate_school;
+
+        let path = std::env::temp_dir().join("scha_test_year_exact.csv");
+        let writer = Arc::new(Mutex::new(Writer::from_path(&path).unwrap()));
+
+        let geo_map = CGeoData {
+            map: HashMap::from([("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 })]),
+        };
+        let pcodes = HashMap::from([
+            ("M1 1AA".to_owned(), vec![
+                (pcode_record("{A}", 2018, 100000.0, "M1 1AA"), None),
+                (pcode_record("{B}", 2019, 120000.0, "M1 1AA"), None),
+            ]),
+        ]);
+        // Only 2018 school data, so the 2019 sale falls back to it.
+        let sec_map = HashMap::from([(2018, vec![AggregateSchoolRecord { year: 2018, ..aggregate_school() }])]);
+
+        aggregate_pdata(
+            writer.clone(), pcodes, Arc::new(sec_map), Arc::new(HashMap::new()), Arc::new(Places::default()),
+            Arc::new(geo_map), Arc::new(Vec::new()), Arc::new(HashMap::new()), 2018..2020, &AtomicConfig::default(), None, None,
+        ).unwrap();
+        writer.lock().unwrap().flush().unwrap();
+
+        let mut rows: Vec<RegionalProcessedPcodeRecord> = ReaderBuilder::new().from_path(&path).unwrap().deserialize().map(|x| x.unwrap()).collect();
+        rows.sort_by_key(|x| x.year);
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].sec_est_year, rows[0].sec_year_exact), (Some(2018), Some(1)));
+        assert_eq!((rows[1].sec_est_year, rows[1].sec_year_exact), (Some(2018), Some(0)));
+        assert_eq!((rows[1].prim_est_year, rows[1].prim_year_exact), (None, None));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_summarize_by_lad() {
+        use crate::tests::aggregate_school;
+
+        let path = std::env::temp_dir().join("scha_test_lad_summary.csv");
+        let writer = Arc::new(Mutex::new(Writer::from_path(&path).unwrap()));
+
+        let geo_map = CGeoData {
+            map: HashMap::from([
+                ("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 }),
+                ("M5 4WT".to_owned(), GeoRecord { pcode: "M5 4WT".to_owned(), lat: 53.4875, long: -2.2901 }),
+            ]),
+        };
+        let manchester = Some("Manchester".to_owned());
+        let salford = Some("Salford".to_owned());
+        let pcodes = HashMap::from([
+            ("M1 1AA".to_owned(), vec![
+                (pcode_record("{A}", 2018, 103600.0, "M1 1AA"), manchester.clone()),
+                (pcode_record("{B}", 2018, 207200.0, "M1 1AA"), manchester.clone()),
+                (pcode_record("{C}", 2019, 107018.8, "M1 1AA"), manchester),
+                (pcode_record("{D}", 2019, 107018.8, "M1 1AA"), None),
+            ]),
+            ("M5 4WT".to_owned(), vec![
+                (pcode_record("{E}", 2018, 103600.0, "M5 4WT"), salford.clone()),
+                (pcode_record("{F}", 2019, 214037.6, "M5 4WT"), salford),
+            ]),
+        ]);
+        let sec_map = HashMap::from([
+            (2018, vec![AggregateSchoolRecord { year: 2018, gcseg2: Some(0.5), ..aggregate_school() }]),
+            (2019, vec![AggregateSchoolRecord { year: 2019, gcseg2: Some(0.7), ..aggregate_school() }]),
+        ]);
+
+        aggregate_pdata(
+            writer.clone(), pcodes, Arc::new(sec_map), Arc::new(HashMap::new()), Arc::new(Places::default()),
+            Arc::new(geo_map), Arc::new(Vec::new()), Arc::new(HashMap::new()), 2018..2020, &AtomicConfig::default(), None, None,
+        ).unwrap();
+        writer.lock().unwrap().flush().unwrap();
+
+        let records: Vec<RegionalProcessedPcodeRecord> = load_school_data(&path).unwrap();
+        let summaries = summarize_by_lad(&records);
+
+        let keys: Vec<(&str, u32, u32)> = summaries.iter().map(|x| (x.lad.as_str(), x.year, x.n)).collect();
+        assert_eq!(keys, [("Manchester", 2018, 2), ("Manchester", 2019, 1), ("Salford", 2018, 1), ("Salford", 2019, 1), (UNKNOWN_LAD, 2019, 1)]);
+
+        let close = |a: Option<f32>, b: f32| (a.unwrap() - b).abs() < 0.5;
+        assert!(close(summaries[0].mean_price_real, 150000.0));
+        assert!(close(summaries[1].mean_price_real, 100000.0));
+        assert!(close(summaries[3].mean_price_real, 200000.0));
+        assert_eq!(summaries[0].mean_closest_sec_gcseg2, Some(0.5));
+        assert_eq!(summaries[3].mean_closest_sec_gcseg2, Some(0.7));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_column_set() {
+        let write = |columns: ColumnSet| run_aggregate(&AtomicConfig { columns, ..AtomicConfig::default() }, Vec::new(), Vec::new());
+
+        let rows = write(ColumnSet::Minimal);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].iter().collect::<Vec<_>>(), MINIMAL_COLUMNS);
+        assert_eq!(&rows[1][2], "M1 1AA");
+        assert_eq!(&rows[1][3], "100000.0");
+        assert_eq!(&rows[1][9], "Manchester");
+
+        let rows = write(ColumnSet::Custom(vec!["pcode".to_owned(), "year".to_owned()]));
+        assert_eq!(rows[0].iter().collect::<Vec<_>>(), ["pcode", "year"]);
+        assert_eq!(rows[1].iter().collect::<Vec<_>>(), ["M1 1AA", "2019"]);
+        // Resumable outputs keep `pcode` even when left out.
+        let columns = ColumnSet::Custom(vec!["year".to_owned(), "price".to_owned()]).with_pcode();
+        assert_eq!(columns.columns().unwrap(), ["pcode", "year", "price"]);
+        assert_eq!(ColumnSet::Minimal.with_pcode(), ColumnSet::Minimal);
+
+        let full = write(ColumnSet::Full);
+        assert!(full[0].len() > 60);
+        let schools = write(ColumnSet::Schools);
+        assert_eq!(schools[0].len(), MINIMAL_COLUMNS.len() + SCHOOL_COLUMNS.len());
+    }
+
+    #[test]
+    fn test_precision() {
+        assert_eq!(round_field("1.2384717", 2), "1.24");
+        assert_eq!(round_field("1e-7", 2), "0.00");
+        assert_eq!(round_field("2019", 2), "2019");
+        assert_eq!(round_field("100000.0", 2), "100000.0");
+        assert_eq!(round_field("M1 1AA", 2), "M1 1AA");
+        assert_eq!(round_field("", 2), "");
+
+        let write = |precision: Precision| run_aggregate(&AtomicConfig { precision, ..AtomicConfig::default() }, Vec::new(), Vec::new());
+
+        let full = write(Precision::default());
+        let rounded = write(Precision { distances: Some(3), values: Some(2) });
+
+        // Same header and row length, whether serialized directly or written field by field.
+        assert_eq!(rounded[0], full[0]);
+        assert_eq!(rounded.len(), 2);
+        let field = |rows: &[csv::StringRecord], column: &str| rows[1][rows[0].iter().position(|x| x == column).unwrap()].to_owned();
+        assert_eq!(field(&full, "lat"), "53.4794");
+        assert_eq!(field(&rounded, "lat"), "53.48");
+        assert_eq!(field(&rounded, "price"), "100000.0");
+        assert_eq!(field(&rounded, "pcode"), "M1 1AA");
+        let london = field(&rounded, "dist_london");
+        assert_eq!(london.split('.').nth(1).map(str::len), Some(3), "{}", london);
+        assert!((london.parse::<f64>().unwrap() - field(&full, "dist_london").parse::<f64>().unwrap()).abs() <= 5e-4);
+    }
+
+    #[test]
+    fn test_geocode_all() {
+        let postal = |pcode: &str, lat: f64| PostalData {
+            country_code: "GB".to_owned(),
+            postal_code: pcode.to_owned(),
+            place_name: None,
+            admin_name1: None,
+            admin_code1: None,
+            admin_name2: None,
+            admin_code2: None,
+            admin_name3: None,
+            admin_code3: None,
+            geolocation: Some(GeoLocation { latitude: lat, longitude: -2.0 }),
+            accuracy: geo_rust::Accuracy::Centroid,
+        };
+        let geonames = vec![postal("M1 1AA", 50.0), postal("M2 2BB", 51.0), postal("M3 3CC", 52.0)];
+        let cached_only = CGeoData {
+            map: HashMap::from([("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 })]),
+        };
+        let pcodes: Vec<String> = ["M1 1AA", "m22bb", "M3 3CC", "ZZ9 9ZZ"].map(String::from).to_vec();
+
+        let mut cache = cached_only.clone();
+        let located = geocode_all(&pcodes, &geonames, &mut cache);
+        assert_eq!(located, 3);
+        for pcode in pcodes.iter() {
+            // Afterwards the cache alone is enough, and it won over geonames, as in `geo_data`.
+            assert_eq!(geo_data(pcode, &cache, &[]), geo_data(pcode, &cached_only, &geonames));
+        }
+        assert_eq!(cache.map.len(), 3);
+    }
+
+    #[test]
+    fn test_cached() {
+        static CELL: OnceLock<Arc<Vec<u32>>> = OnceLock::new();
+        let loads = AtomicUsize::new(0);
+        let load = || {
+            loads.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2, 3]
+        };
+
+        let a = cached(&CELL, load);
+        let b = cached(&CELL, load);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_two_closest_places() {
+        let town = |name: &str, lat: f64, lng: f64| Town {
+            record: TownRecord {
+                city: name.to_owned(),
+                lat,
+                lng,
+                admin_name: name.to_owned(),
+                population: 50000,
+                population_proper: 50000,
+                place_type: None,
+            },
+            loc: GeoLocation { latitude: lat, longitude: lng },
+        };
+        let towns = vec![
+            town("Stockport", 53.4083, -2.1494),
+            town("Bolton", 53.5780, -2.4290),
+            town("Salford", 53.4875, -2.2901),
+        ];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        let [first, second] = two_closest_places(&loc, &towns);
+        let (first, first_dist) = first.unwrap();
+        let (second, second_dist) = second.unwrap();
+        assert_eq!(first.record.city, "Salford");
+        assert_eq!(second.record.city, "Stockport");
+        assert!(first_dist < second_dist);
+        assert_eq!(closest_place(&loc, &towns).map(|(x, _)| x.record.city.clone()), Some("Salford".to_owned()));
+
+        assert!(two_closest_places(&loc, &towns[..1])[1].is_none());
+    }
+
+    #[test]
+    fn test_distance_unit() {
+        assert!((DistanceUnit::Miles.from_km(1.0) - 0.621).abs() < 1e-3);
+        assert_eq!(DistanceUnit::Km.from_km(1.0), 1.0);
+        assert_eq!(DistanceUnit::Miles.convert_f32(Some(KM_PER_MILE as f32)), Some(1.0));
+        assert_eq!(DistanceUnit::Miles.convert::<f64>(None), None);
+
+        let path = std::env::temp_dir().join("scha_test_distance_units.csv");
+        write_distance_units(&path, &PCODE_DISTANCE_COLUMNS, DistanceUnit::Miles).unwrap();
+        let rows: Vec<StringRecord> = ReaderBuilder::new().from_path(&path).unwrap().records().map(|x| x.unwrap()).collect();
+        assert_eq!(rows.len(), PCODE_DISTANCE_COLUMNS.len());
+        assert!(rows.iter().all(|x| &x[1] == "miles"));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_clean_rooms_and_area() {
+        assert_eq!(parse_rooms("4"), Some(4));
+        assert_eq!(parse_rooms("4.0"), Some(4));
+        assert_eq!(parse_rooms(" 4 "), Some(4));
+        assert_eq!(parse_rooms(""), None);
+        assert_eq!(parse_rooms("4.5"), None);
+        assert_eq!(parse_rooms("N/A"), None);
+        assert_eq!(parse_rooms("-1"), None);
+
+        assert_eq!(clean_area(0.0), None);
+        assert_eq!(clean_area(-12.0), None);
+        assert_eq!(clean_area(f32::NAN), None);
+        assert_eq!(clean_area(85.5), Some(85.5));
+    }
+
+    #[test]
+    fn test_missing_file_error() {
+        let path = std::env::temp_dir().join("scha_test_does_not_exist.csv");
+
+        let err = parse_cities(&path).err().unwrap();
+        assert!(matches!(err, ScaError::Io(ref e) if e.kind() == io::ErrorKind::NotFound), "{:?}", err);
+
+        let err = load_school_data::<_, AggregateSchoolRecord>(&path).err().unwrap();
+        assert!(matches!(err, ScaError::Io(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_bounds_prefilter() {
+        use crate::tests::aggregate_school;
+
+        // The second school's BNG position is ~100 km away, so the prefilter skips it even though its
+        // (deliberately inconsistent) lat/lng would put it within MAX_DIST.
+        let schools = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.6), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), lat: Some(53.4894), x_km: Some(483.8), gcseg2: Some(0.9), ..aggregate_school() },
+        ];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+        let pos = Some((383.8, 398.1));
+
+        let filtered = scan_secondary(&loc, pos, &schools, &AtomicConfig::default());
+        assert_eq!(filtered.closest.map(|x| x.urn), Some("1".to_owned()));
+        assert_eq!(filtered.best_gcseg2, Some(0.6));
+
+        // Without a projected position nothing is prefiltered; the nearest school is the same.
+        let unfiltered = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(unfiltered.closest.map(|x| x.urn), Some("1".to_owned()));
+        assert_eq!(unfiltered.best_gcseg2, Some(0.9));
+
+        assert!(!outside_bounds(pos, Some(388.0), Some(398.1), Some(1.0), MAX_DIST));
+        assert!(outside_bounds(pos, Some(483.8), Some(398.1), Some(1.0), MAX_DIST));
+        assert!(!outside_bounds(pos, Some(483.8), Some(398.1), None, MAX_DIST));
+    }
+
+    #[test]
+    fn test_closest_tie_break() {
+        use crate::tests::aggregate_school;
+
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+        let a = AggregateSchoolRecord { urn: "100200".to_owned(), lat: Some(53.4894), ..aggregate_school() };
+        let b = AggregateSchoolRecord { urn: "99999".to_owned(), lat: Some(53.4894), ..aggregate_school() };
+
+        for schools in [vec![a.clone(), b.clone()], vec![b, a]] {
+            let m = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+            assert_eq!(m.closest.map(|x| x.urn), Some("99999".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_of_overall_pct() {
+        use crate::tests::aggregate_school;
+
+        let schools = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), of_overall: Some(1), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), of_overall: Some(3), ..aggregate_school() },
+        ];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        // Both at the same place, so equally weighted.
+        let m = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(m.of_overall_pct, Some([50.0, 0.0, 50.0, 0.0]));
+        assert_eq!(m.weighted_of_overall, Some(2.0));
+
+        assert_eq!(GradeShares::default().pct(), None);
+    }
+
+    #[test]
+    fn test_write_contributors() {
+        use crate::tests::{manchester, schools_north_of};
+
+        let loc = manchester();
+        let schools = schools_north_of(&loc, &[(0.0, Some(0.8)), (0.01, Some(0.6)), (0.2, Some(0.4)), (0.02, None)]);
+        let sec_map = HashMap::from([(2019, schools)]);
+        let geo = CGeoData {
+            map: HashMap::from([("M1 1AA".to_owned(), GeoRecord { pcode: "M1 1AA".to_owned(), lat: 53.4794, long: -2.2453 })]),
+        };
+        let config = AtomicConfig { debug_postcodes: HashSet::from(["m11aa".to_owned(), "ZZ9 9ZZ".to_owned()]), ..AtomicConfig::default() };
+
+        let path = std::env::temp_dir().join("scha_test_contributors.csv");
+        let n = write_contributors(&path, &sec_map, &HashMap::new(), &geo, 2019..2020, &config).unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        assert_eq!(reader.headers().unwrap().iter().collect::<Vec<_>>(), ["pcode", "year", "phase", "urn", "dist_km", "weight", "metric"]);
+        let rows: Vec<csv::StringRecord> = reader.records().map(|x| x.unwrap()).collect();
+        // School 2 is ~22km away, outside the weighting distance.
+        assert_eq!(n, 3);
+        assert_eq!(rows.iter().map(|x| &x[3]).collect::<Vec<_>>(), ["0", "1", "3"]);
+        assert!(rows.iter().all(|x| &x[0] == "M1 1AA" && &x[2] == "sec"));
+        assert_eq!(&rows[0][5], "1.0");
+        assert_eq!(&rows[2][6], "");
+
+        // The contributions are exactly what the weighted average is built from.
+        let mut trace = Vec::new();
+        let m = scan_secondary_traced(&loc, None, &sec_map[&2019], &config, None, Some(&mut trace));
+        let mut expected = Scaler::new();
+        trace.iter().for_each(|c| if let Some(x) = c.metric { expected.add(x, c.weight) });
+        assert_eq!(m.weighted_gcseg2, expected.ave());
+        std::fs::remove_file(&path).ok();
+
+        // One line per contributing school, plus the postcode's point.
+        let links = std::env::temp_dir().join("scha_test_contributors.geojson");
+        assert_eq!(debug_links_geojson(&loc, &trace, &links).unwrap(), trace.len());
+        let json: serde_json::Value = serde_json::from_reader(File::open(&links).unwrap()).unwrap();
+        let features = json["features"].as_array().unwrap();
+        assert_eq!(features.iter().filter(|x| x["geometry"]["type"] == "LineString").count(), 3);
+        assert_eq!(features[0]["geometry"]["coordinates"], serde_json::json!([-2.2453, 53.4794]));
+        assert_eq!(features[1]["properties"]["urn"], "0");
+        std::fs::remove_file(&links).ok();
+    }
+
+    #[test]
+    fn test_nearest_schools_for_location() {
+        use crate::tests::{manchester, schools_north_of};
+
+        let loc = manchester();
+        let schools = schools_north_of(&loc, &[(0.001, 0.8), (0.011, 0.6), (0.031, 0.4)]);
+
+        let ctx = nearest_schools_for_location(loc.clone(), &schools, &[], MAX_DIST);
+        assert_eq!(ctx.sec.closest.as_ref().map(|x| x.urn.as_str()), Some("0"));
+        assert!(ctx.prim.closest.is_none());
+
+        // The same location through the pipeline.
+        let columns = ColumnSet::Custom(["closest_sec_urn", "closest_sec_dist", "weighted_sec_gcseg2", "best_sec_gcseg2"].map(String::from).to_vec());
+        let rows = run_aggregate(&AtomicConfig { columns, ..AtomicConfig::default() }, schools, Vec::new());
+        assert_eq!(rows.len(), 2);
+        let field = |i: usize| rows[1][i].parse::<f32>().ok();
+        assert_eq!(&rows[1][0], "0");
+        assert_eq!(field(1), ctx.sec.closest_dist.map(|x| x as f32));
+        assert_eq!(field(2), ctx.sec.weighted_gcseg2);
+        assert_eq!(field(3), ctx.sec.best_gcseg2);
+    }
+
+    #[test]
+    fn test_phase_set() {
+        use crate::tests::{aggregate_school, as_primary};
+
+        let sec = vec![aggregate_school()];
+        let prim = vec![as_primary(&aggregate_school())];
+        let columns = ColumnSet::Custom(["closest_sec_urn", "sec_est_year", "closest_prim_urn", "prim_est_year", "closest_prim_dist", "weighted_prim_rwm_ta"].map(String::from).to_vec());
+        let run = |phases: PhaseSet| {
+            let rows = run_aggregate(&AtomicConfig { columns: columns.clone(), phases, ..AtomicConfig::default() }, sec.clone(), prim.clone());
+            rows[1].iter().map(str::to_owned).collect::<Vec<String>>()
+        };
+
+        let both = run(PhaseSet::Both);
+        assert!(both.iter().all(|x| !x.is_empty()), "{:?}", both);
+
+        // The primary data is there, but secondary-only leaves every primary column empty.
+        let sec_only = run(PhaseSet::Secondary);
+        assert_eq!(sec_only[..2], both[..2]);
+        assert!(sec_only[2..].iter().all(|x| x.is_empty()), "{:?}", sec_only);
+
+        let prim_only = run(PhaseSet::Primary);
+        assert!(prim_only[..2].iter().all(|x| x.is_empty()));
+        assert_eq!(prim_only[2..], both[2..]);
+
+        assert_eq!(PhaseSet::Both.names(), ["sec", "prim"]);
+        assert_eq!(PhaseSet::Secondary.names(), ["sec"]);
+    }
+
+    #[test]
+    fn test_outlier_bounds() {
+        let lad = Some("Manchester".to_owned());
+        let mut records = Vec::new();
+        for (i, price) in [200000.0, 210000.0, 190000.0, 205000.0, 195000.0, 2500000.0].iter().enumerate() {
+            let mut r = pcode_record(&format!("{{{}}}", i), 2019, *price, "M1 1AA");
+            r.priceper = Some(price / 100.0);
+            r.numberrooms = Some("4".to_owned());
+            records.push((r, lad.clone()));
+        }
+        // A different LAD where the same price is normal.
+        let mut r = pcode_record("{X}", 2019, 2500000.0, "SW1A 1AA");
+        r.priceper = Some(25000.0);
+        records.push((r, Some("Westminster".to_owned())));
+        let pcodes = HashMap::from([("M1 1AA".to_owned(), records)]);
+
+        let bounds = OutlierBounds::from_postcodes(&pcodes, 0.0, 80.0);
+        let flagged: Vec<bool> = pcodes["M1 1AA"]
+            .iter()
+            .map(|(r, lad)| {
+                let rooms = r.numberrooms.as_deref().and_then(parse_rooms);
+                bounds.is_outlier(lad.as_deref(), r.year, r.priceper, price_per_room(r.price, rooms))
+            })
+            .collect();
+        assert_eq!(flagged, [false, false, false, false, false, true, false]);
+
+        assert_eq!(price_per_room(200000.0, Some(4)), Some(50000.0));
+        assert_eq!(price_per_room(200000.0, Some(0)), None);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0, 4.0], 50.0), Some(2.5));
+        assert_eq!(percentile(&[], 50.0), None);
+        // Unknown LAD-years are never flagged.
+        assert!(!bounds.is_outlier(Some("Leeds"), 2019, Some(1e9), None));
+    }
+
+    #[test]
+    fn test_outlier_percentiles() {
+        let dir = std::env::temp_dir().join("scha_test_outlier_percentiles");
+        let (_, mut inputs) = sales_fixture(&dir);
+        let pdata = dir.join("rooms.csv");
+        std::fs::write(&pdata, "id,year,propertytype,duration,priceper,price,postcode,tfarea,numberrooms,classt,CONSTRUCTION_AGE_BAND\n\
+            {A},2019,D,F,2000,200000,M1 1AA,,4,,\n\
+            {B},2019,D,F,2100,210000,M1 1AA,,4,,\n\
+            {C},2019,D,F,1900,190000,M1 1AA,,4,,\n\
+            {D},2019,D,F,2050,205000,M1 1AA,,4,,\n\
+            {E},2019,D,F,25000,2500000,M1 1AA,,4,,\n").unwrap();
+        let mut run = |config: AtomicConfig| {
+            let paths = sales_paths(&dir, &pdata, "outliers");
+            std::fs::remove_file(&paths.checkpoint).ok();
+            process_sales(&paths, &mut inputs, 2019..2020, &config).unwrap();
+            let mut rdr = csv::Reader::from_path(&paths.output).unwrap();
+            let headers = rdr.headers().unwrap().clone();
+            let col = |name: &str| headers.iter().position(|x| x == name).unwrap();
+            let (id, outlier) = (col("id"), col("outlier"));
+            let mut flagged: Vec<String> = rdr.records().map(|x| x.unwrap()).filter(|x| &x[outlier] == "true").map(|x| x[id].to_owned()).collect();
+            flagged.sort();
+            flagged
+        };
+
+        // Only flagged when asked for.
+        assert!(run(AtomicConfig::default()).is_empty());
+        assert_eq!(run(AtomicConfig { outlier_percentiles: Some((0.0, 80.0)), ..AtomicConfig::default() }), ["{E}"]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_best_tie_break() {
+        use crate::tests::aggregate_school;
+
+        // Schools 7 and 3 share the top gcseg2; 7 has the better Ofsted grade.
+        let school = |urn: &str, g: f32, dis: f32, of_overall: u32| AggregateSchoolRecord {
+            urn: urn.to_owned(), gcseg2: Some(g), gcseg2_dis: Some(dis), of_overall: Some(of_overall), ..aggregate_school()
+        };
+        let schools = vec![school("3", 0.8, 0.5, 2), school("7", 0.8, 0.3, 1), school("5", 0.6, 0.9, 1)];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        let m = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!((m.best_gcseg2, m.best_gcseg2_dis), (Some(0.8), Some(0.3)));
+
+        // The order of the school list doesn't matter.
+        let reversed: Vec<_> = schools.iter().rev().cloned().collect();
+        let m = scan_secondary(&loc, None, &reversed, &AtomicConfig::default());
+        assert_eq!(m.best_gcseg2_dis, Some(0.3));
+
+        // Equal grades fall back to the lower URN.
+        let same_grade = vec![school("7", 0.8, 0.3, 2), school("3", 0.8, 0.5, 2)];
+        assert_eq!(scan_secondary(&loc, None, &same_grade, &AtomicConfig::default()).best_gcseg2_dis, Some(0.5));
+
+        let config = AtomicConfig { best_tie_break: TieBreak::AverageDis, ..AtomicConfig::default() };
+        let m = scan_secondary(&loc, None, &schools, &config);
+        assert_eq!(m.best_gcseg2, Some(0.8));
+        assert!((m.best_gcseg2_dis.unwrap() - 0.4).abs() < 1e-6);
+
+        assert_eq!(best_with_ties(&[(None, Some(0.5), None, "1")], TieBreak::Ofsted), (None, None));
+    }
+
+    #[test]
+    fn test_stale_decay() {
+        use crate::tests::{manchester, schools_north_of};
+
+        let loc = manchester();
+        let schools = schools_north_of(&loc, &[(0.0, 0.8), (0.01, 0.6)]);
+        let config = AtomicConfig { stale_decay: Some(0.5), ..AtomicConfig::default() };
+
+        let scan = |config: &AtomicConfig, schools: &[AggregateSchoolRecord], sale_year: u32| {
+            let mut trace = Vec::new();
+            let m = scan_secondary_traced(&loc, None, schools, config, Some(sale_year), Some(&mut trace));
+            (m, trace.iter().map(|x| x.weight).collect::<Vec<f32>>())
+        };
+        let (exact, exact_w) = scan(&config, &schools, 2019);
+        let (one, one_w) = scan(&config, &schools, 2020);
+        let (three, three_w) = scan(&config, &schools, 2022);
+
+        for ((e, a), b) in exact_w.iter().zip(one_w.iter()).zip(three_w.iter()) {
+            assert!((a - e * 0.5).abs() < 1e-6);
+            assert!((b - a * 0.25).abs() < 1e-6);
+        }
+        // Equally stale schools keep the same weighted average.
+        assert!((one.weighted_gcseg2.unwrap() - three.weighted_gcseg2.unwrap()).abs() < 1e-6);
+        assert!((exact.weighted_gcseg2.unwrap() - three.weighted_gcseg2.unwrap()).abs() < 1e-6);
+
+        // No decay by default.
+        assert_eq!(scan(&AtomicConfig::default(), &schools, 2022).1, exact_w);
+
+        // Mixed with a fresh school, the stale one's share of the average drops with its age.
+        let mixed = [schools[0].clone(), AggregateSchoolRecord { year: 2017, ..schools[1].clone() }];
+        let (undecayed, _) = scan(&AtomicConfig::default(), &mixed, 2019);
+        let (decayed, decayed_w) = scan(&config, &mixed, 2019);
+        assert!((decayed_w[1] - exact_w[1] * 0.25).abs() < 1e-6);
+        let expected = (0.8 * exact_w[0] + 0.6 * decayed_w[1]) / (exact_w[0] + decayed_w[1]);
+        assert!((decayed.weighted_gcseg2.unwrap() - expected).abs() < 1e-6);
+        assert!(decayed.weighted_gcseg2.unwrap() > undecayed.weighted_gcseg2.unwrap() + 0.01);
+    }
+
+    #[test]
+    fn test_join_chunks() {
+        let chunk = |written: usize| AggregateCounts { written, ..AggregateCounts::default() };
+        let handles = vec![
+            std::thread::spawn(move || Ok(chunk(3))),
+            std::thread::spawn(|| Err("bad chunk".to_owned())),
+            std::thread::spawn(|| panic!("injected failure")),
+            std::thread::spawn(move || Ok(chunk(4))),
+        ];
+        let (counts, failed) = join_chunks(handles);
+        assert_eq!(counts.written, 7);
+        assert_eq!(failed, 2);
+
+        let (counts, failed) = join_chunks(vec![std::thread::spawn(move || Ok(chunk(1)))]);
+        assert_eq!((counts.written, failed), (1, 0));
+    }
+
+    #[test]
+    fn test_effective_distance() {
+        let a = Vector2::new(0.0, 0.0);
+        let b = Vector2::new(4.0, 0.0);
+        assert_eq!(effective_distance(a, b, &[]), 4.0);
+
+        // A river running north-south between them, reaching further south than north.
+        let river = Barrier { a: Vector2::new(2.0, -3.0), b: Vector2::new(2.0, 1.5) };
+        let d = effective_distance(a, b, &[river]);
+        assert!((d - 2.0 * 2.5).abs() < 1e-9, "{}", d);
+
+        // Barriers the straight line doesn't cross, or only touches, change nothing.
+        let beside = Barrier { a: Vector2::new(2.0, 1.0), b: Vector2::new(2.0, 3.0) };
+        let touching = Barrier { a: Vector2::new(2.0, 0.0), b: Vector2::new(2.0, 3.0) };
+        assert_eq!(effective_distance(a, b, &[beside, touching]), 4.0);
+
+        // In a scan, the barrier pushes the school further away.
+        let school = AggregateSchoolRecord { x_km: Some(383.8), y_km: Some(398.1), ..crate::tests::aggregate_school() };
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+        let pos = Some((381.8, 398.1));
+        let plain = scan_secondary(&loc, pos, &[school.clone()], &AtomicConfig::default());
+        let config = AtomicConfig { barriers: vec![Barrier { a: Vector2::new(382.8, 390.0), b: Vector2::new(382.8, 400.0) }], ..AtomicConfig::default() };
+        let blocked = scan_secondary(&loc, pos, &[school], &config);
+        assert!((blocked.closest_dist.unwrap() - (2.0 * 1.0f64.hypot(1.9))).abs() < 1e-3);
+        assert!(blocked.closest_dist > plain.closest_dist);
+    }
+
+    #[test]
+    fn test_include_independent() {
+        use crate::tests::aggregate_school;
+
+        // The independent school is closer and better, but only shows in its own columns.
+        let indep = AggregateSchoolRecord { urn: "1".to_owned(), name: "Private".to_owned(), school_type: "IND".to_owned(), is_state: 0, gcseg2: Some(0.9), ..aggregate_school() };
+        let state = AggregateSchoolRecord { urn: "2".to_owned(), lat: Some(53.4894), ..aggregate_school() };
+        let schools = [indep, state];
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+
+        let without = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert!(without.closest_indep.is_none() && without.closest_indep_dist.is_none());
+
+        let config = AtomicConfig { include_independent: true, ..AtomicConfig::default() };
+        let with = scan_secondary(&loc, None, &schools, &config);
+        assert_eq!(with.closest_indep.as_ref().map(|x| x.name.as_str()), Some("Private"));
+        assert_eq!(with.closest_indep_dist, Some(0.0));
+        assert_eq!(with.closest.as_ref().map(|x| x.urn.as_str()), Some("2"));
+        assert!(with.closest_dist.unwrap() > 1.0);
+        assert_eq!(with.weighted_gcseg2, Some(0.6));
+        assert_eq!(with.best_gcseg2, Some(0.6));
+        assert_eq!((with.closest_dist, with.weighted_gcseg2), (without.closest_dist, without.weighted_gcseg2));
+    }
+
+    #[test]
+    fn test_include_selective() {
+        use crate::tests::aggregate_school;
+
+        // Trafford, which keeps grammar schools. The grammar school is next to the postcode, the comprehensive
+        // about 2 km away.
+        let loc = GeoLocation { latitude: 53.4500, longitude: -2.3500 };
+        let school = |urn: &str, dlat: f64, school_type: &str, is_state: u32, is_selective: u32, g: f32| AggregateSchoolRecord {
+            urn: urn.to_owned(),
+            lad: Some("Trafford".to_owned()),
+            lat: Some(loc.latitude + dlat),
+            lng: Some(loc.longitude),
+            x_km: None,
+            y_km: None,
+            school_type: school_type.to_owned(),
+            is_state,
+            is_selective,
+            gcseg2: Some(g),
+            ..aggregate_school()
+        };
+        let schools = vec![
+            school("1", 0.0, "AC", 0, 1, 0.95),
+            school("2", 0.018, "CY", 1, 0, 0.5),
+            // A selective independent school never counts.
+            school("3", 0.001, "IND", 0, 1, 0.99),
+        ];
+        assert!(is_grammar(&schools[0]) && !is_grammar(&schools[1]) && !is_grammar(&schools[2]));
+
+        let without = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(without.closest.as_ref().map(|x| x.urn.as_str()), Some("2"));
+        assert_eq!(without.weighted_gcseg2, Some(0.5));
+
+        let config = AtomicConfig { include_selective: true, ..AtomicConfig::default() };
+        let mut trace = Vec::new();
+        let with = scan_secondary_traced(&loc, None, &schools, &config, None, Some(&mut trace));
+        assert_eq!(with.closest.as_ref().map(|x| x.urn.as_str()), Some("1"));
+        assert_eq!(with.best_gcseg2, Some(0.95));
+        assert_eq!(trace.iter().map(|x| x.urn.as_str()).collect::<Vec<_>>(), ["1", "2"]);
+        // The grammar school is closer, so it carries more of the weight.
+        let w = with.weighted_gcseg2.unwrap();
+        assert!(w > 0.725 && w < 0.95, "{}", w);
+    }
+
+    #[test]
+    fn test_excl_nearest() {
+        use crate::tests::{manchester, schools_north_of};
+
+        // Roughly 0, 1.1 and 2.2 km north of the postcode.
+        let loc = manchester();
+        let schools = schools_north_of(&loc, &[(0.0, 0.9), (0.01, 0.6), (0.02, 0.3)]);
+
+        assert_eq!(scan_secondary(&loc, None, &schools, &AtomicConfig::default()).weighted_excl_nearest, [None; 6]);
+
+        let config = AtomicConfig { excl_nearest: true, ..AtomicConfig::default() };
+        let mut trace = Vec::new();
+        let m = scan_secondary_traced(&loc, None, &schools, &config, None, Some(&mut trace));
+        assert_eq!(m.closest.as_ref().map(|x| x.urn.as_str()), Some("0"));
+
+        // The same weights as the full mean, less the closest school's.
+        let mut expected = Scaler::new();
+        trace.iter().filter(|x| x.urn != "0").for_each(|c| expected.add(c.metric.unwrap(), c.weight));
+        assert_eq!(trace.len(), 3);
+        assert_eq!(m.weighted_excl_nearest[4], expected.ave());
+        let excl = m.weighted_excl_nearest[4].unwrap();
+        assert!(excl > 0.3 && excl < 0.6 && excl < m.weighted_gcseg2.unwrap(), "{}", excl);
+        // Every school has the same Ofsted grades, so those are unchanged.
+        assert!((m.weighted_excl_nearest[0].unwrap() - m.weighted_of_overall.unwrap()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compute_weighted() {
+        use crate::tests::{manchester, schools_north_of};
+
+        let loc = manchester();
+        let schools = schools_north_of(&loc, &[(0.0, 0.8), (0.01, 0.6), (0.02, 0.4)]);
+
+        let weighted = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert!(weighted.weighted_gcseg2.is_some());
+        let centroid = weighted.catchment_centroid.as_ref().unwrap();
+        assert!(centroid.latitude > 53.4794 && centroid.latitude < 53.4994);
+
+        let config = AtomicConfig { compute_weighted: false, ..AtomicConfig::default() };
+        let closest = scan_secondary(&loc, None, &schools, &config);
+        assert_eq!(closest.closest.as_ref().map(|x| x.urn.as_str()), Some("0"));
+        assert_eq!(closest.closest_dist, weighted.closest_dist);
+        assert_eq!(closest.weighted_gcseg2, None);
+        assert_eq!(closest.weighted_of_overall, None);
+        assert_eq!(closest.best_gcseg2, None);
+        assert_eq!(closest.of_overall_pct, None);
+        assert!(closest.catchment_centroid.is_none());
+    }
+
+    #[test]
+    fn test_weight_scope() {
+        use crate::tests::{manchester, schools_north_of};
+
+        // Roughly 0, 1.1, 2.2, 3.3 and 8.9 km north of the postcode.
+        let loc = manchester();
+        let schools = schools_north_of(&loc, &[(0.0, 0.8), (0.01, 0.6), (0.02, 0.4), (0.03, 0.2), (0.08, 0.9)]);
+
+        // The default only sees the four within 5 km, closer ones weighted more.
+        let within = scan_secondary(&loc, None, &schools, &AtomicConfig::default());
+        assert_eq!(within.best_gcseg2, Some(0.8));
+        let w = within.weighted_gcseg2.unwrap();
+        assert!(w > 0.5 && w < 0.8, "{}", w);
+
+        // The nearest three, equally weighted.
+        let config = AtomicConfig { weight_scope: WeightScope::NearestK(3), ..AtomicConfig::default() };
+        let nearest = scan_secondary(&loc, None, &schools, &config);
+        assert!((nearest.weighted_gcseg2.unwrap() - 0.6).abs() < 1e-6);
+        assert_eq!(nearest.closest.map(|x| x.urn), Some("0".to_owned()));
+
+        // Unlike a distance cutoff, K can reach the far school.
+        let config = AtomicConfig { weight_scope: WeightScope::NearestK(5), ..AtomicConfig::default() };
+        assert_eq!(scan_secondary(&loc, None, &schools, &config).best_gcseg2, Some(0.9));
+    }
+
+    #[test]
+    fn test_max_dist_boundary() {
+        use crate::tests::aggregate_school;
+
+        let loc = GeoLocation { latitude: 53.4794, longitude: -2.2453 };
+        let at = |dlat: f64| GeoLocation { latitude: loc.latitude + dlat, longitude: loc.longitude };
+        // Bisect for a school due north just inside the cutoff, close enough that its distance rounds to
+        // exactly `MAX_DIST` as an f32.
+        let target = f64::from(MAX_DIST) - 1e-7;
+        let (mut lo, mut hi) = (0.0, 0.1);
+        for _ in 0..100 {
+            let mid = (lo + hi) / 2.0;
+            if haversine_km(&loc, &at(mid)) < target { lo = mid } else { hi = mid }
+        }
+        let dist = haversine_km(&loc, &at(lo));
+        assert!(dist < f64::from(MAX_DIST) && dist as f32 == MAX_DIST, "{}", dist);
+
+        let school = AggregateSchoolRecord { lat: Some(at(lo).latitude), lng: Some(loc.longitude), gcseg2: Some(0.7), ..aggregate_school() };
+        let m = scan_secondary(&loc, None, &[school.clone()], &AtomicConfig::default());
+        // Inside by the f64 distance, so weighted, and the closest distance agrees with it.
+        assert_eq!(m.weighted_gcseg2, Some(0.7));
+        assert!(m.closest_dist.unwrap() < f64::from(MAX_DIST));
+
+        let mut trace = Vec::new();
+        scan_secondary_traced(&loc, None, &[school], &AtomicConfig::default(), None, Some(&mut trace));
+        assert_eq!(trace.len(), 1);
+        assert!(trace[0].weight > 0.0);
+
+        // Just outside is left out.
+        let outside = AggregateSchoolRecord { lat: Some(at(hi * 1.001).latitude), lng: Some(loc.longitude), gcseg2: Some(0.7), ..aggregate_school() };
+        let m = scan_secondary(&loc, None, &[outside], &AtomicConfig::default());
+        assert_eq!(m.weighted_gcseg2, None);
+        assert!(m.closest_dist.unwrap() > f64::from(MAX_DIST));
+    }
+
+    #[test]
+    fn test_diff_outputs() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/diff_outputs");
+        let (a, b) = (fixtures.join("a.csv"), fixtures.join("b.csv"));
+
+        assert!(diff_outputs(&a, &a, &["id"]).unwrap().is_empty());
+
+        let report = diff_outputs(&a, &b, &["id"]).unwrap();
+        assert!(report.added.is_empty() && report.removed.is_empty());
+        assert!(report.added_columns.is_empty() && report.removed_columns.is_empty());
+        assert_eq!(
+            report.changed,
+            vec![(
+                vec!["{A3}".to_owned()],
+                vec![ColumnChange { column: "closest_sec_gcseg2".to_owned(), old: "0.55".to_owned(), new: "0.7".to_owned() }]
+            )]
+        );
+
+        // Keyed by year as well, the changed row is still matched.
+        assert_eq!(diff_outputs(&a, &b, &["year", "id"]).unwrap().changed.len(), 1);
+        assert!(matches!(diff_outputs(&a, &b, &["missing"]), Err(ScaError::Missing(_))));
+    }
+}
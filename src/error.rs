@@ -0,0 +1,44 @@
+use thiserror::Error;
+
+/// Errors returned by the top-level runs and the data loaders.
+#[derive(Debug, Error)]
+pub enum ScaError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("csv error: {0}")]
+    Csv(csv::Error),
+    #[error("projection error: {0}")]
+    Proj(String),
+    #[error("parse error: {0}")]
+    Parse(String),
+    #[error("missing {0}")]
+    Missing(String),
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+impl From<csv::Error> for ScaError {
+    /// Failing to open or read a file is an `Io` error even when it comes through the csv reader.
+    fn from(e: csv::Error) -> Self {
+        if !e.is_io_error() {
+            return Self::Csv(e);
+        }
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => Self::Io(e),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<proj::ProjCreateError> for ScaError {
+    fn from(e: proj::ProjCreateError) -> Self {
+        Self::Proj(e.to_string())
+    }
+}
+
+impl From<proj::ProjError> for ScaError {
+    fn from(e: proj::ProjError) -> Self {
+        Self::Proj(e.to_string())
+    }
+}
@@ -3,7 +3,8 @@ use rayon::prelude::*;
 use std::f64::consts::PI;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use crate::assign::Circle;
+use crate::assign::{BBox, Circle};
+use crate::rng::Rng;
 
 /// A hashable key for points, based on their bit representation.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -38,54 +39,36 @@ pub fn overlap(circle: Circle, others: &[Circle], samples: usize) -> f64 {
         return 0.0;
     }
 
-    let (cx, cy) = (circle.origin.x, circle.origin.y);
-    let r = circle.r;
-    let min_x = cx - r;
-    let max_x = cx + r;
-    let min_y = cy - r;
-    let max_y = cy + r;
+    let r = Vector2::new(circle.r, circle.r);
+    let bbox = BBox { min: circle.origin - r, max: circle.origin + r };
+    sample_grid(bbox, samples, samples, |p| circle.contains(&p) && others.iter().any(|c| c.contains(&p)))
+}
 
-    if min_x >= max_x || min_y >= max_y {
+/// Centre of cell `idx` of an `nx` x `ny` grid over `bbox`, numbering cells along x first.
+pub fn grid_centre(bbox: &BBox, nx: usize, ny: usize, idx: usize) -> Vector2<f64> {
+    let cell = bbox.max - bbox.min;
+    bbox.min + Vector2::new(cell.x * ((idx % nx) as f64 + 0.5) / nx as f64, cell.y * ((idx / nx) as f64 + 0.5) / ny as f64)
+}
+
+/// Estimates the area of the part of `bbox` where `predicate` holds by testing the centre of each cell of
+/// an `nx` x `ny` grid. The error comes from cells straddling the boundary, so it shrinks roughly as
+/// 1/resolution.
+pub fn sample_grid(bbox: BBox, nx: usize, ny: usize, predicate: impl Fn(Vector2<f64>) -> bool + Sync) -> f64 {
+    if nx == 0 || ny == 0 {
         return 0.0;
     }
-
-    // Increase resolution for better accuracy
-    let samples_per_dimension = samples;
-    let dx = (max_x - min_x) / (samples_per_dimension as f64);
-    let dy = (max_y - min_y) / (samples_per_dimension as f64);
-    let cell_area = dx * dy;
-
-    // We'll create an iterator over all sample points using indices:
-    let total_samples = samples_per_dimension * samples_per_dimension;
-
-    // Collect the sum of areas in parallel:
-    let sum_area = (0..total_samples).into_par_iter().map(|idx| {
-        // Convert idx -> (i, j)
-        let i = idx / samples_per_dimension;
-        let j = idx % samples_per_dimension;
-
-        let x = min_x + (i as f64 + 0.5) * dx;
-        let y = min_y + (j as f64 + 0.5) * dy;
-
-        // Check if inside main circle
-        let dist_sq_main = (x - cx) * (x - cx) + (y - cy) * (y - cy);
-        if dist_sq_main <= r * r {
-            // Check if inside at least one other circle
-            for c in others {
-                let dx_o = x - c.origin.x;
-                let dy_o = y - c.origin.y;
-                if dx_o*dx_o + dy_o*dy_o <= c.r * c.r {
-                    return cell_area; // This sample is in overlap
-                }
-            }
-        }
-        0.0
-    }).sum::<f64>();
-
-    sum_area
+    let inside = (0..nx * ny).into_par_iter().filter(|&idx| predicate(grid_centre(&bbox, nx, ny, idx))).count();
+    bbox.area() * inside as f64 / (nx * ny) as f64
 }
 
 pub fn intersect_all_approx(circles: &[Circle]) -> f64 {
+    intersect_all_approx_with(circles, 500)
+}
+
+/// Estimates the intersection area of all circles by sampling a `samples` x `samples` grid over the
+/// intersection of their bounding boxes. The error comes from cells straddling the boundary, so it
+/// shrinks roughly as 1/samples.
+pub fn intersect_all_approx_with(circles: &[Circle], samples: usize) -> f64 {
     // Handle trivial cases
     if circles.is_empty() {
         return 0.0;
@@ -129,43 +112,12 @@ pub fn intersect_all_approx(circles: &[Circle]) -> f64 {
         return 0.0;
     }
 
-    // Approximate using sampling
-    // Increase these values for higher accuracy but slower computation
-    let samples_per_dimension = 500;
-    let dx = (max_x - min_x) / (samples_per_dimension as f64);
-    let dy = (max_y - min_y) / (samples_per_dimension as f64);
-
-    let mut inside_count = 0;
-    let total_samples = samples_per_dimension * samples_per_dimension;
-
-    for i in 0..samples_per_dimension {
-        for j in 0..samples_per_dimension {
-            let x = min_x + (i as f64 + 0.5) * dx; // center of each cell
-            let y = min_y + (j as f64 + 0.5) * dy;
-
-            // Check if (x, y) is inside all circles
-            let mut inside_all = true;
-            for c in circles {
-                let (cx, cy) = (c.origin.x, c.origin.y);
-                let r = c.r;
-                let dist_sq = (x - cx) * (x - cx) + (y - cy) * (y - cy);
-                if dist_sq > r * r {
-                    inside_all = false;
-                    break;
-                }
-            }
-
-            if inside_all {
-                inside_count += 1;
-            }
-        }
-    }
-
-    let bounding_box_area = (max_x - min_x) * (max_y - min_y);
-    let fraction = inside_count as f64 / total_samples as f64;
-    fraction * bounding_box_area
+    let bbox = BBox { min: Vector2::new(min_x, min_y), max: Vector2::new(max_x, max_y) };
+    sample_grid(bbox, samples, samples, |p| circles.iter().all(|c| c.contains(&p)))
 }
 
+/// The area shared by all of `circles`. Sets with any disjoint pair are rejected by
+/// `has_common_intersection` before any intersection points are built.
 pub fn intersect_all(circles: &[Circle]) -> f64 {
     match circles.len() {
         0 => 0.0,
@@ -174,6 +126,60 @@ pub fn intersect_all(circles: &[Circle]) -> f64 {
     }
 }
 
+/// Computes the intersection of `circles` both exactly and by sampling, returning `(exact, approx, rel_err)`
+/// where `rel_err` is their difference relative to the larger of the two (zero if both are empty).
+pub fn compare_intersection_methods(circles: &[Circle], samples: usize) -> (f64, f64, f64) {
+    let exact = intersect_all(circles);
+    let approx = intersect_all_approx_with(circles, samples);
+    let scale = exact.abs().max(approx.abs());
+    let rel_err = if scale > 0.0 { (exact - approx).abs() / scale } else { 0.0 };
+    (exact, approx, rel_err)
+}
+
+/// `n` random circles, centred in the unit square with radii in [0.3, 1.0) so that they usually all
+/// overlap. The same seed always gives the same circles.
+pub fn random_circles(n: usize, seed: u64) -> Vec<Circle> {
+    let mut rng = Rng::new(seed);
+    (0..n)
+        .map(|_| Circle { origin: Vector2::new(rng.next_f64(), rng.next_f64()), r: rng.range(0.3, 1.0) })
+        .collect()
+}
+
+/// Whether any pair of circles is (nearly) tangent, where both methods are ill-conditioned.
+fn near_tangent(circles: &[Circle]) -> bool {
+    circles.iter().enumerate().any(|(i, a)| circles[i+1..].iter().any(|b| {
+        let d = (a.origin - b.origin).norm();
+        (d - (a.r + b.r)).abs() < 1e-2 || (d - (a.r - b.r).abs()).abs() < 1e-2
+    }))
+}
+
+/// Runs `n_trials` random sets of `n_circles` circles through both `intersect_all` and the sampled
+/// approximation, printing every set where they disagree by more than 2% and panicking if any do.
+/// Nearly tangent sets and tiny slivers are skipped, since the sampling can't resolve them. Returns
+/// the number of sets that were compared.
+pub fn fuzz_intersection(n_circles: usize, n_trials: usize, seed: u64) -> usize {
+    let mut checked = 0;
+    let mut failed = 0;
+    for trial in 0..n_trials {
+        let circles = random_circles(n_circles, seed.wrapping_add(trial as u64));
+        if near_tangent(&circles) {
+            continue;
+        }
+
+        let (exact, approx, rel_err) = compare_intersection_methods(&circles, 500);
+        if exact.max(approx) < 1e-2 {
+            continue;
+        }
+        checked += 1;
+        if rel_err >= 0.02 {
+            println!("exact {} vs approx {} ({}) for {:?}", exact, approx, rel_err, circles);
+            failed += 1;
+        }
+    }
+    assert_eq!(failed, 0, "{} of {} circle sets disagree", failed, checked);
+    checked
+}
+
 /// Computes the intersection area of multiple circles using geometric decomposition.
 /// Steps:
 /// 1. Find pairwise intersection points of all circles.
@@ -263,46 +269,53 @@ fn intersection_of_many_circles(circles: &[Circle]) -> f64 {
     // Build adjacency
     let mut adjacency: HashMap<PointKey, Vec<(PointKey, usize)>> = HashMap::new();
     let mut unique_points_map: HashMap<PointKey, Vector2<f64>> = HashMap::new();
-    for (ci, p1, p2) in &boundary_arcs {
+    for (ai, (_, p1, p2)) in boundary_arcs.iter().enumerate() {
         let p1_key = PointKey::from(*p1);
         let p2_key = PointKey::from(*p2);
 
-        adjacency.entry(p1_key).or_default().push((p2_key, *ci));
-        adjacency.entry(p2_key).or_default().push((p1_key, *ci));
+        adjacency.entry(p1_key).or_default().push((p2_key, ai));
+        adjacency.entry(p2_key).or_default().push((p1_key, ai));
         unique_points_map.insert(p1_key, *p1);
         unique_points_map.insert(p2_key, *p2);
     }
 
     let start = *unique_points_map.values().next().unwrap();
-    let polygon_points = build_boundary_polygon(&adjacency, &unique_points_map, start);
+    let (polygon_points, closed) = build_boundary_polygon(&adjacency, &unique_points_map, start);
+    if !closed {
+        // The arcs don't form a single loop, so fall back to sampling.
+        return intersect_all_approx(circles);
+    }
 
     let polygon_area = polygon_area(&polygon_points);
 
     let mut arc_area_sum = 0.0;
+    // Two vertices are joined by an arc of each circle, so each arc must only be used once.
+    let mut used = vec![false; boundary_arcs.len()];
     for i in 0..polygon_points.len() {
         let p1 = polygon_points[i];
         let p2 = polygon_points[(i+1)%polygon_points.len()];
 
-        // find arc circle
+        // find arc
         let p1_key = PointKey::from(p1);
         let p2_key = PointKey::from(p2);
-        let circles_for_edge = &adjacency[&p1_key];
-        let mut arc_circle = None;
-        for (candidate, ci) in circles_for_edge {
-            if *candidate == p2_key {
-                arc_circle = Some(*ci);
-                break;
-            }
-        }
-        let ci = arc_circle.expect("No circle found for arc - invalid geometry");
-        let c = circles[ci];
-        arc_area_sum += arc_segment_area(c, p1, p2);
+        let ai = adjacency[&p1_key]
+            .iter()
+            .find(|(candidate, ai)| *candidate == p2_key && !used[*ai])
+            .map(|(_, ai)| *ai)
+            .expect("No circle found for arc - invalid geometry");
+        used[ai] = true;
+
+        let (ci, a, b) = boundary_arcs[ai];
+        arc_area_sum += arc_segment_area(circles[ci], a, b);
     }
 
     polygon_area + arc_area_sum
 }
 
-/// Check if all circles overlap in some region quickly by comparing bounding boxes
+/// Cheap pre-filter for `intersection_of_many_circles`: false means the circles certainly share no area,
+/// true only that they might. Rejects when the bounding boxes have no common overlap or any pair of circles
+/// is disjoint. Circles that overlap pairwise can still have no common point (three around a triangle);
+/// those are left to the point-inside checks.
 fn has_common_intersection(circles: &[Circle]) -> bool {
     let mut min_x = f64::NEG_INFINITY;
     let mut min_y = f64::NEG_INFINITY;
@@ -324,6 +337,15 @@ fn has_common_intersection(circles: &[Circle]) -> bool {
             return false;
         }
     }
+
+    // Overlapping boxes don't mean overlapping circles, e.g. two circles touching diagonally.
+    for (i, a) in circles.iter().enumerate() {
+        for b in &circles[i + 1..] {
+            if distance(a.origin, b.origin) >= a.r + b.r {
+                return false;
+            }
+        }
+    }
     true
 }
 
@@ -387,59 +409,53 @@ fn mid_angle_for_circle_arc(c: &Circle, p1: Vector2<f64>, p2: Vector2<f64>) -> f
 }
 
 /// Build boundary polygon from adjacency map by following arcs in order.
-/// We assume it forms a single closed loop.
+/// We assume it forms a single closed loop. Each arc is followed at most once, so malformed adjacency
+/// (e.g. a point with three arcs) can't make it spin. Returns the polygon and whether it closed back
+/// at `start`; if not, the polygon is only the part walked before getting stuck.
 fn build_boundary_polygon(
     adjacency: &std::collections::HashMap<PointKey, Vec<(PointKey, usize)>>,
     unique_points_map: &std::collections::HashMap<PointKey, Vector2<f64>>,
     start: Vector2<f64>
-) -> Vec<Vector2<f64>> {
+) -> (Vec<Vector2<f64>>, bool) {
     let mut polygon = Vec::new();
     polygon.push(start);
     let mut current = start;
-    let mut prev = PointKey { x_bits: u64::MAX, y_bits: u64::MAX };
 
-    loop {
+    let n_arcs = adjacency.values().flatten().map(|(_, ai)| *ai + 1).max().unwrap_or(0);
+    let mut visited = vec![false; n_arcs];
+
+    // Every step uses up an arc, so this is never hit unless the visited set is bypassed.
+    for _ in 0..=n_arcs {
         let current_key = PointKey::from(current);
-        let neighbors = &adjacency[&current_key];
-
-        let maybe_next_key = if polygon.len() == 1 {
-            // Just take the first neighbor
-            Some(neighbors[0].0)
-        } else {
-            // Filter out the previous point to move forward
-            let pprev = prev;
-            neighbors.iter()
-                .map(|x| x.0)
-                .filter(|&x| x != pprev)
-                .next()
+        let neighbors = match adjacency.get(&current_key) {
+            Some(x) => x,
+            None => break,
         };
 
-        let next_key = match maybe_next_key {
-            Some(nk) => nk,
+        let next = neighbors.iter().find(|(_, ai)| !visited[*ai]);
+        let (next_key, ai) = match next {
+            Some(x) => *x,
             None => {
-                // Handle the case when no next key is found.
-                // This might indicate a degenerate polygon or unexpected geometry.
-                // Decide what to do: return the polygon as is, or return an empty vector, etc.
-                // For now, let's break out, returning the polygon as constructed so far.
+                // Dead end - degenerate or unexpected geometry.
                 break;
             }
         };
+        visited[ai] = true;
 
         let next_v = unique_points_map[&next_key];
         if (next_v - start).norm() < 1e-14 && polygon.len() > 1 {
             // closed loop found
-            break;
+            return (polygon, true);
         }
         polygon.push(next_v);
-        prev = PointKey::from(current);
         current = next_v;
     }
 
-    polygon
+    (polygon, false)
 }
 
 /// Compute polygon area using shoelace formula
-fn polygon_area(points: &[Vector2<f64>]) -> f64 {
+pub fn polygon_area(points: &[Vector2<f64>]) -> f64 {
     let n = points.len();
     let mut area = 0.0;
     for i in 0..n {
@@ -449,7 +465,8 @@ fn polygon_area(points: &[Vector2<f64>]) -> f64 {
     area.abs()*0.5
 }
 
-/// Compute the arc segment area for arc on circle c from p1 to p2.
+/// Compute the area between the chord and the counterclockwise arc on circle c from p1 to p2.
+/// Arcs over half the circle give the major segment.
 fn arc_segment_area(c: Circle, p1: Vector2<f64>, p2: Vector2<f64>) -> f64 {
     let v1 = p1 - c.origin;
     let v2 = p2 - c.origin;
@@ -458,10 +475,6 @@ fn arc_segment_area(c: Circle, p1: Vector2<f64>, p2: Vector2<f64>) -> f64 {
     let mut dtheta = ang2 - ang1;
     if dtheta < 0.0 { dtheta += 2.0*PI; }
 
-    if dtheta > PI {
-        dtheta = 2.0*PI - dtheta;
-    }
-
     let r = c.r;
     let segment_area = (r*r/2.0)*(dtheta - dtheta.sin());
 
@@ -471,6 +484,65 @@ fn arc_segment_area(c: Circle, p1: Vector2<f64>, p2: Vector2<f64>) -> f64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_common_intersection_prefilter() {
+        let circle = |x: f64, y: f64, r: f64| Circle { origin: Vector2::new(x, y), r };
+
+        // Boxes overlap at the corner, but the circles are apart.
+        let diagonal = [circle(0.0, 0.0, 1.0), circle(1.5, 1.5, 1.0)];
+        assert!(!has_common_intersection(&diagonal));
+        assert_eq!(intersect_all(&diagonal), 0.0);
+
+        // Overlapping pairwise around a triangle, but the centroid is ~1.155 from each centre.
+        let centres = [(0.0, 0.0), (2.0, 0.0), (1.0, 3f64.sqrt())];
+        let triangle = centres.map(|(x, y)| circle(x, y, 1.1));
+        assert!(has_common_intersection(&triangle));
+        assert_eq!(intersect_all(&triangle), 0.0);
+
+        // Slightly larger and they do share a small region.
+        let triangle = centres.map(|(x, y)| circle(x, y, 1.2));
+        assert!(intersect_all(&triangle) > 0.0);
+
+        // One disjoint pair among heavily overlapping circles. Their boxes overlap, so only the distance
+        // check rejects them.
+        let mut many: Vec<Circle> = (0..8).map(|i| circle(0.1 * i as f64, 0.0, 3.0)).collect();
+        assert!(intersect_all(&many) > 0.0);
+        many.push(circle(-0.5, -0.5, 0.7));
+        many.push(circle(0.5, 0.5, 0.7));
+        assert!(!has_common_intersection(&many));
+        assert_eq!(intersect_all(&many), 0.0);
+    }
+
+    #[test]
+    fn test_boundary_polygon_branch() {
+        // A tail into a loop that doesn't contain the start: A - B, then B - C - D - B.
+        let pts = [Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0), Vector2::new(2.0, 1.0), Vector2::new(2.0, -1.0)];
+        let keys: Vec<PointKey> = pts.iter().map(|p| PointKey::from(*p)).collect();
+        let arcs = [(0, 1), (1, 2), (2, 3), (3, 1)];
+
+        let mut adjacency: HashMap<PointKey, Vec<(PointKey, usize)>> = HashMap::new();
+        for (ai, (a, b)) in arcs.iter().enumerate() {
+            adjacency.entry(keys[*a]).or_default().push((keys[*b], ai));
+            adjacency.entry(keys[*b]).or_default().push((keys[*a], ai));
+        }
+        let unique_points_map: HashMap<PointKey, Vector2<f64>> = keys.iter().copied().zip(pts).collect();
+
+        let (polygon, closed) = build_boundary_polygon(&adjacency, &unique_points_map, pts[0]);
+        assert!(!closed);
+        assert!(polygon.len() <= arcs.len() + 1);
+
+        // A well formed loop still closes.
+        let square = [(0, 1), (1, 2), (2, 3), (3, 0)];
+        let mut adjacency: HashMap<PointKey, Vec<(PointKey, usize)>> = HashMap::new();
+        for (ai, (a, b)) in square.iter().enumerate() {
+            adjacency.entry(keys[*a]).or_default().push((keys[*b], ai));
+            adjacency.entry(keys[*b]).or_default().push((keys[*a], ai));
+        }
+        let (polygon, closed) = build_boundary_polygon(&adjacency, &unique_points_map, pts[0]);
+        assert!(closed);
+        assert_eq!(polygon.len(), 4);
+    }
     #[test]
     fn test_no_circles() {
         let circles = [];
@@ -502,4 +574,70 @@ mod tests {
         println!("xix:@ {}", area);
         assert!((area - 2.1521).abs() < 0.05);
     }
+
+    #[test]
+    fn test_unequal_circles() {
+        // Lens area of two circles radii r1, r2 with centres d apart.
+        let lens = |r1: f64, r2: f64, d: f64| {
+            r1*r1*((d*d + r1*r1 - r2*r2) / (2.0*d*r1)).acos()
+                + r2*r2*((d*d + r2*r2 - r1*r1) / (2.0*d*r2)).acos()
+                - 0.5*((-d + r1 + r2)*(d + r1 - r2)*(d - r1 + r2)*(d + r1 + r2)).sqrt()
+        };
+
+        let c1 = Circle { origin: Vector2::new(0.0,0.0), r:0.4 };
+        let c2 = Circle { origin: Vector2::new(0.75,0.0), r:0.7 };
+        assert!((intersect_all(&[c1,c2]) - lens(0.4, 0.7, 0.75)).abs() < 1e-9);
+
+        // Most of the small circle is inside the large one, so its boundary arc is the major arc.
+        let c1 = Circle { origin: Vector2::new(0.0,0.0), r:1.0 };
+        let c2 = Circle { origin: Vector2::new(0.8,0.0), r:0.5 };
+        assert!((intersect_all(&[c1,c2]) - lens(1.0, 0.5, 0.8)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sample_grid() {
+        let c = Circle { origin: Vector2::new(1.0, -2.0), r: 1.5 };
+        let bbox = BBox { min: Vector2::new(-1.0, -4.0), max: Vector2::new(3.0, 0.0) };
+
+        let errors: Vec<f64> = [10, 100, 1000].iter()
+            .map(|&n| (sample_grid(bbox, n, n, |p| c.contains(&p)) - c.area()).abs())
+            .collect();
+        assert!(errors[1] < errors[0] && errors[2] < errors[1], "{:?}", errors);
+        assert!(errors[2] < 1e-3 * c.area(), "{:?}", errors);
+
+        // Non-square grids, and the whole box.
+        assert!((sample_grid(bbox, 400, 100, |p| c.contains(&p)) - c.area()).abs() < 0.05);
+        assert_eq!(sample_grid(bbox, 3, 7, |_| true), bbox.area());
+        assert_eq!(sample_grid(bbox, 0, 7, |_| true), 0.0);
+        assert_eq!(grid_centre(&bbox, 4, 4, 5), Vector2::new(0.5, -2.5));
+    }
+
+    #[test]
+    fn test_approx_converges() {
+        let c1 = Circle { origin: Vector2::new(0.0,0.0), r:1.0 };
+        let c2 = Circle { origin: Vector2::new(0.5,0.0), r:1.0 };
+        let exact = intersect_all(&[c1,c2]);
+
+        let errors: Vec<f64> = [10, 100, 1000].iter()
+            .map(|&n| (intersect_all_approx_with(&[c1,c2], n) - exact).abs())
+            .collect();
+        assert!(errors[1] < errors[0] && errors[2] < errors[1], "{:?}", errors);
+        assert!(errors[2] < 1e-3, "{:?}", errors);
+    }
+
+    #[test]
+    fn test_fuzz_intersection() {
+        for n in 2..=5 {
+            let checked = fuzz_intersection(n, 100, 0x5eed + n as u64);
+            assert!(checked > 30, "only {} of 100 cases checked for {} circles", checked, n);
+        }
+        assert_eq!(random_circles(4, 7), random_circles(4, 7));
+        assert_ne!(random_circles(4, 7), random_circles(4, 8));
+    }
+
+    #[test]
+    fn test_compare_intersection_methods() {
+        let checked: usize = (2..=4).map(|n| fuzz_intersection(n, 100, 0x5eed_5c4a + n as u64)).sum();
+        assert!(checked > 100, "only {} cases checked", checked);
+    }
 }
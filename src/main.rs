@@ -1,14 +1,21 @@
 use atomic::run_atomic;
+use error::ScaError;
 use csv::Writer;
-use geo_rust::{Country, GeoLocation};
+use encoding_rs::Encoding;
+use geo_rust::GeoLocation;
 use regex::Regex;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{collections::HashMap, error::Error, io, path::Path, process};
+use std::{collections::HashMap, error::Error, io, path::{Path, PathBuf}, process};
 
 pub mod assign;
 pub mod atomic;
+pub mod error;
 mod intersect;
+pub mod manifest;
 mod render;
+pub mod rng;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 pub const LADs: [&'static str; 34] = [
     "Blackburn with Darwen",
@@ -51,6 +58,9 @@ pub const TARGET_SCHOOL_TYPES: [&'static str; 11] = [
     "AC", "ACC", "AC1619", "ACC1619", "CY", "F1619", "FSS", "F", "FD", "VA", "VC",
 ];
 
+/// Independent school types, tracked apart from the state schools in `TARGET_SCHOOL_TYPES`.
+pub const INDEPENDENT_SCHOOL_TYPES: [&'static str; 2] = ["IND", "INDSPEC"];
+
 pub const CUM_RPI_DEFL: [f32; 7] = [
     1.0,   //2017
     1.036, // 2018 : base * 2017 rpi
@@ -61,6 +71,23 @@ pub const CUM_RPI_DEFL: [f32; 7] = [
     1.293513644,
 ];
 
+/// Base year of `CUM_RPI_DEFL`; prices in this year are already in real terms.
+pub const RPI_BASE_YEAR: u32 = 2017;
+
+/// Cumulative RPI factor for `year` relative to `RPI_BASE_YEAR`, if we have one.
+pub fn rpi_deflator(year: u32) -> Option<f32> {
+    year.checked_sub(RPI_BASE_YEAR)
+        .and_then(|i| CUM_RPI_DEFL.get(i as usize))
+        .copied()
+}
+
+/// Converts a nominal price to 2017 prices by dividing through by the cumulative deflator.
+pub fn real_price(price: f32, rpi_defl: Option<f32>) -> Option<f32> {
+    rpi_defl.map(|d| price / d)
+}
+
+/// Weighted values, serializable so partial accumulations from separate shards can be saved and `merge`d.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Scaler {
     vals: Vec<(f32, f32)>,
 }
@@ -70,12 +97,19 @@ impl Scaler {
         Self { vals: Vec::new() }
     }
 
+    /// Adds `v` with weight `w`. Non-positive weights and non-finite values or weights are ignored, as a
+    /// single NaN would otherwise make the whole average NaN.
     pub fn add(&mut self, v: f32, w: f32) {
-        if w > 0.0 {
+        if w > 0.0 && w.is_finite() && v.is_finite() {
             self.vals.push((v, w));
         }
     }
 
+    /// Adds all of `other`'s values, as if they had been added to this one.
+    pub fn merge(&mut self, other: Scaler) {
+        self.vals.extend(other.vals);
+    }
+
     pub fn ave(&self) -> Option<f32> {
         if self.vals.is_empty() {
             None
@@ -89,6 +123,63 @@ impl Scaler {
             Some(x)
         }
     }
+
+    /// Weighted median of the added values. When the cumulative weight lands exactly on half of the
+    /// total the two straddling values are averaged, so equal weights give the ordinary median.
+    pub fn median(&self) -> Option<f32> {
+        if self.vals.is_empty() {
+            return None;
+        }
+
+        let mut sorted = self.vals.clone();
+        sorted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let half: f32 = sorted.iter().map(|v| v.1).sum::<f32>() / 2.0;
+
+        let mut acc = 0.0;
+        for (i, (v, w)) in sorted.iter().enumerate() {
+            acc += w;
+            if (acc - half).abs() <= f32::EPSILON * half {
+                return Some(sorted.get(i + 1).map_or(*v, |next| (v + next.0) / 2.0));
+            } else if acc > half {
+                return Some(*v);
+            }
+        }
+        sorted.last().map(|x| x.0)
+    }
+}
+
+/// Weighted locations, for placing a marker at the weighted centre of the points that fed a `Scaler`.
+#[derive(Debug, Default, Clone)]
+pub struct GeoScaler {
+    vals: Vec<(f64, f64, f32)>,
+}
+
+impl GeoScaler {
+    pub fn new() -> Self {
+        Self { vals: Vec::new() }
+    }
+
+    pub fn add(&mut self, loc: &GeoLocation, w: f32) {
+        if w > 0.0 {
+            self.vals.push((loc.latitude, loc.longitude, w));
+        }
+    }
+
+    pub fn total_weight(&self) -> f32 {
+        self.vals.iter().map(|v| v.2).sum()
+    }
+
+    /// Weighted mean of the latitudes and longitudes, which is close enough to the true centroid over the
+    /// few km of a catchment.
+    pub fn centroid(&self) -> Option<GeoLocation> {
+        if self.vals.is_empty() {
+            return None;
+        }
+        let sum = self.total_weight() as f64;
+        let (lat, lng) = self.vals.iter().fold((0.0, 0.0), |(lat, lng), (x, y, w)| (lat + x * *w as f64 / sum, lng + y * *w as f64 / sum));
+        Some(GeoLocation { latitude: lat, longitude: lng })
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -135,6 +226,10 @@ impl School for SchoolRecord {
     fn get_pcode(&self) -> &str {
         &self.pcode
     }
+
+    fn get_location(&self) -> Option<GeoLocation> {
+        parse_location(&self.lat, &self.long)
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -175,6 +270,10 @@ impl School for PSchoolRecord {
     fn get_pcode(&self) -> &str {
         &self.pcode
     }
+
+    fn get_location(&self) -> Option<GeoLocation> {
+        parse_location(&self.lat, &self.long)
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -190,7 +289,11 @@ struct RegionPcodeRecord {
 struct SchoolInfo<S: School> {
     record: S,
     ofsted: Option<OfstedRecord>,
+    /// From the postcode.
     lad: Option<String>,
+    /// The Ofsted "Local authority", which can disagree with `lad` after boundary changes.
+    lad_ofsted: Option<String>,
+    location: Option<GeoLocation>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Default)]
@@ -249,7 +352,12 @@ impl AggregateRecord {
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct AggregateSchoolRecord {
     pub year: u32,
+    /// The LAD of the school's postcode.
+    #[serde(rename = "lad_postcode", alias = "lad")]
     pub lad: Option<String>,
+    /// The LAD of the school's Ofsted record.
+    #[serde(default)]
+    pub lad_ofsted: Option<String>,
     pub msoa: String,
     pub name: String,
     pub pcode: String,
@@ -258,6 +366,11 @@ pub struct AggregateSchoolRecord {
     pub x_km: Option<f64>,
     pub y_km: Option<f64>,
     pub radius: Option<f64>,
+    /// Area of the catchment circle, when a radius was solved.
+    #[serde(default)]
+    pub catchment_area_km2: Option<f64>,
+    #[serde(default)]
+    pub catchment_area_ha: Option<f64>,
     pub target_density: Option<f64>,
     pub target_prop: Option<f64>,
     pub pop: Option<u32>,
@@ -276,7 +389,48 @@ pub struct AggregateSchoolRecord {
     pub gcseg2_dis: Option<f32>,
 }
 
+/// Whether a target area can be scaled to: finite and positive.
+fn valid_area(area: Option<f64>) -> bool {
+    area.map_or(false, |x| x.is_finite() && x > 0.0)
+}
+
 impl AggregateSchoolRecord {
+    /// The first reason this school cannot be given a catchment radius, if any.
+    pub fn drop_reason(&self) -> Option<&'static str> {
+        if self.gcseg2.is_none() {
+            Some("missing gcseg2")
+        } else if self.x_km.is_none() || self.y_km.is_none() {
+            Some("missing location")
+        } else if self.target_density.is_none() {
+            Some("missing target_density")
+        } else if self.pop.is_none() {
+            Some("missing pop")
+        } else if self.target_prop.is_none() {
+            Some("missing target_prop")
+        } else if !valid_area(self.target_area()) {
+            Some("non-positive target area")
+        } else if self.is_selective == 1 {
+            Some("selective")
+        } else if self.is_state == 0 {
+            Some("not state")
+        } else {
+            None
+        }
+    }
+
+    /// The catchment area (km^2) needed to reach `pop` pupils at the target density and proportion.
+    pub fn target_area(&self) -> Option<f64> {
+        Some(self.pop? as f64 / (self.target_density? * self.target_prop?))
+    }
+
+    /// The school's target area around its BNG position, the input to scaling.
+    pub fn radial(&self) -> Option<assign::RadialArea> {
+        Some(assign::RadialArea {
+            origin: Vector2::new(self.x_km?, self.y_km?),
+            area: self.target_area()?,
+        })
+    }
+
     #[inline]
     pub fn location(&self) -> Option<GeoLocation> {
         if let (Some(lat), Some(lng)) = (self.lat, self.lng) {
@@ -290,7 +444,89 @@ impl AggregateSchoolRecord {
     }
 }
 
+/// The geographic CRS, whose coordinates live in `lng`/`lat` rather than `x_km`/`y_km`.
+const WGS84: &str = "EPSG:4326";
+
+/// Creates the transform from `from` to `to`. This fails when the PROJ database is missing as well as
+/// for unknown CRSs, so the error says how to install it.
+pub fn new_transform(from: &str, to: &str) -> Result<Proj, ScaError> {
+    Proj::new_known_crs(from, to, None).map_err(|e| {
+        ScaError::Proj(format!("cannot create {} -> {} transform ({}); check that PROJ and its data (e.g. the proj-data package) are installed", from, to, e))
+    })
+}
+
+/// Projects `lat`/`lon` to a BNG easting and northing in km, with a `WGS84` -> EPSG:27700 transform. PROJ
+/// takes longitude first, so calls go through here rather than passing the tuple by hand.
+pub fn to_bng_km(proj: &Proj, lat: f64, lon: f64) -> Option<(f64, f64)> {
+    proj.convert((lon, lat))
+        .map(|(x, y)| (x / 1000.0, y / 1000.0)) // Convert to kms
+        .ok()
+}
+
+/// Reprojects every record with a location in `from` to `to` using one transform. Records in or
+/// out of EPSG:4326 use `lng`/`lat`; projected CRSs use `x_km`/`y_km` (in km). Records without a
+/// location in `from` are left unchanged.
+pub fn reproject_records(records: &mut [AggregateSchoolRecord], from: &str, to: &str) -> Result<(), ScaError> {
+    let proj = new_transform(from, to)?;
+
+    for record in records.iter_mut() {
+        let point = if from == WGS84 {
+            record.lng.zip(record.lat)
+        } else {
+            record.x_km.zip(record.y_km).map(|(x, y)| (x * 1000.0, y * 1000.0))
+        };
+        let Some(point) = point else {
+            continue;
+        };
+
+        let (x, y) = proj.convert(point)?;
+        if to == WGS84 {
+            record.lng = Some(x);
+            record.lat = Some(y);
+        } else {
+            record.x_km = Some(x / 1000.0);
+            record.y_km = Some(y / 1000.0);
+        }
+    }
+
+    Ok(())
+}
+
 impl AggregatePSchoolRecord {
+    /// The first reason this school cannot be given a catchment radius, if any.
+    pub fn drop_reason(&self) -> Option<&'static str> {
+        if self.rwm_ta.is_none() {
+            Some("missing rwm_ta")
+        } else if self.x_km.is_none() || self.y_km.is_none() {
+            Some("missing location")
+        } else if self.target_density.is_none() {
+            Some("missing target_density")
+        } else if self.pop.is_none() {
+            Some("missing pop")
+        } else if self.target_prop.is_none() {
+            Some("missing target_prop")
+        } else if !valid_area(self.target_area()) {
+            Some("non-positive target area")
+        } else if self.is_state == 0 {
+            Some("not state")
+        } else {
+            None
+        }
+    }
+
+    /// The catchment area (km^2) needed to reach `pop` pupils at the target density and proportion.
+    pub fn target_area(&self) -> Option<f64> {
+        Some(self.pop? as f64 / (self.target_density? * self.target_prop?))
+    }
+
+    /// The school's target area around its BNG position, the input to scaling.
+    pub fn radial(&self) -> Option<assign::RadialArea> {
+        Some(assign::RadialArea {
+            origin: Vector2::new(self.x_km?, self.y_km?),
+            area: self.target_area()?,
+        })
+    }
+
     #[inline]
     pub fn location(&self) -> Option<GeoLocation> {
         if let (Some(lat), Some(lng)) = (self.lat, self.lng) {
@@ -304,10 +540,27 @@ impl AggregatePSchoolRecord {
     }
 }
 
+/// Extra column appended to a school record in the dropped schools output.
+#[derive(serde::Serialize)]
+struct DropReason {
+    drop_reason: &'static str,
+}
+
+impl DropReason {
+    fn of(reason: Option<&'static str>) -> Self {
+        Self { drop_reason: reason.unwrap_or("") }
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct AggregatePSchoolRecord {
     pub year: u32,
+    /// The LAD of the school's postcode.
+    #[serde(rename = "lad_postcode", alias = "lad")]
     pub lad: Option<String>,
+    /// The LAD of the school's Ofsted record.
+    #[serde(default)]
+    pub lad_ofsted: Option<String>,
     pub name: String,
     pub pcode: String,
     pub lat: Option<f64>,
@@ -316,6 +569,11 @@ pub struct AggregatePSchoolRecord {
     pub x_km: Option<f64>,
     pub y_km: Option<f64>,
     pub radius: Option<f64>,
+    /// Area of the catchment circle, when a radius was solved.
+    #[serde(default)]
+    pub catchment_area_km2: Option<f64>,
+    #[serde(default)]
+    pub catchment_area_ha: Option<f64>,
     pub target_density: Option<f64>,
     pub target_prop: Option<f64>,
     pub urn: String,
@@ -333,9 +591,269 @@ trait School {
     fn get_urn(&self) -> &str;
 
     fn get_pcode(&self) -> &str;
+
+    fn get_location(&self) -> Option<GeoLocation>;
+}
+
+/// The school phase, which has its own input file, records and outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Primary,
+    Secondary,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 2] = [Phase::Secondary, Phase::Primary];
+
+    /// The short name used in file names and the sqlite `circles` table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Primary => "prim",
+            Self::Secondary => "sec",
+        }
+    }
+
+    /// The sanitised school file for `year` in `input_dir`.
+    fn input(&self, input_dir: &Path, year: u32) -> PathBuf {
+        match self {
+            Self::Primary => input_dir.join(format!("san_scrawp_{}.csv", year)),
+            Self::Secondary => input_dir.join(format!("san_scraw_{}.csv", year)),
+        }
+    }
+
+    /// `<stem>_<name>.<ext>` in `output_dir`, e.g. `all_sec.csv`.
+    fn output(&self, output_dir: &Path, stem: &str, ext: &str) -> PathBuf {
+        output_dir.join(format!("{}_{}.{}", stem, self.name(), ext))
+    }
+}
+
+/// Which phases a run computes, e.g. to build without a missing year's primary data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PhaseSet {
+    Primary,
+    Secondary,
+    #[default]
+    Both,
+}
+
+impl PhaseSet {
+    pub fn includes(&self, phase: Phase) -> bool {
+        matches!((self, phase), (Self::Both, _) | (Self::Primary, Phase::Primary) | (Self::Secondary, Phase::Secondary))
+    }
+
+    /// The names of the included phases, as recorded in the manifest.
+    pub fn names(&self) -> Vec<String> {
+        Phase::ALL.iter().filter(|x| self.includes(**x)).map(|x| x.name().to_owned()).collect()
+    }
+}
+
+/// Hectares in a km^2.
+const HA_PER_KM2: f64 = 100.0;
+
+/// The area of a catchment of radius `r` km, in km^2 and hectares.
+fn catchment_areas(r: f64) -> (Option<f64>, Option<f64>) {
+    let km2 = std::f64::consts::PI * r * r;
+    (Some(km2), Some(km2 * HA_PER_KM2))
+}
+
+/// A phase's aggregate school record, so `run_schools` can project, join, scale and write both phases the
+/// same way.
+trait PhaseSchool: Serialize + Clone + Sized {
+    /// The raw record parsed from the phase's school file.
+    type Raw: School + DeserializeOwned;
+
+    const PHASE: Phase;
+
+    /// Builds the record for `year`, with `pos` the school's BNG position in km.
+    fn aggregate(year: u32, school: SchoolInfo<Self::Raw>, pos: Option<(f64, f64)>, default_density: Option<f64>) -> Self;
+
+    /// The phase's headline attainment measure: `gcseg2` for secondaries and `rwm_ta` for primaries.
+    fn quality_metric(&self) -> Option<f32>;
+
+    /// The sort key under `order`, higher is better.
+    fn order_key(&self, order: OrderBy) -> Option<f64>;
+
+    fn urn(&self) -> &str;
+
+    fn drop_reason(&self) -> Option<&'static str>;
+
+    fn radial(&self) -> Option<assign::RadialArea>;
+
+    /// The LAD of the school's postcode and its BNG position in km, where it has both.
+    fn lad_position(&self) -> Option<(&str, Vector2<f64>)>;
+
+    fn set_radius(&mut self, r: f64);
+
+    /// Writes the year's scaled records to the QGIS database. Only the catchments by default.
+    #[cfg(feature = "sqlite")]
+    fn write_sqlite(db: &mut rusqlite::Connection, year: u32, scaled: &[(Self, Option<assign::Circle>)]) -> Result<(), ScaError> {
+        let keyed: Vec<(&str, assign::Circle)> = scaled.iter().filter_map(|(s, c)| Some((s.urn(), (*c)?))).collect();
+        sqlite::write_circles(db, Self::PHASE.name(), year, &keyed)
+    }
 }
 
-fn load_regions<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, Box<dyn Error>> {
+impl PhaseSchool for AggregateSchoolRecord {
+    type Raw = SchoolRecord;
+
+    const PHASE: Phase = Phase::Secondary;
+
+    fn aggregate(year: u32, school: SchoolInfo<SchoolRecord>, pos: Option<(f64, f64)>, default_density: Option<f64>) -> Self {
+        aggregate_sec(year, school, pos, default_density)
+    }
+
+    fn quality_metric(&self) -> Option<f32> {
+        self.gcseg2
+    }
+
+    fn order_key(&self, order: OrderBy) -> Option<f64> {
+        order.sec_key(self)
+    }
+
+    fn urn(&self) -> &str {
+        &self.urn
+    }
+
+    fn drop_reason(&self) -> Option<&'static str> {
+        AggregateSchoolRecord::drop_reason(self)
+    }
+
+    fn radial(&self) -> Option<assign::RadialArea> {
+        AggregateSchoolRecord::radial(self)
+    }
+
+    fn lad_position(&self) -> Option<(&str, Vector2<f64>)> {
+        Some((self.lad.as_deref()?, Vector2::new(self.x_km?, self.y_km?)))
+    }
+
+    fn set_radius(&mut self, r: f64) {
+        self.radius = Some(r);
+        (self.catchment_area_km2, self.catchment_area_ha) = catchment_areas(r);
+    }
+
+    /// The catchments, and the secondaries themselves.
+    #[cfg(feature = "sqlite")]
+    fn write_sqlite(db: &mut rusqlite::Connection, year: u32, scaled: &[(Self, Option<assign::Circle>)]) -> Result<(), ScaError> {
+        let keyed: Vec<(&str, assign::Circle)> = scaled.iter().filter_map(|(s, c)| Some((s.urn.as_str(), (*c)?))).collect();
+        sqlite::write_circles(db, Self::PHASE.name(), year, &keyed)?;
+        let schools: Vec<AggregateSchoolRecord> = scaled.iter().map(|(s, _)| s.clone()).collect();
+        sqlite::write_schools(db, &schools)
+    }
+}
+
+impl PhaseSchool for AggregatePSchoolRecord {
+    type Raw = PSchoolRecord;
+
+    const PHASE: Phase = Phase::Primary;
+
+    fn aggregate(year: u32, school: SchoolInfo<PSchoolRecord>, pos: Option<(f64, f64)>, default_density: Option<f64>) -> Self {
+        aggregate_prim(year, school, pos, default_density)
+    }
+
+    fn quality_metric(&self) -> Option<f32> {
+        self.rwm_ta
+    }
+
+    fn order_key(&self, order: OrderBy) -> Option<f64> {
+        order.prim_key(self)
+    }
+
+    fn urn(&self) -> &str {
+        &self.urn
+    }
+
+    fn drop_reason(&self) -> Option<&'static str> {
+        AggregatePSchoolRecord::drop_reason(self)
+    }
+
+    fn radial(&self) -> Option<assign::RadialArea> {
+        AggregatePSchoolRecord::radial(self)
+    }
+
+    fn lad_position(&self) -> Option<(&str, Vector2<f64>)> {
+        Some((self.lad.as_deref()?, Vector2::new(self.x_km?, self.y_km?)))
+    }
+
+    fn set_radius(&mut self, r: f64) {
+        self.radius = Some(r);
+        (self.catchment_area_km2, self.catchment_area_ha) = catchment_areas(r);
+    }
+}
+
+/// Parses the raw lat/long columns of a school record. Blank or malformed values give `None`.
+fn parse_location(lat: &str, long: &str) -> Option<GeoLocation> {
+    match (lat.trim().parse::<f64>(), long.trim().parse::<f64>()) {
+        (Ok(latitude), Ok(longitude)) => Some(GeoLocation { latitude, longitude }),
+        _ => None,
+    }
+}
+
+/// Opens a CSV written in `encoding`, converting it to UTF-8. Either way the file is streamed; anything but
+/// UTF-8 (e.g. Windows-1252 DfE exports) is decoded a buffer at a time.
+pub fn csv_reader<P: AsRef<Path>>(path: P, encoding: &'static Encoding) -> Result<csv::Reader<Box<dyn io::Read>>, ScaError> {
+    let file = std::fs::File::open(path)?;
+    let reader: Box<dyn io::Read> = if encoding == encoding_rs::UTF_8 {
+        Box::new(file)
+    } else {
+        Box::new(DecodeReader::new(file, encoding, DECODE_BUFFER))
+    };
+    Ok(ReaderBuilder::new().from_reader(reader))
+}
+
+/// Bytes of input `csv_reader` decodes at a time.
+const DECODE_BUFFER: usize = 1 << 16;
+
+/// Reads `inner`, written in some encoding, as UTF-8. Like `Encoding::decode` a BOM overrides the encoding, and
+/// malformed sequences become U+FFFD.
+struct DecodeReader<R> {
+    inner: R,
+    decoder: encoding_rs::Decoder,
+    input: Vec<u8>,
+    // The undecoded part of `input`.
+    input_start: usize,
+    input_end: usize,
+    output: Vec<u8>,
+    // The part of `output` not yet read.
+    output_start: usize,
+    output_end: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: io::Read> DecodeReader<R> {
+    fn new(inner: R, encoding: &'static Encoding, capacity: usize) -> Self {
+        let decoder = encoding.new_decoder();
+        // Room for a whole buffer of input, so every decode call makes progress.
+        let output = vec![0; decoder.max_utf8_buffer_length(capacity).unwrap_or(4 * capacity).max(4)];
+        Self { inner, decoder, input: vec![0; capacity.max(1)], input_start: 0, input_end: 0, output, output_start: 0, output_end: 0, eof: false, done: false }
+    }
+}
+
+impl<R: io::Read> io::Read for DecodeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_start == self.output_end {
+            if self.done {
+                return Ok(0);
+            }
+            if self.input_start == self.input_end && !self.eof {
+                self.input_end = self.inner.read(&mut self.input)?;
+                self.input_start = 0;
+                self.eof = self.input_end == 0;
+            }
+            let (result, read, written, _) = self.decoder.decode_to_utf8(&self.input[self.input_start..self.input_end], &mut self.output, self.eof);
+            self.input_start += read;
+            self.output_start = 0;
+            self.output_end = written;
+            // Once the last input has gone through, the decoder has flushed anything it held back.
+            self.done = self.eof && result == encoding_rs::CoderResult::InputEmpty;
+        }
+        let n = buf.len().min(self.output_end - self.output_start);
+        buf[..n].copy_from_slice(&self.output[self.output_start..self.output_start + n]);
+        self.output_start += n;
+        Ok(n)
+    }
+}
+
+fn load_regions<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, ScaError> {
     let mut rdr = csv::Reader::from_path(path)?;
 
     let mut iter = rdr.deserialize::<RegionPcodeRecord>();
@@ -345,7 +863,7 @@ fn load_regions<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>, Box<
         if let Ok(record) = result {
             let mut lad = record.lad;
             lad.replace(".", "");
-            region_map.insert(record.pcode.trim().to_owned(), lad.clone());
+            region_map.insert(normalize_postcode(&record.pcode), lad.clone());
         }
     }
 
@@ -374,6 +892,22 @@ struct OfstedRecord {
 
     #[serde(rename = "Sixth form provision (where applicable)")]
     sixthform: Option<u32>,
+
+    #[serde(rename = "Inspection start date", default)]
+    inspection_date: Option<String>,
+}
+
+impl OfstedRecord {
+    /// The inspection date as (year, month, day), from either dd/mm/yyyy or yyyy-mm-dd.
+    fn inspected_on(&self) -> Option<(u32, u32, u32)> {
+        let date = self.inspection_date.as_deref()?.trim();
+        let parts: Vec<u32> = date.split(|c| c == '/' || c == '-').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+        match parts[..] {
+            [y, m, d] if y > 31 => Some((y, m, d)),
+            [d, m, y] => Some((y, m, d)),
+            _ => None,
+        }
+    }
 }
 
 fn find_ofsted<P: AsRef<Path>>(path: P, urn: &str) -> Result<Option<OfstedRecord>, Box<dyn Error>> {
@@ -393,21 +927,45 @@ fn find_ofsted<P: AsRef<Path>>(path: P, urn: &str) -> Result<Option<OfstedRecord
     Ok(None)
 }
 
-fn load_ofsted<P: AsRef<Path>>(path: P) -> Result<HashMap<String, OfstedRecord>, Box<dyn Error>> {
+/// Loads every inspection per URN, oldest first. Undated inspections sort before dated ones.
+fn load_ofsted<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<OfstedRecord>>, ScaError> {
     let mut rdr = csv::Reader::from_path(path)?;
     let mut iter = rdr.deserialize::<OfstedRecord>();
 
-    let mut map: HashMap<String, OfstedRecord> = HashMap::new();
+    let mut map: HashMap<String, Vec<OfstedRecord>> = HashMap::new();
 
     for result in iter {
         if let Ok(record) = result {
-            map.insert(record.urn.clone(), record);
+            map.entry(record.urn.clone()).or_default().push(record);
         }
     }
 
+    for inspections in map.values_mut() {
+        inspections.sort_by_key(|x| x.inspected_on());
+    }
+
     Ok(map)
 }
 
+/// The latest inspection on or before `year`, so older school data isn't rated by a later inspection.
+/// Undated inspections are always eligible, and the last one listed wins.
+fn ofsted_for_year(inspections: &[OfstedRecord], year: u32) -> Option<&OfstedRecord> {
+    inspections.iter().rev().find(|x| x.inspected_on().map(|(y, _, _)| y <= year).unwrap_or(true))
+}
+
+/// The form of a postcode used as a key: uppercase with one space before the three character inward
+/// code, so "sw1a1aa" and " SW1A  1AA" both give "SW1A 1AA". Anything too short to have an inward code
+/// is just uppercased with the whitespace removed.
+pub fn normalize_postcode(s: &str) -> String {
+    let compact: String = s.chars().filter(|c| !c.is_whitespace()).flat_map(char::to_uppercase).collect();
+    if compact.len() > 3 && compact.is_ascii() {
+        let (outward, inward) = compact.split_at(compact.len() - 3);
+        format!("{} {}", outward, inward)
+    } else {
+        compact
+    }
+}
+
 fn first_letters(postcode: &str) -> Option<String> {
     let re = Regex::new(r"^[A-Za-z]+").unwrap();
     match re.find(postcode) {
@@ -416,15 +974,26 @@ fn first_letters(postcode: &str) -> Option<String> {
     }
 }
 
+/// A school with no Ofsted row at all, as written to `no_ofsted.csv`.
+#[derive(Serialize)]
+struct NoOfstedRow<'a> {
+    year: u32,
+    urn: &'a str,
+    pcode: &'a str,
+}
+
+/// Parses the schools of `year`, joining on their Ofsted inspection and LAD. Schools whose URN has no Ofsted
+/// row at all (as opposed to no inspection by `year`) are written to `no_ofsted` if given. Also returns the
+/// fraction of schools with an Ofsted row, which is 1 when there are no schools.
 fn parse_dset<P: AsRef<Path>, S: School + DeserializeOwned>(
     path: P,
-    ofsted_data: &HashMap<String, OfstedRecord>,
+    year: u32,
+    ofsted_data: &HashMap<String, Vec<OfstedRecord>>,
     region_map: &HashMap<String, String>,
-) -> Result<Vec<SchoolInfo<S>>, Box<dyn Error>> {
-    let mut rdr = ReaderBuilder::new()
-        //.has_headers(true)
-        //.flexible(true)
-        .from_path(path)?;
+    mut no_ofsted: Option<&mut Writer<std::fs::File>>,
+    encoding: &'static Encoding,
+) -> Result<(Vec<SchoolInfo<S>>, f64), ScaError> {
+    let mut rdr = csv_reader(path, encoding)?;
 
     let mut iter = rdr.deserialize::<S>();
 
@@ -434,16 +1003,34 @@ fn parse_dset<P: AsRef<Path>, S: School + DeserializeOwned>(
     let mut man_a = 0;
 
     let mut failed = 0;
+    let mut unmatched = 0;
 
     for result in iter {
         match result {
             Ok(record) => {
-                let ofsted = ofsted_data.get(record.get_urn()).cloned();
-                let lad = region_map.get(record.get_pcode()).cloned();
+                let inspections = ofsted_data.get(record.get_urn());
+                if inspections.is_none() {
+                    unmatched += 1;
+                    if let Some(writer) = no_ofsted.as_mut() {
+                        writer.serialize(NoOfstedRow { year, urn: record.get_urn(), pcode: record.get_pcode() })?;
+                    }
+                }
+                let ofsted = inspections.and_then(|x| ofsted_for_year(x, year)).cloned();
+                let lad = region_map.get(&normalize_postcode(record.get_pcode())).cloned();
+                // Schools not inspected by `year` still have a local authority.
+                let lad_ofsted = ofsted.as_ref().or(inspections.and_then(|x| x.last())).map(|x| x.lad.clone());
+                if let (Some(a), Some(b)) = (&lad, &lad_ofsted) {
+                    if !same_lad(a, b) {
+                        println!("LAD mismatch for {} ({}): postcode {}, Ofsted {}", record.get_urn(), record.get_pcode(), a, b);
+                    }
+                }
+                let location = record.get_location();
                 schools.push(SchoolInfo {
                     record,
                     ofsted,
                     lad,
+                    lad_ofsted,
+                    location,
                 })
             }
             Err(e) => {
@@ -454,275 +1041,457 @@ fn parse_dset<P: AsRef<Path>, S: School + DeserializeOwned>(
     }
 
     //println!("a, n, f: {}, {}, {}", man_a, man_n, failed);
-    Ok(schools)
+    let match_rate = if schools.is_empty() { 1.0 } else { 1.0 - unmatched as f64 / schools.len() as f64 };
+    Ok((schools, match_rate))
+}
+
+/// Whether two LAD names are the same, ignoring case and surrounding whitespace.
+fn same_lad(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
 }
 
-fn percentage_string_to_float(input: &str) -> Result<f32, std::num::ParseFloatError> {
+/// Parses a percentage ("45%" or "45") as a fraction. Blank, malformed and non-finite ("NaN", "inf") values give `None`.
+fn percentage_string_to_float(input: &str) -> Option<f32> {
     let cleaned = input.trim_end_matches('%');
-    cleaned.parse::<f32>().map(|n| n / 100.0)
+    cleaned.parse::<f32>().ok().filter(|n| n.is_finite()).map(|n| n / 100.0)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    //run_schools(2019..2020)
+fn main() -> Result<(), ScaError> {
+    //run_schools(2019..2020, None, OrderBy::default(), false)
     run_atomic()
+    //atomic::run_postcode_schools(2019)
     //combine_csv_files("depr", "depr.csv"); Ok(())
+    //combine_csv_dedup("pp", "pp.csv", &["id"], KeepRow::Last, true); Ok(())
+    //print!("{}", atomic::diff_outputs("before/full_atomic_async.csv", "full_atomic_async.csv", &["id"])?); Ok(())
     //assign::circle_test();
 }
 
 const STATE_PROP: f64 = 0.8;
 
-fn run_schools(years: std::ops::Range<u32>) -> Result<(), Box<dyn Error>> {
-    let regions = load_regions("postcodes.csv")?;
-    let ofsted = load_ofsted("ofsted.csv")?;
+/// How school catchments are grown in `run_schools`.
+const SCALE_METHOD: assign::ScaleMethod = assign::ScaleMethod::Sequential;
+
+/// How overlaps are measured while scaling. Sampled, as dense urban catchments have too many
+/// neighbours for exact inclusion-exclusion.
+const INTERSECTION_METHOD: assign::IntersectionMethod = assign::IntersectionMethod::Sampled { samples: 1500 };
+
+/// Area residual (km2) below which a catchment counts as solved while scaling.
+const SCALE_EPSILON: f64 = 1e-3;
+
+/// Encoding of the `san_scraw*.csv` school files.
+const SCHOOL_ENCODING: &Encoding = encoding_rs::UTF_8;
+
+/// The quality measure that orders schools for scaling in `run_schools`: better schools claim catchment
+/// area first. Primaries have no GCSE or EBacc results, so those order primaries by `rwm_ta`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrderBy {
+    /// Proportion with grade 4+ in English and maths GCSE.
+    #[default]
+    Gcseg2,
+    /// Ofsted overall grade, outstanding first.
+    OfstedOverall,
+    /// Progress 8 score in the EBacc element.
+    Ebacc,
+}
 
-    let mut geo_map = load_geo_data("geo.csv")?;
-    let geonames_data = geo_rust::get_postal_data(Country::UnitedKingdomFull);
+impl OrderBy {
+    /// The secondary's sort key, higher is better.
+    fn sec_key(&self, school: &AggregateSchoolRecord) -> Option<f64> {
+        match self {
+            Self::Gcseg2 => school.gcseg2.map(f64::from),
+            Self::OfstedOverall => school.of_overall.map(|x| -f64::from(x)),
+            Self::Ebacc => school.ebacc.trim().parse().ok().filter(|x: &f64| x.is_finite()),
+        }
+    }
 
-    println!("parsed postcodes, {}", regions.len());
-    let mut agg_sec: Vec<(u32, Vec<AggregateRecord>)> = Vec::new();
+    /// The primary's sort key, higher is better.
+    fn prim_key(&self, school: &AggregatePSchoolRecord) -> Option<f64> {
+        match self {
+            Self::Gcseg2 | Self::Ebacc => school.rwm_ta.map(f64::from),
+            Self::OfstedOverall => school.of_overall.map(|x| -f64::from(x)),
+        }
+    }
+}
 
-    let mut agg_prim: Vec<(u32, Vec<AggregatePRecord>)> = Vec::new();
+/// Best first by `key`; schools without one go last, in their original order.
+fn sort_by_key_desc<T>(schools: &mut [T], key: impl Fn(&T) -> Option<f64>) {
+    schools.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap_or(std::cmp::Ordering::Equal));
+}
 
-    let mut complete_writer_sec = Writer::from_path("all_sec.csv")?;
+/// Parses a school's target density, using `default_density` when it is blank, malformed or not finite.
+fn target_density(s: &str, default_density: Option<f64>) -> Option<f64> {
+    s.parse().ok().filter(|x: &f64| x.is_finite()).or(default_density)
+}
 
-    let mut complete_writer_prim = Writer::from_path("all_prim.csv")?;
+/// Whether a school is `(is_state, is_selective)`: selective when its admissions policy is "SEL", and
+/// state when its type is one of `targets` and it is not selective.
+fn classify_school(school_type: &str, adm_pol: &str, targets: &[&str]) -> (bool, bool) {
+    let selective = adm_pol == "SEL";
+    // Only choose the right kind of schools.
+    let state = targets.contains(&school_type) && !selective;
+    (state, selective)
+}
 
-    let to_bng = Proj::new_known_crs("EPSG:4326", "EPSG:27700", None)
-        .expect("Failed to create transformation");
+/// Builds the secondary record for `year`, with `pos` the school's BNG position in km.
+fn aggregate_sec(year: u32, school: SchoolInfo<SchoolRecord>, pos: Option<(f64, f64)>, default_density: Option<f64>) -> AggregateSchoolRecord {
+    let gcseg2 = percentage_string_to_float(&school.record.gcseg2);
+    let gcseg2_dis = percentage_string_to_float(&school.record.gcseg2_dis);
+
+    let (state, selective) = classify_school(&school.record.school_type, &school.record.adm_pol, &TARGET_SCHOOL_TYPES);
+
+    AggregateSchoolRecord {
+        year,
+        name: school.record.name.clone(),
+        pcode: school.record.pcode.clone(),
+        msoa: school.record.msoa.clone(),
+        target_density: target_density(&school.record.target_density, default_density),
+        radius: None, // Will allocate once we order by quality.
+        catchment_area_km2: None,
+        catchment_area_ha: None,
+        lat: school.location.as_ref().map(|x| x.latitude),
+        lng: school.location.as_ref().map(|x| x.longitude),
+        target_prop: school.record.target_prop.parse::<f64>().ok(),
+        pop: school.record.pop.parse::<f32>().map(|x| x as u32).ok(),
+        x_km: pos.map(|(x, _)| x),
+        y_km: pos.map(|(_, y)| y),
+        urn: school.record.urn.clone(),
+        is_selective: selective as u32,
+        school_type: school.record.school_type.clone(),
+        is_state: state as u32,
+        lad: school.lad,
+        lad_ofsted: school.lad_ofsted,
+        ebacc: school.record.ebacc.clone(),
+        p8: school.record.p8.clone(),
+        of_overall: school.ofsted.as_ref().and_then(|x| x.overall),
+        of_behaviour: school.ofsted.as_ref().and_then(|x| x.behaviour),
+        of_educ: school.ofsted.as_ref().and_then(|x| x.educ),
+        of_pdev: school.ofsted.as_ref().and_then(|x| x.pdev),
+        of_sixthform: school.ofsted.as_ref().and_then(|x| x.sixthform),
+
+        gcseg2,
+        gcseg2_dis,
+    }
+}
 
-    for i in years {
-        // let fname: String = format!("scraw_{}.csv", i);
-        // sanitize(&fname, &format!("san_{}", &fname));
-        //
-        // let fname: String = format!("scrawp_{}.csv", i);
-        // sanitize(&fname, &format!("san_{}", &fname));
-        {
-            let fname = format!("san_scraw_{}.csv", i);
-
-            match parse_dset::<String, SchoolRecord>(fname, &ofsted, &regions) {
-                Ok(schools) => {
-                    let mut ag_schools = Vec::with_capacity(schools.len());
-                    for school in schools {
-                        let gcseg2 = percentage_string_to_float(&school.record.gcseg2).ok();
-                        let gcseg2_dis = percentage_string_to_float(&school.record.gcseg2_dis).ok();
-
-                        let selective = school.record.adm_pol == "SEL";
-
-                        //let loc = geo_data(&school.record.pcode, &mut geo_map, &geonames_data);
-
-                        // Only choose the right kind of schools.
-                        let state = TARGET_SCHOOL_TYPES
-                            .contains(&school.record.school_type.as_str())
-                            && !selective;
-
-                        let pos = if let (Some(lat), Some(long)) = (
-                            school.record.lat.parse::<f64>().ok(),
-                            school.record.long.parse::<f64>().ok(),
-                        ) {
-                            to_bng
-                                .convert((long, lat))
-                                .map(|(x, y)| (x / 1000.0, y / 1000.0)) // Convert to kms
-                                .ok()
-                        } else {
-                            None
-                        };
-
-                        let r = AggregateSchoolRecord {
-                            year: i,
-                            name: school.record.name.clone(),
-                            pcode: school.record.pcode.clone(),
-                            msoa: school.record.msoa.clone(),
-                            target_density: school.record.target_density.parse().ok(),
-                            radius: None, // Will allocate once we order by quality.
-                            lat: school.record.lat.parse::<f64>().ok(),
-                            lng: school.record.long.parse::<f64>().ok(),
-                            target_prop: school.record.target_prop.parse::<f64>().ok(),
-                            pop: school.record.pop.parse::<f32>().map(|x| x as u32).ok(),
-                            x_km: pos.map(|(x, _)| x),
-                            y_km: pos.map(|(_, y)| y),
-                            urn: school.record.urn.clone(),
-                            is_selective: selective as u32,
-                            school_type: school.record.school_type.clone(),
-                            is_state: state as u32,
-                            lad: school.lad,
-                            ebacc: school.record.ebacc.clone(),
-                            p8: school.record.p8.clone(),
-                            of_overall: school.ofsted.as_ref().and_then(|x| x.overall),
-                            of_behaviour: school.ofsted.as_ref().and_then(|x| x.behaviour),
-                            of_educ: school.ofsted.as_ref().and_then(|x| x.educ),
-                            of_pdev: school.ofsted.as_ref().and_then(|x| x.pdev),
-                            of_sixthform: school.ofsted.as_ref().and_then(|x| x.sixthform),
-
-                            gcseg2,
-                            gcseg2_dis,
-                        };
-
-                        //println!("vs: {}, {}, {}, {}, {}, x:{}", r.gcseg2.is_none(), r.x_km.is_none(), r.y_km.is_none(), r.target_density.is_none(), r.pop.is_none(), school.record.pop);
-
-                        ag_schools.push(r);
-                    }
+/// Builds the primary record for `year`, with `pos` the school's BNG position in km.
+fn aggregate_prim(year: u32, school: SchoolInfo<PSchoolRecord>, pos: Option<(f64, f64)>, default_density: Option<f64>) -> AggregatePSchoolRecord {
+    let rwm_ta = percentage_string_to_float(&school.record.rwm_ta);
+    let rwm_ta_dis = percentage_string_to_float(&school.record.rwm_ta_dis);
+
+    // The primary data has no admissions policy; primaries are never selective.
+    let (state, _) = classify_school(&school.record.school_type, "", &TARGET_SCHOOL_TYPES);
+    AggregatePSchoolRecord {
+        year,
+        name: school.record.name.clone(),
+        pcode: school.record.pcode.clone(),
+        lat: school.location.as_ref().map(|x| x.latitude),
+        lng: school.location.as_ref().map(|x| x.longitude),
+        target_prop: school.record.target_prop.parse::<f64>().ok(),
+        pop: school.record.pop.parse::<f32>().map(|x| x as u32).ok(),
+        x_km: pos.map(|(x, _)| x),
+        y_km: pos.map(|(_, y)| y),
+        target_density: target_density(&school.record.target_density, default_density),
+        radius: None, // Will allocate once we order by quality.
+        catchment_area_km2: None,
+        catchment_area_ha: None,
+        urn: school.record.urn.clone(),
+        is_state: state as u32,
+        school_type: school.record.school_type.clone(),
+        lad: school.lad,
+        lad_ofsted: school.lad_ofsted,
+        of_overall: school.ofsted.as_ref().and_then(|x| x.overall),
+        of_behaviour: school.ofsted.as_ref().and_then(|x| x.behaviour),
+        of_educ: school.ofsted.as_ref().and_then(|x| x.educ),
+        of_pdev: school.ofsted.as_ref().and_then(|x| x.pdev),
+        rwm_ta,
+        rwm_ta_dis,
+    }
+}
 
-                    // Remove schools without the stuff we need to calculate radius.
-                    let (drained, mut ag_schools): (Vec<_>, Vec<_>) =
-                        ag_schools.into_iter().partition(|r| {
-                            (r.gcseg2.is_none()
-                                || r.x_km.is_none()
-                                || r.y_km.is_none()
-                                || r.target_density.is_none()
-                                || r.pop.is_none()
-                                || r.target_prop.is_none()
-                                || r.is_selective == 1
-                                || r.is_state == 0)
-                        });
-
-                    println!("ag: {}", ag_schools.len());
-
-                    ag_schools
-                        .sort_by(|a, b| b.gcseg2.unwrap().partial_cmp(&a.gcseg2.unwrap()).unwrap());
-
-                    // First sort schools by quality. Ordering matches ag_schools one to one.
-                    let radials: Vec<assign::RadialArea> = ag_schools
-                        .iter()
-                        .filter_map(|r| {
-                            Some(assign::RadialArea {
-                                origin: Vector2::new(r.x_km.unwrap(), r.y_km.unwrap()),
-                                area: r.pop.unwrap() as f64
-                                    / (r.target_density.unwrap() * r.target_prop.unwrap()),
-                            })
-                        })
-                        .collect();
-
-                    let circles = assign::scale_all(&radials, 0.5, 1e-3, 1000)
-                        .ok_or("Failed to scale radials!")?;
-
-                    for (school, circle) in ag_schools.iter_mut().zip(circles.iter()) {
-                        school.radius = Some(circle.r);
-                    }
+/// Gives every school that passes `drop_reason` its catchment circle (setting `radius`), with the best
+/// schools by `order` claiming area first. Returns each input record exactly once: the scaled schools in
+/// priority order paired with their circle, then the dropped ones paired with `None`.
+fn scale_schools<T: PhaseSchool>(records: Vec<T>, order: OrderBy) -> Result<Vec<(T, Option<assign::Circle>)>, ScaError> {
+    // Remove schools without the stuff we need to calculate radius.
+    let (drained, mut ag_schools): (Vec<_>, Vec<_>) =
+        records.into_iter().partition(|r| r.drop_reason().is_some());
 
-                    for school in ag_schools {
-                        complete_writer_sec.serialize(&school)?;
-                    }
+    println!("ag: {}", ag_schools.len());
 
-                    for school in drained {
-                        complete_writer_sec.serialize(&school)?;
-                    }
-                    println!("parsed schools {}", i);
+    sort_by_key_desc(&mut ag_schools, |x| x.order_key(order));
 
-                    render::draw_circles_to_png(&circles, 1000, 1000, "map_sec.png");
-                }
-                Err(e) => println!("Failed to parse school: {}", e),
-            }
+    // First sort schools by quality. Ordering matches ag_schools one to one.
+    let radials: Vec<assign::RadialArea> = ag_schools.iter().map(|r| r.radial().unwrap()).collect();
+
+    let circles = assign::scale(&radials, SCALE_METHOD, INTERSECTION_METHOD, 0.5, SCALE_EPSILON, 1000)
+        .ok_or_else(|| ScaError::Missing("catchment radii (scaling failed)".to_owned()))?;
+
+    let mut scaled = Vec::with_capacity(ag_schools.len() + drained.len());
+    for (mut school, circle) in ag_schools.into_iter().zip(circles) {
+        school.set_radius(circle.r);
+        scaled.push((school, Some(circle)));
+    }
+    scaled.extend(drained.into_iter().map(|x| (x, None)));
+
+    Ok(scaled)
+}
+
+/// The convex hull of each LAD's schools (by BNG position, in km), ordered by LAD. Schools without a LAD or
+/// position are left out.
+fn lad_hulls<'a, T: PhaseSchool + 'a>(schools: impl IntoIterator<Item = &'a T>) -> Vec<(String, Vec<Vector2<f64>>)> {
+    let mut by_lad: HashMap<&str, Vec<Vector2<f64>>> = HashMap::new();
+    for (lad, pos) in schools.into_iter().filter_map(|s| s.lad_position()) {
+        by_lad.entry(lad).or_default().push(pos);
+    }
+    let mut hulls: Vec<(String, Vec<Vector2<f64>>)> = by_lad.into_iter().map(|(lad, points)| (lad.to_owned(), assign::convex_hull(&points))).collect();
+    hulls.sort_by(|a, b| a.0.cmp(&b.0));
+    hulls
+}
+
+/// One row of `catchments.csv`.
+#[derive(Serialize)]
+struct CatchmentRow<'a> {
+    phase: &'static str,
+    year: u32,
+    urn: &'a str,
+    x_km: f64,
+    y_km: f64,
+    radius: f64,
+    target_area: f64,
+    exclusive_area: f64,
+    // exclusive_area - target_area
+    residual: f64,
+}
+
+/// Writes the area accounting of sequentially scaled catchments: for each school (in scaling order) its
+/// circle, the target area it was given, and the area it actually adds beyond the circles before it (`exclusive`,
+/// from `assign::sequential_exclusive_areas`). `schools`, `circles`, `radials` and `exclusive` correspond one to
+/// one. The rows are appended to `writer` under `T`'s phase and `year`, so one file holds every phase and year of
+/// a run.
+fn write_catchments<W: std::io::Write, T: PhaseSchool>(writer: &mut Writer<W>, year: u32, schools: &[T], circles: &[assign::Circle], radials: &[assign::RadialArea], exclusive: &[f64]) -> Result<(), ScaError> {
+    assert!(schools.len() == circles.len() && circles.len() == radials.len() && radials.len() == exclusive.len(), "Each school needs one circle, radial and exclusive area");
+
+    for (((school, circle), radial), exclusive_area) in schools.iter().zip(circles).zip(radials).zip(exclusive.iter().copied()) {
+        writer.serialize(CatchmentRow {
+            phase: T::PHASE.name(),
+            year,
+            urn: school.urn(),
+            x_km: circle.origin.x,
+            y_km: circle.origin.y,
+            radius: circle.r,
+            target_area: radial.area,
+            exclusive_area,
+            residual: exclusive_area - radial.area,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The files written across all years of one phase.
+struct PhaseWriters {
+    complete: Writer<std::fs::File>,
+    // Schools excluded from radius scaling, with the first check they failed.
+    dropped: Writer<std::fs::File>,
+}
+
+impl PhaseWriters {
+    fn open(phase: Phase, output_dir: &Path) -> Result<Self, ScaError> {
+        Ok(Self {
+            complete: Writer::from_path(phase.output(output_dir, "all", "csv"))?,
+            dropped: Writer::from_path(phase.output(output_dir, "dropped", "csv"))?,
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), ScaError> {
+        self.complete.flush()?;
+        self.dropped.flush()?;
+        Ok(())
+    }
+}
+
+/// What `run_phase` reads for one year, shared by both phases.
+struct PhaseInputs<'a> {
+    input_dir: &'a Path,
+    output_dir: &'a Path,
+    year: u32,
+    ofsted: &'a HashMap<String, Vec<OfstedRecord>>,
+    regions: &'a HashMap<String, String>,
+    to_bng: Option<&'a Proj>,
+    default_density: Option<f64>,
+    order: OrderBy,
+    per_year: bool,
+}
+
+/// The outputs shared by both phases.
+struct PhaseOutputs<'a> {
+    manifest: &'a mut manifest::Manifest,
+    no_ofsted: &'a mut Writer<std::fs::File>,
+    catchments: &'a mut Writer<std::fs::File>,
+    #[cfg(feature = "sqlite")]
+    db: &'a mut rusqlite::Connection,
+}
+
+/// Parses, projects and scales one year of `T`'s phase, writing its records, catchments and maps, and returns
+/// the catchments. A school file that fails to parse is reported and skipped.
+fn run_phase<T: PhaseSchool>(inputs: &PhaseInputs, writers: &mut PhaseWriters, outputs: &mut PhaseOutputs) -> Result<Vec<assign::Circle>, ScaError> {
+    let phase = T::PHASE;
+    let i = inputs.year;
+    let fname = phase.input(inputs.input_dir, i);
+    outputs.manifest.add_input(&fname);
+
+    let (schools, match_rate) = match parse_dset::<_, T::Raw>(fname, i, inputs.ofsted, inputs.regions, Some(&mut *outputs.no_ofsted), SCHOOL_ENCODING) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Failed to parse school: {}", e);
+            return Ok(Vec::new());
         }
-        // Primary
-        {
-            let fname = format!("san_scrawp_{}.csv", i);
-
-            match parse_dset::<String, PSchoolRecord>(fname, &ofsted, &regions) {
-                Ok(schools) => {
-                    let mut ag_schools = Vec::with_capacity(schools.len());
-                    for school in schools {
-                        let rwm_ta = percentage_string_to_float(&school.record.rwm_ta).ok();
-                        let rwm_ta_dis = percentage_string_to_float(&school.record.rwm_ta_dis).ok();
-                        let loc = geo_data(&school.record.pcode, &mut geo_map, &geonames_data);
-
-                        let pos = if let (Some(lat), Some(long)) = (
-                            school.record.lat.parse::<f64>().ok(),
-                            school.record.long.parse::<f64>().ok(),
-                        ) {
-                            to_bng
-                                .convert((long, lat))
-                                .map(|(x, y)| (x / 1000.0, y / 1000.0)) // Convert to kms
-                                .ok()
-                        } else {
-                            None
-                        };
-
-                        // Only choose the right kind of schools.
-                        let state =
-                            TARGET_SCHOOL_TYPES.contains(&school.record.school_type.as_str());
-                        ag_schools.push(AggregatePSchoolRecord {
-                            year: i,
-                            name: school.record.name.clone(),
-                            pcode: school.record.pcode.clone(),
-                            lat: school.record.lat.parse::<f64>().ok(),
-                            lng: school.record.long.parse::<f64>().ok(),
-                            target_prop: school.record.target_prop.parse::<f64>().ok(),
-                            pop: school.record.pop.parse::<f32>().map(|x| x as u32).ok(),
-                            x_km: pos.map(|(x, _)| x),
-                            y_km: pos.map(|(_, y)| y),
-                            target_density: school.record.target_density.parse().ok(),
-                            radius: None, // Will allocate once we order by quality.
-                            urn: school.record.urn.clone(),
-                            is_state: state as u32,
-                            school_type: school.record.school_type.clone(),
-                            lad: school.lad,
-                            of_overall: school.ofsted.as_ref().and_then(|x| x.overall),
-                            of_behaviour: school.ofsted.as_ref().and_then(|x| x.behaviour),
-                            of_educ: school.ofsted.as_ref().and_then(|x| x.educ),
-                            of_pdev: school.ofsted.as_ref().and_then(|x| x.pdev),
-                            rwm_ta,
-                            rwm_ta_dis,
-                        });
-                    }
+    };
+    println!("Matched {:.1}% of {} {} schools to Ofsted", 100.0 * match_rate, i, phase.name());
+    outputs.manifest.records_read += schools.len();
 
-                    // Remove schools without the stuff we need to calculate radius.
-                    let (drained, mut ag_schools): (Vec<_>, Vec<_>) =
-                        ag_schools.into_iter().partition(|r| {
-                            (r.rwm_ta.is_none()
-                                || r.x_km.is_none()
-                                || r.y_km.is_none()
-                                || r.target_density.is_none()
-                                || r.pop.is_none()
-                                || r.target_prop.is_none()
-                                || r.is_state == 0)
-                        });
-
-                    println!("ag: {}", ag_schools.len());
-
-                    ag_schools
-                        .sort_by(|a, b| b.rwm_ta.unwrap().partial_cmp(&a.rwm_ta.unwrap()).unwrap());
-
-                    // First sort schools by quality. Ordering matches ag_schools one to one.
-                    let radials: Vec<assign::RadialArea> = ag_schools
-                        .iter()
-                        .filter_map(|r| {
-                            Some(assign::RadialArea {
-                                origin: Vector2::new(r.x_km.unwrap(), r.y_km.unwrap()),
-                                area: r.pop.unwrap() as f64
-                                    / (r.target_density.unwrap() * r.target_prop.unwrap()),
-                            })
-                        })
-                        .collect();
-
-                    let circles = assign::scale_all(&radials, 0.5, 1e-3, 1000)
-                        .ok_or("Failed to scale radials!")?;
-
-                    for (school, circle) in ag_schools.iter_mut().zip(circles.iter()) {
-                        school.radius = Some(circle.r);
-                    }
+    let mut ag_schools = Vec::with_capacity(schools.len());
+    for school in schools {
+        let pos = school.location.as_ref().and_then(|loc| to_bng_km(inputs.to_bng?, loc.latitude, loc.longitude));
 
-                    for school in ag_schools {
-                        complete_writer_prim.serialize(&school)?;
-                    }
+        ag_schools.push(T::aggregate(i, school, pos, inputs.default_density));
+    }
 
-                    for school in drained {
-                        complete_writer_prim.serialize(&school)?;
-                    }
-                    println!("parsed schools {}", i);
+    let scaled = scale_schools(ag_schools, inputs.order)?;
+    let circles: Vec<assign::Circle> = scaled.iter().filter_map(|(_, c)| *c).collect();
+    // The most spread out LAD, often one with a school placed far from the rest.
+    let widest = lad_hulls(scaled.iter().map(|(s, _)| s)).into_iter().map(|(lad, hull)| (assign::hull_area(&hull), lad)).max_by(|a, b| a.0.total_cmp(&b.0));
+    if let Some((area, lad)) = widest {
+        println!("{} {}: widest LAD hull {} ({:.1} km2)", phase.name(), i, lad, area);
+    }
 
-                    render::draw_circles_to_png(&circles, 1000, 1000, "map_prim.png");
+    let catchment_schools: Vec<T> = scaled.iter().filter(|(_, c)| c.is_some()).map(|(s, _)| s.clone()).collect();
+    let radials: Vec<assign::RadialArea> = catchment_schools.iter().filter_map(|s| s.radial()).collect();
+    // One overlap pass for both the conservation check and the catchment rows.
+    let exclusive = assign::sequential_exclusive_areas(&circles);
+    assign::validate_area_conservation(&exclusive, &radials);
+    let residuals: Vec<(assign::Circle, f64)> = circles.iter().zip(exclusive.iter().zip(&radials)).map(|(c, (a, r))| (*c, (a - r.area).abs())).collect();
+    if let Some(stats) = assign::radius_stats(&residuals, SCALE_EPSILON) {
+        println!("{} {}: {}", phase.name(), i, stats);
+    }
+    write_catchments(outputs.catchments, i, &catchment_schools, &circles, &radials, &exclusive)?;
 
-                    println!("parsed pschools {}", i);
-                }
-                Err(e) => println!("Failed to parse school: {}", e),
-            }
+    #[cfg(feature = "sqlite")]
+    T::write_sqlite(outputs.db, i, &scaled)?;
+
+    let mut year_writer = inputs.per_year.then(|| Writer::from_path(inputs.output_dir.join(format!("{}_{}.csv", phase.name(), i)))).transpose()?;
+    outputs.manifest.records_written += scaled.len();
+    for (school, circle) in scaled {
+        if circle.is_none() {
+            writers.dropped.serialize((&school, DropReason::of(school.drop_reason())))?;
         }
+        writers.complete.serialize(&school)?;
+        if let Some(w) = year_writer.as_mut() {
+            w.serialize(&school)?;
+        }
+    }
+    if let Some(mut w) = year_writer {
+        w.flush()?;
+    }
+    // A year at a time, so a later failure keeps the years already done.
+    writers.flush()?;
+    outputs.catchments.flush()?;
+    println!("parsed {} schools {}", phase.name(), i);
+
+    render::draw_circles_to_png(&circles, 1000, 1000, &phase.output(inputs.output_dir, "map", "png").to_string_lossy());
+    if let Err(e) = render::draw_coverage_heatmap(&circles, 1000, 1000, &inputs.output_dir.join(format!("map_{}_coverage.png", phase.name())).to_string_lossy()) {
+        println!("Failed to render coverage: {}", e);
     }
 
+    Ok(circles)
+}
+
+/// `default_density` is used for schools whose own target density is missing, instead of dropping them.
+/// `order` picks which schools claim catchment area first.
+/// With `per_year`, each year's schools are also written to `sec_<year>.csv` and `prim_<year>.csv`.
+fn run_schools(years: std::ops::Range<u32>, default_density: Option<f64>, order: OrderBy, per_year: bool) -> Result<(), ScaError> {
+    run_schools_in(Path::new("."), Path::new("."), years, default_density, order, per_year)
+}
+
+/// `run_schools`, reading its inputs from `input_dir` and writing the CSVs and maps to `output_dir`.
+fn run_schools_in(input_dir: &Path, output_dir: &Path, years: std::ops::Range<u32>, default_density: Option<f64>, order: OrderBy, per_year: bool) -> Result<(), ScaError> {
+    let mut manifest = manifest::Manifest::new("run_schools", &years);
+    manifest.phases = PhaseSet::Both.names();
+    manifest.add_input(input_dir.join("postcodes.csv"));
+    manifest.add_input(input_dir.join("ofsted.csv"));
+
+    let regions = load_regions(input_dir.join("postcodes.csv"))?;
+    let ofsted = load_ofsted(input_dir.join("ofsted.csv"))?;
+
+    println!("parsed postcodes, {}", regions.len());
+    let mut sec = PhaseWriters::open(Phase::Secondary, output_dir)?;
+    let mut prim = PhaseWriters::open(Phase::Primary, output_dir)?;
+
+    // Schools whose URN is missing from the Ofsted data, to monitor the join.
+    let mut no_ofsted_writer = Writer::from_path(output_dir.join("no_ofsted.csv"))?;
+    // The area accounting of every phase and year's catchments.
+    let mut catchments_writer = Writer::from_path(output_dir.join("catchments.csv"))?;
+
+    // Secondaries and all catchments in one file for QGIS.
+    #[cfg(feature = "sqlite")]
+    let mut db = {
+        let db = rusqlite::Connection::open(output_dir.join("schools.sqlite"))?;
+        sqlite::create_tables(&db)?;
+        db
+    };
+
+    // Without a transform schools keep lat/lng but get no x_km/y_km, so are dropped from scaling.
+    let to_bng = match new_transform(WGS84, "EPSG:27700") {
+        Ok(x) => Some(x),
+        Err(e) => {
+            println!("{}. Skipping projection, so every school will be dropped for missing location.", e);
+            None
+        }
+    };
+
+    for i in years.clone() {
+        // let fname: String = format!("scraw_{}.csv", i);
+        // sanitize(&fname, &format!("san_{}", &fname));
+        //
+        // let fname: String = format!("scrawp_{}.csv", i);
+        // sanitize(&fname, &format!("san_{}", &fname));
+        let inputs = PhaseInputs {
+            input_dir,
+            output_dir,
+            year: i,
+            ofsted: &ofsted,
+            regions: &regions,
+            to_bng: to_bng.as_ref(),
+            default_density,
+            order,
+            per_year,
+        };
+        let mut outputs = PhaseOutputs {
+            manifest: &mut manifest,
+            no_ofsted: &mut no_ofsted_writer,
+            catchments: &mut catchments_writer,
+            #[cfg(feature = "sqlite")]
+            db: &mut db,
+        };
+        let sec_circles = run_phase::<AggregateSchoolRecord>(&inputs, &mut sec, &mut outputs)?;
+        let prim_circles = run_phase::<AggregatePSchoolRecord>(&inputs, &mut prim, &mut outputs)?;
+
+        let layers = vec![
+            (sec_circles, image::Rgb([40u8, 90, 200]), "Secondary".to_owned()),
+            (prim_circles, image::Rgb([230u8, 120, 30]), "Primary".to_owned()),
+        ];
+        if let Err(e) = render::draw_layers(&layers, 1000, 1000, &output_dir.join("map_layers.png").to_string_lossy()) {
+            println!("Failed to render layers: {}", e);
+        }
+    }
+    no_ofsted_writer.flush()?;
+    catchments_writer.flush()?;
+
+    manifest.write(output_dir.join("manifest.json"))?;
+
     Ok(())
 }
 
-use crate::atomic::{geo_data, load_geo_data};
 use csv::ReaderBuilder;
 use nalgebra::Vector2;
 use proj::Proj;
@@ -754,7 +1523,82 @@ fn combine_csv_files(input_folder: &str, output_file: &str) -> Result<(), Box<dy
     Ok(())
 }
 
+/// Which of the rows sharing a key `combine_csv_dedup` keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeepRow {
+    First,
+    Last,
+}
+
+/// Like `combine_csv_files`, but writes one row per value of `key_columns`. Files are read in name order so
+/// that `keep` is well defined. With `sort` the output is ordered by the key columns (compared as text),
+/// otherwise each row goes where its key first appeared.
+fn combine_csv_dedup(input_folder: &str, output_file: &str, key_columns: &[&str], keep: KeepRow, sort: bool) -> Result<(), Box<dyn Error>> {
+    let mut paths: Vec<_> = std::fs::read_dir(input_folder)?
+        .filter_map(|entry| entry.ok().map(|x| x.path()))
+        .filter(|path| path.extension().and_then(|s| s.to_str()) == Some("csv"))
+        .collect();
+    paths.sort();
+
+    let mut headers: Option<csv::StringRecord> = None;
+    let mut key_idx: Vec<usize> = Vec::new();
+    let mut rows: Vec<(Vec<String>, csv::StringRecord)> = Vec::new();
+    let mut seen: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut duplicates = 0;
+
+    for path in paths {
+        let mut reader = ReaderBuilder::new().has_headers(true).from_path(&path)?;
+        if headers.is_none() {
+            let h = reader.headers()?.clone();
+            key_idx = key_columns
+                .iter()
+                .map(|col| h.iter().position(|x| x == *col).ok_or_else(|| format!("no column {} in {}", col, path.display())))
+                .collect::<Result<_, _>>()?;
+            headers = Some(h);
+        }
+
+        for result in reader.records() {
+            let record = result?;
+            let key: Vec<String> = key_idx.iter().map(|i| record.get(*i).unwrap_or("").to_owned()).collect();
+            match seen.get(&key) {
+                Some(&i) => {
+                    duplicates += 1;
+                    if keep == KeepRow::Last {
+                        rows[i].1 = record;
+                    }
+                }
+                None => {
+                    seen.insert(key.clone(), rows.len());
+                    rows.push((key, record));
+                }
+            }
+        }
+    }
+
+    if sort {
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    let mut writer = Writer::from_path(output_file)?;
+    if let Some(headers) = headers {
+        writer.write_record(&headers)?;
+    }
+    for (_, record) in rows {
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    println!("Combined {}, dropping {} duplicate rows", output_file, duplicates);
+    Ok(())
+}
+
 fn sanitize<P: AsRef<Path>>(path: P, out: P) -> Result<(), Box<dyn Error>> {
+    let mut writer = Writer::from_path(out)?;
+    sanitize_to(path, &mut writer)
+}
+
+/// `sanitize`, writing to any writer. Fails if any row can't be written.
+fn sanitize_to<P: AsRef<Path>, W: io::Write>(path: P, writer: &mut Writer<W>) -> Result<(), Box<dyn Error>> {
     let file = std::fs::File::open(path)?;
 
     let mut rdr = ReaderBuilder::new()
@@ -763,7 +1607,6 @@ fn sanitize<P: AsRef<Path>>(path: P, out: P) -> Result<(), Box<dyn Error>> {
         .from_reader(file);
     let headers = rdr.headers()?.len();
 
-    let mut writer = Writer::from_path(out)?;
     if let Ok(headers) = rdr.headers() {
         writer.write_record(headers)?;
     }
@@ -774,9 +1617,711 @@ fn sanitize<P: AsRef<Path>>(path: P, out: P) -> Result<(), Box<dyn Error>> {
                 record.push_field("");
             }
 
-            writer.write_record(&record);
+            writer.write_record(&record)?;
         }
     }
 
+    writer.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn school_record(lat: &str, long: &str) -> SchoolRecord {
+        SchoolRecord {
+            town: "Manchester".to_owned(),
+            pcode: "M1 1AA".to_owned(),
+            name: "Test School".to_owned(),
+            msoa: "E02001062".to_owned(),
+            lat: lat.to_owned(),
+            long: long.to_owned(),
+            target_density: String::new(),
+            target_prop: String::new(),
+            school_type: "CY".to_owned(),
+            adm_pol: "NSE".to_owned(),
+            urn: "100000".to_owned(),
+            pop: String::new(),
+            p8: String::new(),
+            ebacc: String::new(),
+            gcseg2: String::new(),
+            gcseg2_dis: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_school_location() {
+        let loc = school_record("53.4794", "-2.2453").get_location();
+        assert_eq!(loc, Some(GeoLocation { latitude: 53.4794, longitude: -2.2453 }));
+
+        assert_eq!(school_record("", "").get_location(), None);
+        assert_eq!(school_record("53.4794", "").get_location(), None);
+    }
+
+    pub(crate) fn aggregate_school() -> AggregateSchoolRecord {
+        AggregateSchoolRecord {
+            year: 2019,
+            lad: Some("Manchester".to_owned()),
+            lad_ofsted: Some("Manchester".to_owned()),
+            msoa: "E02001062".to_owned(),
+            name: "Test School".to_owned(),
+            pcode: "M1 1AA".to_owned(),
+            lat: Some(53.4794),
+            lng: Some(-2.2453),
+            x_km: Some(383.8),
+            y_km: Some(398.1),
+            radius: None,
+            catchment_area_km2: None,
+            catchment_area_ha: None,
+            target_density: Some(50.0),
+            target_prop: Some(0.1),
+            pop: Some(1000),
+            urn: "100000".to_owned(),
+            school_type: "CY".to_owned(),
+            is_state: 1,
+            is_selective: 0,
+            p8: String::new(),
+            ebacc: String::new(),
+            of_overall: Some(2),
+            of_educ: Some(2),
+            of_behaviour: Some(2),
+            of_pdev: Some(2),
+            of_sixthform: None,
+            gcseg2: Some(0.6),
+            gcseg2_dis: Some(0.4),
+        }
+    }
+
+    /// Where `aggregate_school` is, in central Manchester.
+    pub(crate) fn manchester() -> GeoLocation {
+        GeoLocation { latitude: 53.4794, longitude: -2.2453 }
+    }
+
+    /// One `aggregate_school` per `(dlat, gcseg2)`, `dlat` degrees north of `loc`, with URNs "0", "1", ... in order.
+    pub(crate) fn schools_north_of<G: Copy + Into<Option<f32>>>(loc: &GeoLocation, schools: &[(f64, G)]) -> Vec<AggregateSchoolRecord> {
+        schools
+            .iter()
+            .enumerate()
+            .map(|(i, (dlat, g))| AggregateSchoolRecord { urn: i.to_string(), lat: Some(loc.latitude + dlat), lng: Some(loc.longitude), gcseg2: (*g).into(), ..aggregate_school() })
+            .collect()
+    }
+
+    #[test]
+    fn test_drop_reason() {
+        let school = aggregate_school();
+        assert_eq!(school.drop_reason(), None);
+
+        let school = AggregateSchoolRecord { pop: None, ..aggregate_school() };
+        assert_eq!(school.drop_reason(), Some("missing pop"));
+
+        // Only the first failing check is reported.
+        let school = AggregateSchoolRecord { pop: None, is_selective: 1, ..aggregate_school() };
+        assert_eq!(school.drop_reason(), Some("missing pop"));
+    }
+
+    #[test]
+    fn test_classify_school() {
+        assert_eq!(classify_school("AC", "SEL", &TARGET_SCHOOL_TYPES), (false, true));
+        assert_eq!(classify_school("CY", "NSE", &TARGET_SCHOOL_TYPES), (true, false));
+        assert_eq!(classify_school("CY", "", &TARGET_SCHOOL_TYPES), (true, false));
+        assert_eq!(classify_school("IND", "NSE", &TARGET_SCHOOL_TYPES), (false, false));
+        assert_eq!(classify_school("IND", "SEL", &TARGET_SCHOOL_TYPES), (false, true));
+    }
+
+    #[test]
+    fn test_default_density() {
+        let info = || SchoolInfo {
+            record: SchoolRecord {
+                gcseg2: "60%".to_owned(),
+                target_prop: "0.1".to_owned(),
+                pop: "1000".to_owned(),
+                ..school_record("53.4794", "-2.2453")
+            },
+            ofsted: None,
+            lad: None,
+            lad_ofsted: None,
+            location: Some(GeoLocation { latitude: 53.4794, longitude: -2.2453 }),
+        };
+        let pos = Some((383.8, 398.1));
+
+        let school = aggregate_sec(2019, info(), pos, None);
+        assert_eq!(school.drop_reason(), Some("missing target_density"));
+
+        let school = aggregate_sec(2019, info(), pos, Some(40.0));
+        assert_eq!(school.target_density, Some(40.0));
+        assert_eq!(school.drop_reason(), None);
+
+        // A school's own density takes precedence.
+        assert_eq!(target_density("25", Some(40.0)), Some(25.0));
+    }
+
+    #[test]
+    fn test_zero_target_prop() {
+        let school = AggregateSchoolRecord { target_prop: Some(0.0), ..aggregate_school() };
+        assert_eq!(school.target_area(), Some(f64::INFINITY));
+        assert_eq!(school.drop_reason(), Some("non-positive target area"));
+
+        let school = AggregateSchoolRecord { pop: Some(0), ..aggregate_school() };
+        assert_eq!(school.drop_reason(), Some("non-positive target area"));
+
+        assert!(aggregate_school().target_area().unwrap() > 0.0);
+    }
+
+    /// Whether two CSV fields match, allowing numbers a little slack for projection differences between PROJ builds.
+    fn field_matches(a: &str, b: &str) -> bool {
+        match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => (a - b).abs() <= 1e-2 + 1e-4 * b.abs(),
+            _ => a == b,
+        }
+    }
+
+    #[test]
+    fn test_parse_dset_no_ofsted() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/run_schools");
+        let ofsted = load_ofsted(fixtures.join("ofsted.csv")).unwrap();
+
+        let path = std::env::temp_dir().join("scha_test_no_ofsted_schools.csv");
+        std::fs::write(&path, "TOWN,PCODE,SCHNAME,msoa11,lat,long,target_density,target_prop,NFTYPE,ADMPOL,URN,TOTPUPS,P8MEA,P8MEAEBAC,PTL2BASICS_94,PTFSM6CLA1ABASICS_94\n\
+            Manchester,M1 1AA,Alpha Academy,E02001062,53.4794,-2.2453,4000,0.1,AC,NSE,100001,1000,0.25,0.1,72%,55%\n\
+            Manchester,M1 2AB,New Academy,E02001062,53.4800,-2.2400,4000,0.1,AC,NSE,999999,800,,,60%,40%\n").unwrap();
+        let out = std::env::temp_dir().join("scha_test_no_ofsted.csv");
+        let mut writer = Writer::from_path(&out).unwrap();
+
+        let (schools, match_rate) = parse_dset::<_, SchoolRecord>(&path, 2019, &ofsted, &HashMap::new(), Some(&mut writer), encoding_rs::UTF_8).unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(schools.len(), 2);
+        assert_eq!(match_rate, 0.5);
+        assert!(schools[0].ofsted.is_some());
+        assert!(schools[1].ofsted.is_none());
+
+        let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&out).unwrap().records().map(|x| x.unwrap()).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][1], "999999");
+
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&out).ok();
+    }
+
+    #[test]
+    fn test_parse_dset_latin1() {
+        let path = std::env::temp_dir().join("scha_test_latin1_schools.csv");
+        let csv = "TOWN,PCODE,SCHNAME,msoa11,lat,long,target_density,target_prop,NFTYPE,ADMPOL,URN,TOTPUPS,P8MEA,P8MEAEBAC,PTL2BASICS_94,PTFSM6CLA1ABASICS_94\n\
+            Manchester,M1 1AA,\u{c9}cole Saint-Andr\u{e9} \u{2018}High\u{2019},E02001062,53.4794,-2.2453,4000,0.1,AC,NSE,100001,1000,0.25,0.1,72%,55%\n";
+        let (bytes, _, unmappable) = encoding_rs::WINDOWS_1252.encode(csv);
+        assert!(!unmappable);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (schools, _) = parse_dset::<_, SchoolRecord>(&path, 2019, &HashMap::new(), &HashMap::new(), None, encoding_rs::WINDOWS_1252).unwrap();
+        assert_eq!(schools.len(), 1);
+        assert_eq!(schools[0].record.name, "\u{c9}cole Saint-Andr\u{e9} \u{2018}High\u{2019}");
+
+        // Read as UTF-8 the row doesn't deserialize.
+        let (schools, _) = parse_dset::<_, SchoolRecord>(&path, 2019, &HashMap::new(), &HashMap::new(), None, encoding_rs::UTF_8).unwrap();
+        assert!(schools.is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_decode_reader() {
+        use std::io::Read;
+
+        // Buffers of a few bytes split the multi-byte characters between reads.
+        let text = "Saint-Andr\u{e9}, \u{1f3eb} and \u{2018}High\u{2019}\n".repeat(50);
+        let utf16: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let (latin1, _, _) = encoding_rs::WINDOWS_1252.encode("Saint-Andr\u{e9} \u{2018}High\u{2019}");
+        for capacity in [1, 3, 7, 4096] {
+            let mut decoded = String::new();
+            DecodeReader::new(utf16.as_slice(), encoding_rs::UTF_16LE, capacity).read_to_string(&mut decoded).unwrap();
+            assert_eq!(decoded, text, "{}", capacity);
+
+            let mut decoded = String::new();
+            DecodeReader::new(latin1.as_ref(), encoding_rs::WINDOWS_1252, capacity).read_to_string(&mut decoded).unwrap();
+            assert_eq!(decoded, "Saint-Andr\u{e9} \u{2018}High\u{2019}");
+        }
+
+        // A truncated sequence at the end is replaced rather than dropped.
+        let mut decoded = String::new();
+        DecodeReader::new(&utf16[..utf16.len() - 1], encoding_rs::UTF_16LE, 5).read_to_string(&mut decoded).unwrap();
+        assert!(decoded.ends_with('\u{fffd}'), "{:?}", &decoded[decoded.len() - 8..]);
+    }
+
+    #[test]
+    fn test_lad_mismatch() {
+        let path = std::env::temp_dir().join("scha_test_lad_mismatch.csv");
+        let csv = "TOWN,PCODE,SCHNAME,msoa11,lat,long,target_density,target_prop,NFTYPE,ADMPOL,URN,TOTPUPS,P8MEA,P8MEAEBAC,PTL2BASICS_94,PTFSM6CLA1ABASICS_94\n\
+            Salford,M6 6AP,Boundary High,E02001062,53.4880,-2.2900,4000,0.1,AC,NSE,100007,1000,0.25,0.1,72%,55%\n";
+        std::fs::write(&path, csv).unwrap();
+        let regions = HashMap::from([("M6 6AP".to_owned(), "Manchester".to_owned())]);
+        let ofsted_path = std::env::temp_dir().join("scha_test_lad_mismatch_ofsted.csv");
+        std::fs::write(&ofsted_path, "URN,Local authority,Overall effectiveness\n100007,Salford,2\n").unwrap();
+        let ofsted = load_ofsted(&ofsted_path).unwrap();
+
+        let (schools, _) = parse_dset::<_, SchoolRecord>(&path, 2019, &ofsted, &regions, None, SCHOOL_ENCODING).unwrap();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&ofsted_path).ok();
+        let school = aggregate_sec(2019, schools.into_iter().next().unwrap(), None, None);
+        assert_eq!(school.lad.as_deref(), Some("Manchester"));
+        assert_eq!(school.lad_ofsted.as_deref(), Some("Salford"));
+
+        // Both are written, and older files with a plain `lad` column still load.
+        let mut writer = Writer::from_writer(Vec::new());
+        writer.serialize(&school).unwrap();
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(written.starts_with("year,lad_postcode,lad_ofsted,"), "{}", written);
+        let old = written.replacen("lad_postcode,lad_ofsted,", "lad,", 1).replacen("Manchester,Salford,", "Manchester,", 1);
+        let read: AggregateSchoolRecord = csv::Reader::from_reader(old.as_bytes()).deserialize().next().unwrap().unwrap();
+        assert_eq!((read.lad.as_deref(), read.lad_ofsted), (Some("Manchester"), None));
+
+        assert!(same_lad(" manchester", "Manchester"));
+        assert!(!same_lad("Salford", "Manchester"));
+    }
+
+    /// Fails every write, like a full disk.
+    struct FailingWrite;
+
+    impl io::Write for FailingWrite {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sanitize_write_error() {
+        let path = std::env::temp_dir().join("scha_test_sanitize.csv");
+        std::fs::write(&path, "a,b,c\n1,2\n3,4,5\n").unwrap();
+
+        let mut writer = Writer::from_writer(FailingWrite);
+        let err = sanitize_to(&path, &mut writer).unwrap_err();
+        assert!(err.to_string().contains("disk full"), "{}", err);
+
+        let mut writer = Writer::from_writer(Vec::new());
+        sanitize_to(&path, &mut writer).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "a,b,c\n1,2,\n3,4,5\n");
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_ofsted_for_year() {
+        let path = std::env::temp_dir().join("scha_test_ofsted_dates.csv");
+        std::fs::write(&path, "URN,Local authority,Overall effectiveness,Quality of education,Behaviour and attitudes,Personal development,Sixth form provision (where applicable),Inspection start date
+100001,Manchester,1,1,1,1,,14/03/2023
+100001,Manchester,3,3,2,3,,2017-11-08
+100002,Trafford,2,2,2,2,,
+").unwrap();
+
+        let ofsted = load_ofsted(&path).unwrap();
+        let overall = |urn: &str, year| ofsted.get(urn).and_then(|x| ofsted_for_year(x, year)).and_then(|x| x.overall);
+
+        assert_eq!(ofsted["100001"].len(), 2);
+        assert_eq!(overall("100001", 2019), Some(3));
+        assert_eq!(overall("100001", 2023), Some(1));
+        assert_eq!(overall("100001", 2016), None);
+        // Undated inspections apply to any year.
+        assert_eq!(overall("100002", 2016), Some(2));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_reproject_records() {
+        let mut records = vec![
+            AggregateSchoolRecord { lat: Some(53.4794), lng: Some(-2.2453), x_km: None, y_km: None, ..aggregate_school() },
+            AggregateSchoolRecord { lat: None, lng: None, x_km: None, y_km: None, ..aggregate_school() },
+        ];
+
+        reproject_records(&mut records, "EPSG:4326", "EPSG:27700").unwrap();
+        let (x, y) = (records[0].x_km.unwrap(), records[0].y_km.unwrap());
+        assert!((x - 383.8).abs() < 1.0 && (y - 398.2).abs() < 1.0, "({}, {})", x, y);
+        assert_eq!(records[1].x_km, None);
+
+        records[0].lat = None;
+        records[0].lng = None;
+        reproject_records(&mut records, "EPSG:27700", "EPSG:4326").unwrap();
+        assert!((records[0].lat.unwrap() - 53.4794).abs() < 1e-6);
+        assert!((records[0].lng.unwrap() + 2.2453).abs() < 1e-6);
+        assert_eq!(records[1].lat, None);
+    }
+
+    #[test]
+    fn test_projection_error() {
+        assert!(matches!(new_transform(WGS84, "EPSG:not-a-crs"), Err(ScaError::Proj(_))));
+
+        let mut records = vec![aggregate_school()];
+        let err = reproject_records(&mut records, WGS84, "EPSG:not-a-crs").unwrap_err();
+        assert!(matches!(err, ScaError::Proj(_)));
+        assert!(err.to_string().contains("proj-data"), "{}", err);
+        assert_eq!(records[0].x_km, aggregate_school().x_km);
+    }
+
+    #[test]
+    fn test_to_bng_km() {
+        let to_bng = new_transform(WGS84, "EPSG:27700").unwrap();
+
+        // Manchester town hall.
+        let (x, y) = to_bng_km(&to_bng, 53.4794, -2.2453).unwrap();
+        assert!((0.0..700.0).contains(&x) && (0.0..1300.0).contains(&y), "({}, {})", x, y);
+        assert!((x - 383.8).abs() < 1.0 && (y - 398.1).abs() < 1.0, "({}, {})", x, y);
+
+        // London is south east of it.
+        let (lx, ly) = to_bng_km(&to_bng, 51.5072, -0.1275).unwrap();
+        assert!(lx > x && ly < y);
+    }
+
+    #[test]
+    fn test_scale_schools() {
+        let records = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.4), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), gcseg2: None, ..aggregate_school() },
+            AggregateSchoolRecord { urn: "3".to_owned(), gcseg2: Some(0.7), x_km: Some(385.5), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "4".to_owned(), is_selective: 1, ..aggregate_school() },
+        ];
+
+        let scaled = scale_schools(records.clone(), OrderBy::default()).unwrap();
+
+        let mut urns: Vec<&str> = scaled.iter().map(|(s, _)| s.urn.as_str()).collect();
+        assert_eq!(urns, ["3", "1", "2", "4"]);
+        urns.sort();
+        assert_eq!(urns, records.iter().map(|x| x.urn.as_str()).collect::<Vec<_>>());
+
+        for (school, circle) in scaled.iter() {
+            assert_eq!(circle.is_some(), school.drop_reason().is_none());
+            assert_eq!(school.radius, circle.map(|x| x.r));
+        }
+    }
+
+    #[test]
+    fn test_order_by() {
+        // Overlapping catchments, the better school by gcseg2 being the worse by Ofsted.
+        let records = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.7), of_overall: Some(3), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), gcseg2: Some(0.4), of_overall: Some(1), x_km: Some(386.8), ..aggregate_school() },
+        ];
+
+        let radii = |order: OrderBy| -> Vec<(String, f64)> {
+            let mut scaled: Vec<_> = scale_schools(records.clone(), order).unwrap().into_iter().map(|(s, _)| (s.urn, s.radius.unwrap())).collect();
+            scaled.sort_by(|a, b| a.0.cmp(&b.0));
+            scaled
+        };
+
+        let by_gcse = radii(OrderBy::Gcseg2);
+        let by_ofsted = radii(OrderBy::OfstedOverall);
+        // Whoever goes first keeps the plain target radius; the other grows into the overlap.
+        assert!(by_gcse[0].1 < by_gcse[1].1);
+        assert!(by_ofsted[0].1 > by_ofsted[1].1);
+        assert!((by_gcse[0].1 - by_ofsted[1].1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_combine_csv_dedup() {
+        let dir = std::env::temp_dir().join("scha_test_combine_dedup");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("2019_01.csv"), "id,date,price\nb,2019-01,200\na,2019-01,100\n").unwrap();
+        std::fs::write(dir.join("2019_02.csv"), "id,date,price\na,2019-01,150\nc,2019-02,300\n").unwrap();
+        let out = std::env::temp_dir().join("scha_test_combine_dedup.csv");
+        let out_str = out.to_str().unwrap();
+
+        let rows = || -> Vec<String> {
+            csv::Reader::from_path(&out).unwrap().records().map(|x| x.unwrap().iter().collect::<Vec<_>>().join(",")).collect()
+        };
+
+        combine_csv_dedup(dir.to_str().unwrap(), out_str, &["id", "date"], KeepRow::First, false).unwrap();
+        assert_eq!(rows(), ["b,2019-01,200", "a,2019-01,100", "c,2019-02,300"]);
+
+        combine_csv_dedup(dir.to_str().unwrap(), out_str, &["id", "date"], KeepRow::Last, true).unwrap();
+        assert_eq!(rows(), ["a,2019-01,150", "b,2019-01,200", "c,2019-02,300"]);
+
+        assert!(combine_csv_dedup(dir.to_str().unwrap(), out_str, &["missing"], KeepRow::First, false).is_err());
+    }
+
+    #[test]
+    fn test_catchment_areas() {
+        let pi = std::f64::consts::PI;
+        let mut school = aggregate_school();
+        school.set_radius(1.0);
+        assert_eq!(school.radius, Some(1.0));
+        assert!((school.catchment_area_km2.unwrap() - pi).abs() < 1e-12);
+        assert!((school.catchment_area_ha.unwrap() - 100.0 * pi).abs() < 1e-9);
+
+        let mut school = as_primary(&aggregate_school());
+        school.set_radius(2.0);
+        assert!((school.catchment_area_km2.unwrap() - 4.0 * pi).abs() < 1e-12);
+
+        // Scaling fills them in from the solved radius, and leaves dropped schools without.
+        let records = vec![aggregate_school(), AggregateSchoolRecord { gcseg2: None, ..aggregate_school() }];
+        let scaled = scale_schools(records, OrderBy::default()).unwrap();
+        let r = scaled[0].0.radius.unwrap();
+        assert_eq!(scaled[0].0.catchment_area_km2, Some(pi * r * r));
+        assert_eq!(scaled[1].0.catchment_area_ha, None);
+    }
+
+    #[test]
+    fn test_lad_hulls() {
+        let at = |lad: &str, x: f64, y: f64| AggregateSchoolRecord { lad: Some(lad.to_owned()), x_km: Some(x), y_km: Some(y), ..aggregate_school() };
+        let schools = vec![
+            at("Trafford", 0.0, 0.0),
+            at("Manchester", 0.0, 0.0),
+            at("Manchester", 2.0, 0.0),
+            at("Manchester", 0.0, 2.0),
+            AggregateSchoolRecord { x_km: None, ..at("Manchester", 9.0, 9.0) },
+        ];
+        let hulls = lad_hulls(&schools);
+        assert_eq!(hulls.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["Manchester", "Trafford"]);
+        assert_eq!(hulls[0].1.len(), 3);
+        assert_eq!(assign::hull_area(&hulls[0].1), 2.0);
+        assert_eq!(hulls[1].1, vec![Vector2::new(0.0, 0.0)]);
+    }
+
+    #[test]
+    fn test_write_catchments() {
+        let records = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.7), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), gcseg2: Some(0.4), x_km: Some(386.8), pop: Some(500), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "3".to_owned(), gcseg2: None, ..aggregate_school() },
+        ];
+        let (schools, circles): (Vec<_>, Vec<_>) = scale_schools(records, OrderBy::default())
+            .unwrap()
+            .into_iter()
+            .filter_map(|(s, c)| Some((s, c?)))
+            .unzip();
+        let radials: Vec<assign::RadialArea> = schools.iter().map(|s| s.radial().unwrap()).collect();
+
+        // Two years appended to one file.
+        let path = std::env::temp_dir().join("scha_test_catchments.csv");
+        let mut writer = Writer::from_path(&path).unwrap();
+        let exclusive = assign::sequential_exclusive_areas(&circles);
+        write_catchments(&mut writer, 2019, &schools, &circles, &radials, &exclusive).unwrap();
+        write_catchments(&mut writer, 2020, &schools, &circles, &radials, &exclusive).unwrap();
+        writer.flush().unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let col = |name: &str| headers.iter().position(|x| x == name).unwrap();
+        let rows: Vec<csv::StringRecord> = reader.records().map(|x| x.unwrap()).collect();
+
+        assert_eq!(rows.len(), 2 * schools.len());
+        let years: Vec<&str> = rows.iter().map(|x| &x[col("year")]).collect();
+        assert_eq!(years, [vec!["2019"; schools.len()], vec!["2020"; schools.len()]].concat());
+        assert!(rows.iter().all(|x| &x[col("phase")] == "sec"));
+        for (row, radial) in rows.iter().zip(radials.iter().chain(radials.iter())) {
+            let target: f64 = row[col("target_area")].parse().unwrap();
+            assert!((target - radial.area).abs() < 1e-9);
+            let residual: f64 = row[col("residual")].parse().unwrap();
+            assert!(residual.abs() < 0.01, "{}", residual);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    /// The primary with the same location, intake and metric as `school`.
+    pub(crate) fn as_primary(school: &AggregateSchoolRecord) -> AggregatePSchoolRecord {
+        AggregatePSchoolRecord {
+            year: school.year,
+            lad: school.lad.clone(),
+            lad_ofsted: school.lad_ofsted.clone(),
+            name: school.name.clone(),
+            pcode: school.pcode.clone(),
+            lat: school.lat,
+            lng: school.lng,
+            pop: school.pop,
+            x_km: school.x_km,
+            y_km: school.y_km,
+            radius: None,
+            catchment_area_km2: None,
+            catchment_area_ha: None,
+            target_density: school.target_density,
+            target_prop: school.target_prop,
+            urn: school.urn.clone(),
+            school_type: school.school_type.clone(),
+            is_state: school.is_state,
+            rwm_ta: school.gcseg2,
+            rwm_ta_dis: school.gcseg2_dis,
+            of_overall: school.of_overall,
+            of_educ: school.of_educ,
+            of_behaviour: school.of_behaviour,
+            of_pdev: school.of_pdev,
+        }
+    }
+
+    #[test]
+    fn test_phases_scale_identically() {
+        let sec = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), gcseg2: Some(0.4), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), gcseg2: Some(0.7), x_km: Some(384.5), pop: Some(500), ..aggregate_school() },
+            AggregateSchoolRecord { urn: "3".to_owned(), gcseg2: None, ..aggregate_school() },
+        ];
+        let prim: Vec<AggregatePSchoolRecord> = sec.iter().map(as_primary).collect();
+        assert_eq!(sec.iter().map(|x| x.quality_metric()).collect::<Vec<_>>(), prim.iter().map(|x| x.quality_metric()).collect::<Vec<_>>());
+
+        for order in [OrderBy::Gcseg2, OrderBy::OfstedOverall] {
+            let sec = scale_schools(sec.clone(), order).unwrap();
+            let prim = scale_schools(prim.clone(), order).unwrap();
+            assert_eq!(sec.len(), prim.len());
+            for ((s, sc), (p, pc)) in sec.iter().zip(prim.iter()) {
+                assert_eq!(s.urn, p.urn);
+                assert_eq!(s.radius, p.radius);
+                assert_eq!(sc, pc);
+                assert_eq!(PhaseSchool::drop_reason(s).is_some(), PhaseSchool::drop_reason(p).is_some());
+            }
+        }
+    }
+
+    /// Runs `run_schools` over the fixtures and compares `all_sec.csv` with the golden file.
+    /// Set `SCHA_BLESS=1` to regenerate the golden file instead.
+    #[test]
+    fn test_run_schools_golden() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/run_schools");
+        let golden = fixtures.join("all_sec.golden.csv");
+        let out = std::env::temp_dir().join("scha_test_run_schools");
+        std::fs::create_dir_all(&out).unwrap();
+
+        run_schools_in(&fixtures, &out, 2019..2020, None, OrderBy::default(), false).unwrap();
+
+        if std::env::var_os("SCHA_BLESS").is_some() {
+            std::fs::copy(out.join("all_sec.csv"), &golden).unwrap();
+        }
+
+        let manifest: manifest::Manifest = serde_json::from_reader(std::fs::File::open(out.join("manifest.json")).unwrap()).unwrap();
+        let rows = |name: &str| csv::Reader::from_path(out.join(name)).unwrap().records().count();
+        assert_eq!(manifest.records_written, rows("all_sec.csv") + rows("all_prim.csv"));
+        assert!(manifest.records_read >= manifest.records_written);
+        assert_eq!(manifest.inputs.len(), 4);
+        assert!(manifest.inputs.iter().all(|x| x.size.is_some()));
+
+        let mut actual = csv::Reader::from_path(out.join("all_sec.csv")).unwrap();
+        let mut expected = csv::Reader::from_path(&golden).unwrap();
+        assert_eq!(actual.headers().unwrap(), expected.headers().unwrap());
+
+        let actual: Vec<csv::StringRecord> = actual.records().map(|x| x.unwrap()).collect();
+        let expected: Vec<csv::StringRecord> = expected.records().map(|x| x.unwrap()).collect();
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!(a.len() == e.len() && a.iter().zip(e.iter()).all(|(a, e)| field_matches(a, e)), "{:?} != {:?}", a, e);
+        }
+
+        std::fs::remove_dir_all(&out).ok();
+    }
+
+    #[test]
+    fn test_normalize_postcode() {
+        for s in ["SW1A 1AA", "SW1A1AA", "sw1a 1aa", " SW1A  1AA ", "SW1A\t1AA", "Sw1A1aA"] {
+            assert_eq!(normalize_postcode(s), "SW1A 1AA", "{:?}", s);
+        }
+        assert_eq!(normalize_postcode("m1 1aa"), "M1 1AA");
+        assert_eq!(normalize_postcode(" m1 "), "M1");
+
+        let path = std::env::temp_dir().join("scha_test_regions.csv");
+        std::fs::write(&path, "pcd,lad23cd,lad23nm\nM11AA,E08000003,Manchester\nsw1a 1aa,E09000033,Westminster\n").unwrap();
+        let regions = load_regions(&path).unwrap();
+        assert_eq!(regions.get(&normalize_postcode("M1 1AA")).map(|x| x.as_str()), Some("Manchester"));
+        assert_eq!(regions.get(&normalize_postcode("SW1A1AA")).map(|x| x.as_str()), Some("Westminster"));
+        std::fs::remove_file(&path).ok();
+    }
+
+    /// Nothing in a run draws random numbers: coincident centres are nudged by golden angle steps, sampled
+    /// intersections use grid centres and ties break on URN. So two runs give byte-identical output.
+    #[test]
+    fn test_reproducible_runs() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/run_schools");
+        let run = |name: &str| {
+            let out = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&out).unwrap();
+            run_schools_in(&fixtures, &out, 2019..2020, None, OrderBy::default(), false).unwrap();
+            out
+        };
+        let (a, b) = (run("scha_test_reproducible_a"), run("scha_test_reproducible_b"));
+        for name in ["all_sec.csv", "all_prim.csv", "dropped_sec.csv", "catchments.csv", "map_sec.png", "map_layers.png"] {
+            assert_eq!(std::fs::read(a.join(name)).unwrap(), std::fs::read(b.join(name)).unwrap(), "{}", name);
+        }
+        std::fs::remove_dir_all(&a).ok();
+        std::fs::remove_dir_all(&b).ok();
+
+        // Coincident centres scaled with the sampled intersection.
+        let radials: Vec<assign::RadialArea> = [(0.0, 0.0), (0.0, 0.0), (0.8, 0.1), (0.0, 0.0)]
+            .iter()
+            .map(|&(x, y)| assign::RadialArea { origin: Vector2::new(x, y), area: 1.0 })
+            .collect();
+        let scale = || assign::scale(&radials, assign::ScaleMethod::Sequential, assign::IntersectionMethod::Sampled { samples: 200 }, 0.5, 1e-3, 1000).unwrap();
+        let bits = |circles: Vec<assign::Circle>| circles.iter().flat_map(|c| [c.origin.x, c.origin.y, c.r].map(f64::to_bits)).collect::<Vec<u64>>();
+        assert_eq!(bits(scale()), bits(scale()));
+    }
+
+    #[test]
+    fn test_run_schools_per_year() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/run_schools");
+        let out = std::env::temp_dir().join("scha_test_run_schools_per_year");
+        std::fs::create_dir_all(&out).unwrap();
+
+        run_schools_in(&fixtures, &out, 2018..2020, None, OrderBy::default(), true).unwrap();
+
+        let rows = |name: &str| csv::Reader::from_path(out.join(name)).unwrap().records().count();
+        for phase in ["sec", "prim"] {
+            let per_year: usize = (2018..2020)
+                .map(|year| out.join(format!("{}_{}.csv", phase, year)))
+                .filter(|path| path.exists())
+                .map(|path| csv::Reader::from_path(path).unwrap().records().count())
+                .sum();
+            assert_eq!(per_year, rows(&format!("all_{}.csv", phase)));
+        }
+        assert!(out.join("sec_2019.csv").exists());
+
+        std::fs::remove_dir_all(&out).ok();
+    }
+
+    #[test]
+    fn test_scaler_serde_merge() {
+        let mut a = Scaler::new();
+        a.add(1.0, 1.0);
+        a.add(2.0, 3.0);
+        let mut b = Scaler::new();
+        b.add(4.0, 2.0);
+        b.add(9.0, 0.0);
+
+        let json = serde_json::to_string(&a).unwrap();
+        let mut restored: Scaler = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, a);
+
+        restored.merge(b);
+        let expected = (1.0 * 1.0 + 2.0 * 3.0 + 4.0 * 2.0) / 6.0;
+        assert!((restored.ave().unwrap() - expected).abs() < 1e-6);
+
+        let mut empty = Scaler::new();
+        empty.merge(Scaler::new());
+        assert_eq!(empty.ave(), None);
+    }
+
+    #[test]
+    fn test_scaler_non_finite() {
+        let mut scaler = Scaler::new();
+        scaler.add(1.0, 1.0);
+        scaler.add(f32::NAN, 1.0);
+        scaler.add(f32::INFINITY, 1.0);
+        scaler.add(3.0, f32::INFINITY);
+        scaler.add(3.0, 1.0);
+        assert_eq!(scaler.ave(), Some(2.0));
+        assert_eq!(scaler.median(), Some(2.0));
+
+        assert_eq!(percentage_string_to_float("45%"), Some(0.45));
+        assert_eq!(percentage_string_to_float("NaN"), None);
+        assert_eq!(percentage_string_to_float("inf%"), None);
+        assert_eq!(target_density("NaN", Some(2.0)), Some(2.0));
+    }
+
+    #[test]
+    fn test_geo_scaler() {
+        let mut scaler = GeoScaler::new();
+        assert!(scaler.centroid().is_none());
+
+        scaler.add(&GeoLocation { latitude: 53.0, longitude: -2.0 }, 0.5);
+        scaler.add(&GeoLocation { latitude: 54.0, longitude: -3.0 }, 0.5);
+        scaler.add(&GeoLocation { latitude: 60.0, longitude: 0.0 }, 0.0);
+        let c = scaler.centroid().unwrap();
+        assert!((c.latitude - 53.5).abs() < 1e-9);
+        assert!((c.longitude + 2.5).abs() < 1e-9);
+        assert_eq!(scaler.total_weight(), 1.0);
+    }
+}
@@ -0,0 +1,62 @@
+//! A `manifest.json` written at the end of each run, tying its outputs back to the inputs that produced them.
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io, ops::Range, path::Path, time::UNIX_EPOCH};
+
+use crate::error::ScaError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputFile {
+    pub path: String,
+    /// `None` if the file could not be read.
+    pub size: Option<u64>,
+    /// Seconds since the Unix epoch.
+    pub modified: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub run: String,
+    pub version: String,
+    pub year_start: u32,
+    pub year_end: u32,
+    pub inputs: Vec<InputFile>,
+    pub records_read: usize,
+    pub records_written: usize,
+    /// The school phases computed ("sec", "prim"). The other phase's columns are left empty by design.
+    #[serde(default)]
+    pub phases: Vec<String>,
+}
+
+impl Manifest {
+    pub fn new(run: &str, years: &Range<u32>) -> Self {
+        Self {
+            run: run.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            year_start: years.start,
+            year_end: years.end,
+            inputs: Vec::new(),
+            records_read: 0,
+            records_written: 0,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Records an input file with its size and modification time, if it can be read.
+    pub fn add_input<P: AsRef<Path>>(&mut self, path: P) {
+        let metadata = std::fs::metadata(&path).ok();
+        self.inputs.push(InputFile {
+            path: path.as_ref().to_string_lossy().into_owned(),
+            size: metadata.as_ref().map(|x| x.len()),
+            modified: metadata
+                .and_then(|x| x.modified().ok())
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs()),
+        });
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), ScaError> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self).map_err(io::Error::from)?;
+        Ok(())
+    }
+}
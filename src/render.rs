@@ -1,7 +1,8 @@
-use image::{ImageBuffer, Rgb, Rgba};
+use image::{ImageBuffer, Rgb, RgbImage, Rgba};
 use nalgebra::{Vector2, Vector3};
-use std::f64::consts::PI;
-use crate::assign::Circle;
+use std::{error::Error, f64::consts::PI};
+use crate::assign::{BBox, Circle};
+use crate::intersect::grid_centre;
 
 fn vector_to_rgb(vec: Vector3<f32>) -> Rgb<u8> {
     let r = (vec.x.clamp(0.0, 1.0) * 255.0).round() as u8;
@@ -10,42 +11,47 @@ fn vector_to_rgb(vec: Vector3<f32>) -> Rgb<u8> {
     Rgb([r, g, b])
 }
 
+/// Gradient colors from red (first circle) to blue (last circle), used when no colors are supplied.
+pub fn gradient_colors(n: usize) -> Vec<Rgb<u8>> {
+    let c0 = Vector3::new(1.0, 0.0, 0.0);
+    let c1 = Vector3::new(0.0, 0.0, 1.0);
+
+    let len = n as f32;
+    (0..n).map(|i| {
+        let s = i as f32 / len;
+        vector_to_rgb(c0 * s + c1 * (1.0 - s))
+    }).collect()
+}
+
 /// Draws the given circles to a PNG image at `output_path`. The image will be
 /// width x height, and the circles will be normalized to fill the image as much as possible.
 pub fn draw_circles_to_png(circles: &[Circle], width: u32, height: u32, output_path: &str) {
-    if circles.is_empty() {
-        let img = ImageBuffer::from_fn(width, height, |_x, _y| Rgb([255u8, 255u8, 255u8]));
-        img.save(output_path).unwrap();
-        return;
-    }
-
-    // Compute bounding box
-    let (min_x, min_y, max_x, max_y) = {
-        let mut min_x = f64::INFINITY;
-        let mut min_y = f64::INFINITY;
-        let mut max_x = f64::NEG_INFINITY;
-        let mut max_y = f64::NEG_INFINITY;
+    let colors = gradient_colors(circles.len());
+    draw_circles_with_colors(circles, &colors, Rgb([255u8, 255u8, 255u8]), width, height, output_path).unwrap();
+}
 
-        for c in circles {
-            let x0 = c.origin.x - c.r;
-            let x1 = c.origin.x + c.r;
-            let y0 = c.origin.y - c.r;
-            let y1 = c.origin.y + c.r;
+/// Same as `draw_circles_to_png` but with one supplied color per circle and a custom background.
+pub fn draw_circles_with_colors(circles: &[Circle], colors: &[Rgb<u8>], background: Rgb<u8>, width: u32, height: u32, output_path: &str) -> Result<(), Box<dyn Error>> {
+    let img = render_circles(circles, colors, background, width, height)?;
+    img.save(output_path)?;
+    Ok(())
+}
 
-            if x0 < min_x { min_x = x0; }
-            if x1 > max_x { max_x = x1; }
-            if y0 < min_y { min_y = y0; }
-            if y1 > max_y { max_y = y1; }
-        }
+/// Maps the circles into image coordinates, normalized to fill a width x height image as much as possible.
+/// Returns `None` when there is nothing to draw.
+fn to_image_circles(circles: &[Circle], width: u32, height: u32) -> Option<Vec<((f64, f64), f64)>> {
+    to_image_circles_in(circles, &BBox::around(circles)?, width, height)
+}
 
-        (min_x, min_y, max_x, max_y)
-    };
+/// As `to_image_circles`, but normalized so that `bbox` fills the image, so several sets of circles can
+/// share one mapping. Returns `None` when `bbox` is degenerate.
+fn to_image_circles_in(circles: &[Circle], bbox: &BBox, width: u32, height: u32) -> Option<Vec<((f64, f64), f64)>> {
+    let (min_x, min_y) = (bbox.min.x, bbox.min.y);
+    let (max_x, max_y) = (bbox.max.x, bbox.max.y);
 
     if (max_x - min_x).abs() < 1e-14 || (max_y - min_y).abs() < 1e-14 {
         // Degenerate case: all circles might be in one point.
-        let img = ImageBuffer::from_fn(width, height, |_x, _y| Rgb::<u8>([255,255,255]));
-        img.save(output_path).unwrap();
-        return;
+        return None;
     }
 
     // Compute scale and offset
@@ -67,23 +73,24 @@ pub fn draw_circles_to_png(circles: &[Circle], width: u32, height: u32, output_p
         (x_img, y_img)
     };
 
-    // Assign colors to each circle.
-    // For simplicity, let's generate some distinct colors.
-    // In practice, you might choose a palette or random colors.
-    let c0 = Vector3::new(1.0, 0.0, 0.0);
-    let c1 = Vector3::new(0.0, 0.0, 1.0);
+    // Transform circles to image coordinates
+    Some(circles.iter()
+        .map(|c| (to_image_coords(c.origin), c.r * scale))
+        .collect())
+}
 
-    let len = circles.len() as f32;
+/// Renders the circles into an image buffer. `colors` must have one entry per circle.
+pub fn render_circles(circles: &[Circle], colors: &[Rgb<u8>], background: Rgb<u8>, width: u32, height: u32) -> Result<RgbImage, Box<dyn Error>> {
+    if colors.len() != circles.len() {
+        return Err(format!("Expected {} colors, got {}", circles.len(), colors.len()).into());
+    }
 
-    // Transform circles to image coordinates
-    let transformed_circles: Vec<((f64, f64), f64, Vector3<f32>)> = circles.iter().enumerate()
-        .map(|(i, c)| {
-            let (cx, cy) = to_image_coords(c.origin);
-            let s = i as f32 / len;
-            ((cx, cy), c.r * scale, c0 * s + c1 * (1.0 - s))
-        }).collect();
+    let mut img = ImageBuffer::from_fn(width, height, |_x, _y| background);
 
-    let mut img = ImageBuffer::from_fn(width, height, |_x, _y| Rgb([255u8, 255u8, 255u8]));
+    let transformed_circles: Vec<((f64, f64), f64, Rgb<u8>)> = match to_image_circles(circles, width, height) {
+        Some(t) => t.into_iter().zip(colors.iter()).map(|((c, r), col)| (c, r, *col)).collect(),
+        None => return Ok(img),
+    };
 
     // Drawing logic:
     // The first circle is on top. That means we should check circles in order:
@@ -99,12 +106,340 @@ pub fn draw_circles_to_png(circles: &[Circle], width: u32, height: u32, output_p
                 let dx = px - cx;
                 let dy = py - cy;
                 if dx*dx + dy*dy <= r_scaled*r_scaled {
-                    img.put_pixel(x, y, vector_to_rgb(*col));
+                    img.put_pixel(x, y, *col);
                     break; // Stop checking other circles
                 }
             }
         }
     }
 
-    img.save(output_path).unwrap();
+    Ok(img)
+}
+
+fn rgb_to_vector(col: Rgb<u8>) -> Vector3<f32> {
+    Vector3::new(col[0] as f32, col[1] as f32, col[2] as f32) / 255.0
+}
+
+/// Draws the circles to a PNG image with each one `alpha` opaque, so overlaps show through as blended
+/// colors. Uses the same gradient colors as `draw_circles_to_png`.
+pub fn draw_circles_blended(circles: &[Circle], alpha: f32, width: u32, height: u32, output_path: &str) -> Result<(), Box<dyn Error>> {
+    let colors = gradient_colors(circles.len());
+    let img = render_circles_blended(circles, &colors, alpha, Rgb([255u8, 255u8, 255u8]), width, height)?;
+    img.save(output_path)?;
+    Ok(())
+}
+
+/// As `render_circles`, but rather than painting only the top circle at each pixel, every circle is
+/// composited over the ones below it with the given `alpha`. The first circle is still on top.
+pub fn render_circles_blended(circles: &[Circle], colors: &[Rgb<u8>], alpha: f32, background: Rgb<u8>, width: u32, height: u32) -> Result<RgbImage, Box<dyn Error>> {
+    if colors.len() != circles.len() {
+        return Err(format!("Expected {} colors, got {}", circles.len(), colors.len()).into());
+    }
+    let alpha = alpha.clamp(0.0, 1.0);
+
+    // Blend in floating point so that stacked circles don't accumulate rounding error.
+    let mut buffer = vec![rgb_to_vector(background); (width * height) as usize];
+
+    if let Some(transformed_circles) = to_image_circles(circles, width, height) {
+        // Painter's algorithm: draw from the bottom (last) circle up to the top (first).
+        for (((cx, cy), r_scaled), col) in transformed_circles.iter().zip(colors.iter()).rev() {
+            let col = rgb_to_vector(*col);
+            for y in 0..height {
+                for x in 0..width {
+                    let dx = x as f64 + 0.5 - cx;
+                    let dy = y as f64 + 0.5 - cy;
+                    if dx*dx + dy*dy <= r_scaled*r_scaled {
+                        let px = &mut buffer[(y * width + x) as usize];
+                        *px = col * alpha + *px * (1.0 - alpha);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(ImageBuffer::from_fn(width, height, |x, y| vector_to_rgb(buffer[(y * width + x) as usize])))
+}
+
+/// Color for a pixel covered by `count` circles: white for none, a light yellow for one,
+/// then progressively darker reds as more catchments double up.
+pub fn coverage_color(count: usize) -> Rgb<u8> {
+    match count {
+        0 => Rgb([255u8, 255u8, 255u8]),
+        1 => Rgb([255u8, 240u8, 180u8]),
+        _ => {
+            // 2 is bright red, darkening towards a deep red at 6+.
+            let s = ((count - 2) as f32 / 4.0).min(1.0);
+            let c0 = Vector3::new(1.0, 0.2, 0.1);
+            let c1 = Vector3::new(0.35, 0.0, 0.0);
+            vector_to_rgb(c0 * (1.0 - s) + c1 * s)
+        }
+    }
+}
+
+/// Renders how many circles cover each pixel, colored by `coverage_color`. Uses the same
+/// normalization as `render_circles`, so the two images line up.
+pub fn render_coverage(circles: &[Circle], width: u32, height: u32) -> RgbImage {
+    let mut img = ImageBuffer::from_fn(width, height, |_x, _y| coverage_color(0));
+
+    let transformed_circles = match to_image_circles(circles, width, height) {
+        Some(t) => t,
+        None => return img,
+    };
+
+    // Pixel centres, in the transformed coordinates.
+    let bbox = BBox { min: Vector2::zeros(), max: Vector2::new(width as f64, height as f64) };
+    for idx in 0..(width * height) as usize {
+        let p = grid_centre(&bbox, width as usize, height as usize, idx);
+
+        let count = transformed_circles.iter()
+            .filter(|((cx, cy), r_scaled)| {
+                let dx = p.x - cx;
+                let dy = p.y - cy;
+                dx*dx + dy*dy <= r_scaled*r_scaled
+            })
+            .count();
+        img.put_pixel(idx as u32 % width, idx as u32 / width, coverage_color(count));
+    }
+
+    img
+}
+
+/// Draws a heatmap of circle coverage to a PNG image at `output_path`, showing where catchments overlap.
+pub fn draw_coverage_heatmap(circles: &[Circle], width: u32, height: u32, output_path: &str) -> Result<(), Box<dyn Error>> {
+    render_coverage(circles, width, height).save(output_path)?;
+    Ok(())
+}
+
+/// How opaque each layer of `render_layers` is, so the layers below show through.
+pub const LAYER_ALPHA: f32 = 0.5;
+
+/// Image pixels per font pixel in the legend.
+const LEGEND_SCALE: u32 = 2;
+
+/// Space around and between legend entries, in image pixels.
+const LEGEND_PAD: u32 = 4;
+
+/// Rows of a 3x5 pixel glyph, top first, with the leftmost pixel in the highest bit. Lowercase letters
+/// are drawn as uppercase and anything without a glyph is left blank.
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        _ => [0; 5],
+    }
+}
+
+/// Fills a rectangle, clipped to the image.
+fn fill_rect(img: &mut RgbImage, x: u32, y: u32, w: u32, h: u32, color: Rgb<u8>) {
+    for py in y..(y + h).min(img.height()) {
+        for px in x..(x + w).min(img.width()) {
+            img.put_pixel(px, py, color);
+        }
+    }
+}
+
+/// Draws `text` with its top left corner at `(x, y)`, each font pixel `scale` image pixels across.
+fn draw_text(img: &mut RgbImage, x: u32, y: u32, text: &str, scale: u32, color: Rgb<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let x0 = x + i as u32 * 4 * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill_rect(img, x0 + col * scale, y + row as u32 * scale, scale, scale, color);
+                }
+            }
+        }
+    }
+}
+
+/// Draws a legend in the top left corner: a swatch and label per entry, on a white box.
+fn draw_legend(img: &mut RgbImage, entries: &[(Rgb<u8>, &str)]) {
+    if entries.is_empty() {
+        return;
+    }
+    let line = 5 * LEGEND_SCALE;
+    let text_width = entries.iter().map(|(_, label)| label.chars().count() as u32 * 4 * LEGEND_SCALE).max().unwrap_or(0);
+    let width = LEGEND_PAD * 3 + line + text_width;
+    let height = LEGEND_PAD + entries.len() as u32 * (line + LEGEND_PAD);
+    fill_rect(img, 0, 0, width, height, Rgb([255u8, 255u8, 255u8]));
+
+    for (i, (color, label)) in entries.iter().enumerate() {
+        let y = LEGEND_PAD + i as u32 * (line + LEGEND_PAD);
+        fill_rect(img, LEGEND_PAD, y, line, line, *color);
+        draw_text(img, LEGEND_PAD * 2 + line, y, label, LEGEND_SCALE, Rgb([0u8, 0u8, 0u8]));
+    }
+}
+
+/// Renders several sets of circles over each other, each `(circles, color, label)` in its own color at
+/// `LAYER_ALPHA`, with a legend. All layers share one normalization, so they line up. The first layer is
+/// on top; within a layer, overlapping circles don't darken.
+pub fn render_layers(layers: &[(Vec<Circle>, Rgb<u8>, String)], width: u32, height: u32) -> RgbImage {
+    let background = Rgb([255u8, 255u8, 255u8]);
+    let mut buffer = vec![rgb_to_vector(background); (width * height) as usize];
+
+    let bbox = layers.iter().filter_map(|(circles, _, _)| BBox::around(circles)).reduce(|a, b| a.union(&b));
+    if let Some(bbox) = bbox {
+        for (circles, color, _) in layers.iter().rev() {
+            let Some(transformed_circles) = to_image_circles_in(circles, &bbox, width, height) else {
+                continue;
+            };
+            let col = rgb_to_vector(*color);
+            for y in 0..height {
+                for x in 0..width {
+                    let inside = transformed_circles.iter().any(|((cx, cy), r_scaled)| {
+                        let dx = x as f64 + 0.5 - cx;
+                        let dy = y as f64 + 0.5 - cy;
+                        dx*dx + dy*dy <= r_scaled*r_scaled
+                    });
+                    if inside {
+                        let px = &mut buffer[(y * width + x) as usize];
+                        *px = col * LAYER_ALPHA + *px * (1.0 - LAYER_ALPHA);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut img = ImageBuffer::from_fn(width, height, |x, y| vector_to_rgb(buffer[(y * width + x) as usize]));
+    let entries: Vec<(Rgb<u8>, &str)> = layers.iter().map(|(_, color, label)| (*color, label.as_str())).collect();
+    draw_legend(&mut img, &entries);
+    img
+}
+
+/// Draws `render_layers` to a PNG image at `output_path`, e.g. to compare secondary and primary catchments.
+pub fn draw_layers(layers: &[(Vec<Circle>, Rgb<u8>, String)], width: u32, height: u32, output_path: &str) -> Result<(), Box<dyn Error>> {
+    render_layers(layers, width, height).save(output_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_supplied_colors() {
+        let red = Rgb([255u8, 0, 0]);
+        let black = Rgb([0u8, 0, 0]);
+        let circles = [Circle { origin: Vector2::new(0.0, 0.0), r: 1.0 }];
+
+        let img = render_circles(&circles, &[red], black, 100, 100).unwrap();
+
+        assert_eq!(*img.get_pixel(50, 50), red);
+        assert_eq!(*img.get_pixel(0, 0), black);
+        assert_eq!(*img.get_pixel(99, 99), black);
+
+        assert!(render_circles(&circles, &[], black, 100, 100).is_err());
+    }
+
+    #[test]
+    fn test_render_blended() {
+        let red = Rgb([255u8, 0, 0]);
+        let blue = Rgb([0u8, 0, 255]);
+        let white = Rgb([255u8, 255, 255]);
+        // Same layout as the coverage test: the circles overlap around pixel (175, 100).
+        let circles = [
+            Circle { origin: Vector2::new(0.0, 0.0), r: 1.0 },
+            Circle { origin: Vector2::new(1.5, 0.0), r: 1.0 },
+        ];
+
+        let img = render_circles_blended(&circles, &[red, blue], 0.5, white, 350, 200).unwrap();
+
+        // Blue over white, then red on top: 0.5 * red + 0.25 * blue + 0.25 * white.
+        assert_eq!(*img.get_pixel(175, 100), Rgb([191u8, 64, 128]));
+        assert_eq!(*img.get_pixel(50, 100), Rgb([255u8, 128, 128]));
+        assert_eq!(*img.get_pixel(300, 100), Rgb([128u8, 128, 255]));
+        assert_eq!(*img.get_pixel(0, 0), white);
+
+        // Fully opaque matches the plain renderer.
+        let opaque = render_circles_blended(&circles, &[red, blue], 1.0, white, 350, 200).unwrap();
+        assert_eq!(opaque, render_circles(&circles, &[red, blue], white, 350, 200).unwrap());
+    }
+
+    #[test]
+    fn test_coverage_heatmap() {
+        // Two unit circles overlapping around x = 0.75, the bounding box is 3.5 x 2 so the
+        // image is 350 x 200 at 100px per unit with no offset.
+        let circles = [
+            Circle { origin: Vector2::new(0.0, 0.0), r: 1.0 },
+            Circle { origin: Vector2::new(1.5, 0.0), r: 1.0 },
+        ];
+
+        let img = render_coverage(&circles, 350, 200);
+
+        assert_eq!(*img.get_pixel(175, 100), coverage_color(2));
+        assert_eq!(*img.get_pixel(50, 100), coverage_color(1));
+        assert_eq!(*img.get_pixel(300, 100), coverage_color(1));
+        assert_eq!(*img.get_pixel(0, 0), coverage_color(0));
+        assert_ne!(coverage_color(2), coverage_color(1));
+    }
+
+    #[test]
+    fn test_render_layers() {
+        let red = Rgb([255u8, 0, 0]);
+        let blue = Rgb([0u8, 0, 255]);
+        let white = Rgb([255u8, 255, 255]);
+        let tint = |col: Rgb<u8>| vector_to_rgb(rgb_to_vector(col) * LAYER_ALPHA + rgb_to_vector(white) * (1.0 - LAYER_ALPHA));
+        // The combined box is 12 x 2, so the image is 600 x 100 at 50px per unit with no offset.
+        let near = vec![Circle { origin: Vector2::new(0.0, 0.0), r: 1.0 }];
+        let far = vec![Circle { origin: Vector2::new(10.0, 0.0), r: 1.0 }];
+        let layers = vec![(near.clone(), red, "sec".to_owned()), (far.clone(), blue, "prim".to_owned())];
+
+        let img = render_layers(&layers, 600, 100);
+        assert_eq!(*img.get_pixel(50, 60), tint(red));
+        assert_eq!(*img.get_pixel(550, 60), tint(blue));
+        // The far circle keeps the shared scale rather than filling the image on its own.
+        assert_eq!(*img.get_pixel(505, 60), tint(blue));
+        assert_eq!(*img.get_pixel(495, 60), white);
+        assert_eq!(*img.get_pixel(300, 60), white);
+
+        // Alone, the far layer is centred and scaled to fill the image.
+        let alone = render_layers(&layers[1..], 600, 100);
+        assert_eq!(*alone.get_pixel(300, 60), tint(blue));
+
+        // Both legend swatches are drawn, in order, at full strength.
+        let swatch = LEGEND_PAD + 1;
+        assert_eq!(*img.get_pixel(swatch, swatch), red);
+        assert_eq!(*img.get_pixel(swatch, swatch + 5 * LEGEND_SCALE + LEGEND_PAD), blue);
+    }
 }
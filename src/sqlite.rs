@@ -0,0 +1,117 @@
+//! Single-file SQLite output for loading schools and catchments into QGIS.
+use proj::Proj;
+use rusqlite::{params, Connection};
+
+use crate::{assign::Circle, error::ScaError, AggregateSchoolRecord};
+
+/// Creates the `schools` and `circles` tables if they don't exist. `schools` has the columns of `all_sec.csv`.
+pub fn create_tables(conn: &Connection) -> Result<(), ScaError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schools (
+            year INTEGER NOT NULL,
+            lad_postcode TEXT,
+            lad_ofsted TEXT,
+            msoa TEXT NOT NULL,
+            name TEXT NOT NULL,
+            pcode TEXT NOT NULL,
+            lat REAL,
+            lng REAL,
+            x_km REAL,
+            y_km REAL,
+            radius REAL,
+            catchment_area_km2 REAL,
+            catchment_area_ha REAL,
+            target_density REAL,
+            target_prop REAL,
+            pop INTEGER,
+            urn TEXT NOT NULL,
+            school_type TEXT NOT NULL,
+            is_state INTEGER NOT NULL,
+            is_selective INTEGER NOT NULL,
+            p8 TEXT NOT NULL,
+            ebacc TEXT NOT NULL,
+            of_overall INTEGER,
+            of_educ INTEGER,
+            of_behaviour INTEGER,
+            of_pdev INTEGER,
+            of_sixthform INTEGER,
+            gcseg2 REAL,
+            gcseg2_dis REAL
+        );
+        CREATE TABLE IF NOT EXISTS circles (
+            phase TEXT NOT NULL,
+            year INTEGER NOT NULL,
+            urn TEXT NOT NULL,
+            lng REAL NOT NULL,
+            lat REAL NOT NULL,
+            radius_km REAL NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+pub fn write_schools(conn: &mut Connection, schools: &[AggregateSchoolRecord]) -> Result<(), ScaError> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO schools VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29)",
+        )?;
+        for s in schools {
+            stmt.execute(params![
+                s.year, s.lad, s.lad_ofsted, s.msoa, s.name, s.pcode, s.lat, s.lng, s.x_km, s.y_km, s.radius,
+                s.catchment_area_km2, s.catchment_area_ha, s.target_density, s.target_prop, s.pop, s.urn, s.school_type, s.is_state, s.is_selective,
+                s.p8, s.ebacc, s.of_overall, s.of_educ, s.of_behaviour, s.of_pdev, s.of_sixthform,
+                s.gcseg2, s.gcseg2_dis,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Writes the scaled catchments of one phase ("sec" or "prim") and year, keyed by school URN.
+/// Circles are in BNG km and are stored by their centre's lon/lat.
+pub fn write_circles(conn: &mut Connection, phase: &str, year: u32, circles: &[(&str, Circle)]) -> Result<(), ScaError> {
+    let from_bng = Proj::new_known_crs("EPSG:27700", "EPSG:4326", None)?;
+
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO circles VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        for (urn, circle) in circles {
+            let (lng, lat) = from_bng.convert((circle.origin.x * 1000.0, circle.origin.y * 1000.0))?;
+            stmt.execute(params![phase, year, urn, lng, lat, circle.r])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector2;
+
+    #[test]
+    fn test_sqlite_row_counts() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+
+        let schools = vec![
+            AggregateSchoolRecord { urn: "1".to_owned(), ..crate::tests::aggregate_school() },
+            AggregateSchoolRecord { urn: "2".to_owned(), ..crate::tests::aggregate_school() },
+        ];
+        write_schools(&mut conn, &schools).unwrap();
+
+        let circles = [
+            ("1", Circle { origin: Vector2::new(383.8, 398.1), r: 0.9 }),
+            ("2", Circle { origin: Vector2::new(384.5, 396.7), r: 1.1 }),
+            ("3", Circle { origin: Vector2::new(385.5, 394.8), r: 0.7 }),
+        ];
+        write_circles(&mut conn, "sec", 2019, &circles).unwrap();
+
+        let n: i64 = conn.query_row("SELECT COUNT(*) FROM schools", [], |row| row.get(0)).unwrap();
+        assert_eq!(n, schools.len() as i64);
+        let n: i64 = conn.query_row("SELECT COUNT(*) FROM circles", [], |row| row.get(0)).unwrap();
+        assert_eq!(n, circles.len() as i64);
+    }
+}